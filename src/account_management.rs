@@ -1,223 +1,215 @@
-use argon2::Config;
-use bson::{doc, oid::ObjectId, DateTime};
-use pvrustlib::ServiceError;
-use rand::{RngCore, rngs::OsRng};
-use redis::AsyncCommands;
-
-use crate::{context::AppContext, common::{SERVICE_NAME, rate_limit}, log, models::ActivityLogEntry};
-
-
-pub async fn update_email(ctx: &AppContext, uid: ObjectId, email: String, verify_code: String, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
-	let id = format!("email-verify-{}", email);
-	let mut conn = ctx.redis_client.get_async_connection().await?;
-	rate_limit(&email, &mut conn).await?;
-	let expected_code: Option<String> = conn.get(&id).await?;
-	if let None = expected_code {
-		return Err(ServiceError::new_error_kind(SERVICE_NAME, "INCORRECT_VERIFY_CODE").into());
-	}
-	let expected_code = expected_code.unwrap();
-	if expected_code != verify_code {
-		println!("{}", expected_code);
-		return Err(ServiceError::new_error_kind(SERVICE_NAME, "INCORRECT_VERIFY_CODE").into());
-	}
+use bson::{DateTime, oid::ObjectId};
+
+use crate::{context::AppContext, common::rate_limit, error::Error, extractors::Requester, identity::{Identity, IdentityProvider}, log, models::{ActivityLogEntry, Voter}, new_login::check_verify_code, recovery_codes};
+
+
+pub async fn update_email(ctx: &AppContext, uid: ObjectId, email: String, verify_code: String, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Error> {
+	check_verify_code(ctx, format!("email-verify-{}", email), &email, &verify_code).await?;
 
-	if let Some(voter) = ctx.voters_coll.find_one(doc! { "_id": uid.clone() }, None).await? {
-		if let Some(exisiting_voter) = ctx.voters_coll.find_one(doc! { "email": email.clone() }, None).await? {
+	if let Some(mut voter) = ctx.voters.find_by_id(&uid).await? {
+		if let Some(exisiting_voter) = ctx.voters.find_by_identity(&Identity::Email(email.clone())).await? {
 			if exisiting_voter._id != voter._id {
-				return Err(ServiceError::new_error_kind(SERVICE_NAME, "EMAIL_IN_USE").into());
+				return Err(Error::Conflict("EMAIL_IN_USE"));
 			}
 		}
-		rate_limit(&voter._id.unwrap(), &mut conn).await?;
-		ctx.voters_coll.update_one(
-			doc! { "_id": uid },
-			doc! {
-				"$set": {
-					"email": email.clone(),
-					"email_verified": true
-				}
-			},
-			None).await?;
+		rate_limit(&uid, ctx).await?;
+		let old_email = voter.email.clone();
+		voter.email = Some(email.clone());
+		voter.email_verified = true;
+		ctx.voters.replace(&voter).await?;
 		log(ctx, ActivityLogEntry::UpdateEmail {
-			created_at: DateTime::now(),
+			created_at: ctx.now(),
 			uid: uid.clone(),
-			old_email: voter.email.clone(),
+			old_email: old_email,
 			new_email: email,
 			requester_ip: ip,
 			requester_additional_fingerprint: additional_fingerprint
 		}).await;
 	} else {
 		if let Some(ip) = ip {
-			rate_limit(&ip, &mut conn).await?;
+			rate_limit(&ip, ctx).await?;
 		}
-		return Err(ServiceError::new_not_found(SERVICE_NAME, None).into());
+		return Err(Error::NotFound);
 	}
 
 	Ok(())
 }
 
-pub async fn update_phone(ctx: &AppContext, uid: ObjectId, phone: String, verify_code: String, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
-	let id = format!("phone-verify-{}", phone);
-	let mut conn = ctx.redis_client.get_async_connection().await?;
-	rate_limit(&phone, &mut conn).await?;
-	let expected_code: Option<String> = conn.get(&id).await?;
-	if let None = expected_code {
-		return Err(ServiceError::new_error_kind(SERVICE_NAME, "INCORRECT_VERIFY_CODE").into());
-	}
-	let expected_code = expected_code.unwrap();
-	if expected_code != verify_code {
-		println!("{}", expected_code);
-		return Err(ServiceError::new_error_kind(SERVICE_NAME, "INCORRECT_VERIFY_CODE").into());
-	}
+pub async fn update_phone(ctx: &AppContext, uid: ObjectId, phone: String, verify_code: String, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Error> {
+	check_verify_code(ctx, format!("phone-verify-{}", phone), &phone, &verify_code).await?;
 
-	if let Some(voter) = ctx.voters_coll.find_one(doc! { "_id": uid.clone() }, None).await? {
-		if let Some(exisiting_voter) = ctx.voters_coll.find_one(doc! { "phone": phone.clone() }, None).await? {
+	if let Some(mut voter) = ctx.voters.find_by_id(&uid).await? {
+		if let Some(exisiting_voter) = ctx.voters.find_by_identity(&Identity::Phone(phone.clone())).await? {
 			if exisiting_voter._id != voter._id {
-				return Err(ServiceError::new_error_kind(SERVICE_NAME, "PHONE_IN_USE").into());
+				return Err(Error::Conflict("PHONE_IN_USE"));
 			}
 		}
-		rate_limit(&voter._id.unwrap(), &mut conn).await?;
-		ctx.voters_coll.update_one(
-			doc! { "_id": uid },
-			doc! {
-				"$set": {
-					"phone": phone.clone(),
-					"phone_verified": true
-				}
-			},
-			None).await?;
+		rate_limit(&uid, ctx).await?;
+		let old_phone = voter.phone.clone();
+		voter.phone = Some(phone.clone());
+		voter.phone_verified = true;
+		ctx.voters.replace(&voter).await?;
 		log(ctx, ActivityLogEntry::UpdatePhone {
-			created_at: DateTime::now(),
+			created_at: ctx.now(),
 			uid: uid.clone(),
-			old_phone: voter.phone.clone(),
+			old_phone: old_phone,
 			new_phone: phone,
 			requester_ip: ip,
 			requester_additional_fingerprint: additional_fingerprint
 		}).await;
 	} else {
 		if let Some(ip) = ip {
-			rate_limit(&ip, &mut conn).await?;
+			rate_limit(&ip, ctx).await?;
 		}
-		return Err(ServiceError::new_not_found(SERVICE_NAME, None).into());
+		return Err(Error::NotFound);
 	}
 
 	Ok(())
 }
 
-pub async fn update_nickname(ctx: &AppContext, uid: ObjectId, new_nickname: String, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
-	let mut conn = ctx.redis_client.get_async_connection().await?;
-	if let Some(voter) = ctx.voters_coll.find_one(doc! { "_id": uid.clone() }, None).await? {
-		rate_limit(&voter._id.unwrap(), &mut conn).await?;
-		ctx.voters_coll.update_one(
-			doc! { "_id": uid },
-			doc! {
-				"$set": {
-					"nickname": new_nickname.clone()
-				}
-			},
-			None).await?;
+pub async fn update_nickname(ctx: &AppContext, uid: ObjectId, new_nickname: String, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Error> {
+	if let Some(mut voter) = ctx.voters.find_by_id(&uid).await? {
+		rate_limit(&uid, ctx).await?;
+		let old_nickname = voter.nickname.clone();
+		voter.nickname = Some(new_nickname.clone());
+		ctx.voters.replace(&voter).await?;
 		log(ctx, ActivityLogEntry::UpdateNickname {
-			created_at: DateTime::now(),
+			created_at: ctx.now(),
 			uid: uid.clone(),
-			old_nickname: voter.nickname.clone(),
+			old_nickname: old_nickname,
 			new_nickname: new_nickname,
 			requester_ip: ip,
 			requester_additional_fingerprint: additional_fingerprint
 		}).await;
 	} else {
 		if let Some(ip) = ip {
-			rate_limit(&ip, &mut conn).await?;
+			rate_limit(&ip, ctx).await?;
 		}
-		return Err(ServiceError::new_not_found(SERVICE_NAME, None).into());
+		return Err(Error::NotFound);
 	}
 
 	Ok(())
 }
 
 
-pub async fn update_password(ctx: &AppContext, uid: ObjectId, old_password: Option<String>, new_password: String, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
-	let mut redis_conn = ctx.redis_client.get_async_connection().await?;
-	if let Some(voter) = ctx.voters_coll.find_one(doc! { "_id": uid.clone() }, None).await? {
-		rate_limit(&voter._id.unwrap(), &mut redis_conn).await?;
-		if let Some(password_hashed) = voter.password_hashed.as_ref() {
-			if let Some(salt) = voter.salt.as_ref() {
-				if let Some(old_password) = old_password {
-					let pwrt = format!("{}{}", old_password, salt);
-					if !bcrypt::verify(pwrt, password_hashed).ok().unwrap_or(false) {
-						return Err(ServiceError::new_error_kind(SERVICE_NAME, "INCORRECT_PASSWORD").into());
-					} else {
-						// legacy bcrypt verified
-						// upgrade to argon2
-						let mut salt = [0u8; 16];
-						OsRng.fill_bytes(&mut salt);
-						let new_password_hashed = argon2::hash_encoded(new_password.as_bytes(), &salt, &Config::default())?;
-						let mut voter = voter.clone();
-						voter.salt = None;
-						voter.password_hashed = Some(new_password_hashed.clone());
-						ctx.voters_coll.replace_one(doc! { "_id": uid.clone() }, voter.clone(), None).await?;
-						return Ok(());
-					}
-				} else {
-					// missing: old password
-					return Err(ServiceError::new_error_kind(SERVICE_NAME, "INCORRECT_PASSWORD").into());
-				}
-			}
-			if let Some(old_password) = old_password {
-				if argon2::verify_encoded(password_hashed, old_password.as_bytes())? {
-					let mut voter = voter.clone();
-					let mut salt = [0u8; 16];
-					OsRng.fill_bytes(&mut salt);
-					let new_password_hashed = argon2::hash_encoded(new_password.as_bytes(), &salt, &Config::default())?;
-					voter.salt = None;
-					voter.password_hashed = Some(new_password_hashed.clone());
-					ctx.voters_coll.replace_one(doc! { "_id": uid.clone() }, voter.clone(), None).await?;
-					log(ctx, ActivityLogEntry::UpdatePassword {
-						created_at: DateTime::now(),
-						uid: uid.clone(),
-						requester_ip: ip,
-						requester_additional_fingerprint: additional_fingerprint
-					}).await;
-					return Ok(());
-				} else {
-					return Err(ServiceError::new_error_kind(SERVICE_NAME, "INCORRECT_PASSWORD").into());
-				}
-			} else {
-				let mut voter = voter.clone();
-				let mut salt = [0u8; 16];
-				OsRng.fill_bytes(&mut salt);
-				let new_password_hashed = argon2::hash_encoded(new_password.as_bytes(), &salt, &Config::default())?;
-				voter.salt = None;
-				voter.password_hashed = Some(new_password_hashed.clone());
-				ctx.voters_coll.replace_one(doc! { "_id": uid.clone() }, voter.clone(), None).await?;
-				log(ctx, ActivityLogEntry::UpdatePassword {
-					created_at: DateTime::now(),
-					uid: uid.clone(),
-					requester_ip: ip,
-					requester_additional_fingerprint: additional_fingerprint
-				}).await;
-				return Ok(());
-			}
-		} else {
-			return Err(ServiceError::new_error_kind(SERVICE_NAME, "LOGIN_METHOD_NOT_SUPPORTED").into());
-		}
+/// Set a new password, the old one is required if the voter has one
+pub async fn update_password(ctx: &AppContext, uid: ObjectId, old_password: Option<String>, new_password: String, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Error> {
+	let mut voter = ctx.voters.find_by_id(&uid).await?.ok_or(Error::NotFound)?;
+	rate_limit(&uid, ctx).await?;
+	if voter.password_hashed.is_some() {
+		ctx.password_hasher.verify(&voter, &old_password.ok_or(Error::Auth("INCORRECT_PASSWORD"))?).await?;
+	}
+	ctx.password_policy.check(&new_password, &voter.personal_info())?;
+	ctx.password_hasher.set_password(&mut voter, &new_password).await?;
+	ctx.voters.replace(&voter).await?;
+	log(ctx, ActivityLogEntry::UpdatePassword {
+		created_at: ctx.now(),
+		uid: uid,
+		requester_ip: ip,
+		requester_additional_fingerprint: additional_fingerprint
+	}).await;
+	Ok(())
+}
+
+
+/// Make sure the voter itself asks for something destructive
+///
+/// Voters with a password confirm with it, others with a code sent to their phone or email
+/// or one of their recovery codes, which is used up on `voter`.
+pub(crate) async fn reauthenticate(ctx: &AppContext, voter: &mut Voter, password: Option<String>, verify_code: Option<String>, requester: &Requester) -> Result<(), Error> {
+	if voter.password_hashed.is_some() {
+		return ctx.password_hasher.verify(voter, &password.ok_or(Error::Auth("INCORRECT_PASSWORD"))?).await.map(|_| ());
+	}
+	let verify_code = verify_code.ok_or(Error::Validation("REAUTH_REQUIRED"))?;
+	if recovery_codes::is_recovery_code(&verify_code) {
+		return recovery_codes::consume(ctx, voter, &verify_code, requester).await;
+	}
+	if let Some(phone) = voter.phone.as_ref() {
+		return check_verify_code(ctx, format!("phone-verify-{}", phone), phone, &verify_code).await;
+	}
+	if let Some(email) = voter.email.as_ref() {
+		return check_verify_code(ctx, format!("email-verify-{}", email), email, &verify_code).await;
+	}
+	Err(Error::Validation("REAUTH_REQUIRED"))
+}
+
+/// Mark a voter removed and log it out, personal data is kept until the grace period ends
+pub async fn start_removal(ctx: &AppContext, voter: &mut Voter) -> Result<(), Error> {
+	voter.removed = Some(true);
+	voter.purge_after = Some(DateTime::from_millis((ctx.clock.now() + chrono::Duration::days(ctx.deletion_grace_days)).timestamp_millis()));
+	ctx.voters.replace(voter).await?;
+	ctx.revoke_sessions(voter._id.as_ref().unwrap()).await
+}
+
+/// Remove a voter after re-authentication, can be undone with `restore_voter` during the grace period
+pub async fn remove_voter(ctx: &AppContext, uid: ObjectId, password: Option<String>, verify_code: Option<String>, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Error> {
+	let mut voter = match ctx.voters.find_by_id(&uid).await? {
+		Some(voter) if voter.removed != Some(true) => voter,
+		_ => return Err(Error::NotFound)
 	};
+	rate_limit(&uid, ctx).await?;
+	reauthenticate(ctx, &mut voter, password, verify_code, &Requester { ip: ip.clone(), additional_fingerprint: additional_fingerprint.clone() }).await?;
+	start_removal(ctx, &mut voter).await?;
+	log(ctx, ActivityLogEntry::RemoveVoter {
+		created_at: ctx.now(),
+		uid: uid.clone(),
+		requester_ip: ip,
+		requester_additional_fingerprint: additional_fingerprint
+	}).await;
+	Ok(())
+}
+
+/// Refuse voters not removed, or whose personal data was purged
+fn check_restorable(ctx: &AppContext, voter: &Voter) -> Result<(), Error> {
+	if voter.removed != Some(true) {
+		return Err(Error::Validation("VOTER_NOT_REMOVED"));
+	}
+	if voter.purged_at.is_some() || voter.purge_after.map_or(false, |t| t <= ctx.now()) {
+		return Err(Error::NotFound);
+	}
 	Ok(())
 }
 
+/// Undo a removal, refused once personal data was purged
+pub async fn cancel_removal(ctx: &AppContext, voter: &mut Voter) -> Result<(), Error> {
+	check_restorable(ctx, voter)?;
+	voter.removed = None;
+	voter.purge_after = None;
+	ctx.voters.replace(voter).await
+}
 
-pub async fn remove_voter(ctx: &AppContext, uid: ObjectId, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
-	if let Some(mut voter) = ctx.voters_coll.find_one(doc! { "_id": uid.clone() }, None).await? {
-		voter.removed = Some(true);
-		voter.email = None;
-		voter.email_verified = false;
-		voter.phone = None;
-		voter.phone_verified = false;
-		ctx.voters_coll.replace_one(doc! { "_id": uid.clone() }, voter.clone(), None).await?;
-		log(ctx, ActivityLogEntry::RemoveVoter {
-			created_at: DateTime::now(),
-			uid: uid.clone(),
-			requester_ip: ip,
-			requester_additional_fingerprint: additional_fingerprint
+/// Restore a removed voter proving who it is the same way as logging in
+pub async fn restore_voter(ctx: &AppContext, provider: &dyn IdentityProvider, requester: &Requester) -> Result<Voter, Error> {
+	let mut voter = ctx.voters.find_by_identity(&provider.identity()).await?.ok_or(Error::NotFound)?;
+	// checked first so recovery codes are not used up for nothing
+	check_restorable(ctx, &voter)?;
+	if let Some(updated) = provider.verify(ctx, Some(&voter)).await? {
+		voter = updated;
+	}
+	cancel_removal(ctx, &mut voter).await?;
+	log(ctx, ActivityLogEntry::RestoreVoter {
+		created_at: ctx.now(),
+		uid: voter._id.clone().unwrap(),
+		requester_ip: requester.ip.clone(),
+		requester_additional_fingerprint: requester.additional_fingerprint.clone()
+	}).await;
+	Ok(voter)
+}
+
+/// Purge personal data of every removed voter past its grace period, returns how many were purged
+///
+/// The voter document is kept, pseudonymized, as past votes refer to its id.
+pub async fn purge_removed_voters(ctx: &AppContext) -> Result<usize, Error> {
+	let voters = ctx.voters.find_purgeable(ctx.now()).await?;
+	for mut voter in voters.iter().cloned() {
+		let uid = voter._id.clone().unwrap();
+		ctx.logs.pseudonymize(&uid, voter.email.as_deref(), voter.phone.as_deref()).await?;
+		voter.pseudonymize(ctx.now());
+		ctx.voters.replace(&voter).await?;
+		log(ctx, ActivityLogEntry::PurgeVoter {
+			created_at: ctx.now(),
+			uid: uid
 		}).await;
 	}
-	Ok(())
+	Ok(voters.len())
 }
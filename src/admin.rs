@@ -0,0 +1,266 @@
+//! Support operations shared by the admin tools
+//!
+//! Every action taken on behalf of an operator is logged as `ActivityLogEntry::AdminAction`.
+
+use std::str::FromStr;
+
+use bson::{DateTime, oid::ObjectId};
+
+use crate::{account_management, common::{normalize_email, normalize_phone}, context::AppContext, error::Error, identity::Identity, log, models::{ActivityLogEntry, AdminLinkedIdentity, AdminRole, AdminVoterDetail, Ban, Voter, generate_admin_token}, repository::VoterQuery};
+
+/// A contact which can be verified
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Contact {
+	Email,
+	Phone
+}
+
+impl Contact {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Contact::Email => "email",
+			Contact::Phone => "phone",
+		}
+	}
+}
+
+impl FromStr for Contact {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"email" => Ok(Contact::Email),
+			"phone" => Ok(Contact::Phone),
+			_ => Err(Error::Validation("UNKNOWN_CONTACT"))
+		}
+	}
+}
+
+async fn audit(ctx: &AppContext, operator: &str, uid: Option<&ObjectId>, action: &str, detail: Option<String>) {
+	log(ctx, ActivityLogEntry::AdminAction {
+		created_at: ctx.now(),
+		operator: operator.to_string(),
+		uid: uid.cloned(),
+		action: action.to_string(),
+		detail: detail
+	}).await;
+}
+
+async fn get_voter(ctx: &AppContext, uid: &ObjectId) -> Result<Voter, Error> {
+	ctx.voters.find_by_id(uid).await?.ok_or(Error::NotFound)
+}
+
+/// Find a voter by uid, vote id (`thvote-<year>-<uid>`), email or phone
+pub async fn find_voter(ctx: &AppContext, key: &str) -> Result<Option<Voter>, Error> {
+	let key = key.trim();
+	if let Ok(uid) = ObjectId::from_str(key) {
+		return ctx.voters.find_by_id(&uid).await;
+	}
+	if let Some(rest) = key.strip_prefix("thvote-") {
+		let uid = rest.splitn(2, '-').nth(1).and_then(|uid| ObjectId::from_str(uid).ok()).ok_or(Error::Validation("INVALID_VOTE_ID"))?;
+		return ctx.voters.find_by_id(&uid).await;
+	}
+	if key.contains('@') {
+		return ctx.voters.find_by_identity(&Identity::Email(normalize_email(key))).await;
+	}
+	ctx.voters.find_by_identity(&Identity::Phone(normalize_phone(key))).await
+}
+
+/// Look a voter up as `find_voter` does for an operator, secrets redacted, the lookup is audited
+pub async fn lookup_voter(ctx: &AppContext, operator: &str, key: &str) -> Result<Option<Voter>, Error> {
+	let voter = find_voter(ctx, key).await?;
+	audit(ctx, operator, voter.as_ref().and_then(|v| v._id.as_ref()), "lookup", None).await;
+	Ok(voter.map(|v| v.redacted()))
+}
+
+/// Largest page of the admin voter search
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+/// Page of voters matching `query`, pages start at 1, and the total number of matches, searching is audited
+pub async fn search_voters(ctx: &AppContext, operator: &str, query: &VoterQuery, page: u64, page_size: i64) -> Result<(Vec<Voter>, u64), Error> {
+	let page_size = page_size.max(1).min(MAX_PAGE_SIZE);
+	let skip = (page.max(1) - 1) * page_size as u64;
+	let found = ctx.voters.search(query, ctx.now(), skip, page_size).await?;
+	audit(ctx, operator, None, "search", serde_json::to_string(query).ok()).await;
+	Ok(found)
+}
+
+/// Everything known about a voter, viewing it is audited
+pub async fn voter_detail(ctx: &AppContext, operator: &str, uid: &ObjectId) -> Result<AdminVoterDetail, Error> {
+	let voter = get_voter(ctx, uid).await?;
+	let identities = Identity::all_of(&voter).iter().map(|i| AdminLinkedIdentity { kind: i.kind().to_string(), value: i.value().to_string() }).collect();
+	let logs = ctx.logs.find_by_uid(uid, 100).await?;
+	audit(ctx, operator, Some(uid), "view", None).await;
+	Ok(AdminVoterDetail { voter: voter.to_admin_view(), identities, logs })
+}
+
+/// Ban a voter, or suspend it for `hours`, and log it out everywhere
+pub async fn ban_voter(ctx: &AppContext, operator: &str, uid: &ObjectId, reason: &str, hours: Option<i64>) -> Result<(), Error> {
+	// a suspension already over would still be logged as one
+	if hours.map_or(false, |h| h <= 0) {
+		return Err(Error::Validation("INVALID_HOURS"));
+	}
+	let mut voter = get_voter(ctx, uid).await?;
+	let expires_at = hours.map(|h| DateTime::from_millis((ctx.clock.now() + chrono::Duration::hours(h)).timestamp_millis()));
+	voter.ban = Some(Ban {
+		reason: reason.to_string(),
+		operator: operator.to_string(),
+		created_at: ctx.now(),
+		expires_at: expires_at
+	});
+	ctx.voters.replace(&voter).await?;
+	ctx.revoke_sessions(uid).await?;
+	let detail = match hours {
+		Some(h) => format!("{} (for {} hours)", reason, h),
+		None => reason.to_string()
+	};
+	audit(ctx, operator, Some(uid), if hours.is_some() { "suspend" } else { "ban" }, Some(detail)).await;
+	Ok(())
+}
+
+pub async fn unban_voter(ctx: &AppContext, operator: &str, uid: &ObjectId) -> Result<(), Error> {
+	let mut voter = get_voter(ctx, uid).await?;
+	if voter.ban.is_none() {
+		return Err(Error::Validation("VOTER_NOT_BANNED"));
+	}
+	voter.ban = None;
+	ctx.voters.replace(&voter).await?;
+	audit(ctx, operator, Some(uid), "unban", None).await;
+	Ok(())
+}
+
+/// Set a contact as verified without sending a code, or remove it if `value` is unset
+pub async fn override_contact(ctx: &AppContext, operator: &str, uid: &ObjectId, contact: Contact, value: Option<&str>) -> Result<(), Error> {
+	let mut voter = get_voter(ctx, uid).await?;
+	let identity = value.map(|v| match contact {
+		Contact::Email => Identity::Email(normalize_email(v)),
+		Contact::Phone => Identity::Phone(normalize_phone(v)),
+	});
+	if let Some(identity) = identity.as_ref() {
+		if let Some(owner) = ctx.voters.find_by_identity(identity).await? {
+			if owner._id != voter._id {
+				return Err(Error::Conflict(if contact == Contact::Email { "EMAIL_IN_USE" } else { "PHONE_IN_USE" }));
+			}
+		}
+	}
+	let old = match contact {
+		Contact::Email => voter.email.take(),
+		Contact::Phone => voter.phone.take(),
+	};
+	match identity.as_ref() {
+		Some(identity) => identity.link_to(&mut voter),
+		None if contact == Contact::Email => voter.email_verified = false,
+		None => voter.phone_verified = false,
+	}
+	ctx.voters.replace(&voter).await?;
+	let detail = format!("{}: {} -> {}", contact.name(), old.as_deref().unwrap_or("none"), identity.as_ref().map_or("none", |i| i.value()));
+	audit(ctx, operator, Some(uid), "override-contact", Some(detail)).await;
+	Ok(())
+}
+
+/// Admin API token for `name`
+pub async fn issue_admin_token(ctx: &AppContext, operator: &str, name: &str, role: AdminRole) -> String {
+	let token = generate_admin_token(&ctx.key_pair, name, role, ctx.clock.now());
+	audit(ctx, operator, None, "issue-admin-token", Some(format!("{} as {:?}", name, role))).await;
+	token
+}
+
+/// Most recent activity of a voter, newest first, viewing it is audited
+pub async fn voter_log(ctx: &AppContext, operator: &str, uid: &ObjectId, limit: i64) -> Result<Vec<ActivityLogEntry>, Error> {
+	let logs = ctx.logs.find_by_uid(uid, limit).await?;
+	audit(ctx, operator, Some(uid), "view-log", None).await;
+	Ok(logs)
+}
+
+/// Mark a contact as verified or not
+pub async fn set_verified(ctx: &AppContext, operator: &str, uid: &ObjectId, contact: Contact, verified: bool) -> Result<(), Error> {
+	let mut voter = get_voter(ctx, uid).await?;
+	match contact {
+		Contact::Email if voter.email.is_some() => voter.email_verified = verified,
+		Contact::Phone if voter.phone.is_some() => voter.phone_verified = verified,
+		_ => return Err(Error::Validation("CONTACT_NOT_SET"))
+	}
+	ctx.voters.replace(&voter).await?;
+	let action = if verified { "verify" } else { "unverify" };
+	audit(ctx, operator, Some(uid), action, Some(contact.name().to_string())).await;
+	Ok(())
+}
+
+/// Set a new password, a random one is generated and returned if none is given
+///
+/// Existing sessions are revoked.
+pub async fn reset_password(ctx: &AppContext, operator: &str, uid: &ObjectId, new_password: Option<String>) -> Result<String, Error> {
+	let mut voter = get_voter(ctx, uid).await?;
+	let new_password = match new_password {
+		Some(password) => {
+			ctx.password_policy.check(&password, &voter.personal_info())?;
+			password
+		},
+		None => ctx.code_generator.token(8)
+	};
+	ctx.password_hasher.set_password(&mut voter, &new_password).await?;
+	ctx.voters.replace(&voter).await?;
+	ctx.revoke_sessions(uid).await?;
+	audit(ctx, operator, Some(uid), "reset-password", None).await;
+	Ok(new_password)
+}
+
+/// Remove a voter without re-authentication, it can be restored until the grace period ends
+pub async fn remove_voter(ctx: &AppContext, operator: &str, uid: &ObjectId) -> Result<(), Error> {
+	let mut voter = get_voter(ctx, uid).await?;
+	if voter.removed == Some(true) {
+		return Err(Error::Validation("VOTER_ALREADY_REMOVED"));
+	}
+	account_management::start_removal(ctx, &mut voter).await?;
+	audit(ctx, operator, Some(uid), "remove", None).await;
+	Ok(())
+}
+
+/// Undo `remove_voter`, impossible once the voter's personal data was purged
+pub async fn restore_voter(ctx: &AppContext, operator: &str, uid: &ObjectId) -> Result<(), Error> {
+	let mut voter = get_voter(ctx, uid).await?;
+	account_management::cancel_removal(ctx, &mut voter).await?;
+	audit(ctx, operator, Some(uid), "restore", None).await;
+	Ok(())
+}
+
+/// Purge personal data of removed voters past their grace period
+pub async fn purge_removed_voters(ctx: &AppContext, operator: &str) -> Result<usize, Error> {
+	let purged = account_management::purge_removed_voters(ctx).await?;
+	audit(ctx, operator, None, "purge", Some(format!("{} voters", purged))).await;
+	Ok(purged)
+}
+
+/// Delete rate limit and resend guard keys of a uid, IP, email or phone
+pub async fn clear_rate_limits(ctx: &AppContext, operator: &str, target: &str) -> Result<(), Error> {
+	let target = target.trim();
+	let mut keys = vec![
+		format!("rate-limit-{}-last-reset", target),
+		format!("rate-limit-{}-tokens", target),
+	];
+	if target.contains('@') {
+		let email = normalize_email(target);
+		keys.push(format!("rate-limit-{}-last-reset", email));
+		keys.push(format!("rate-limit-{}-tokens", email));
+		keys.push(format!("email-verify-guard-{}", email));
+	} else if ObjectId::from_str(target).is_err() && !target.contains('.') && !target.contains(':') {
+		let phone = normalize_phone(target);
+		keys.push(format!("rate-limit-{}-last-reset", phone));
+		keys.push(format!("rate-limit-{}-tokens", phone));
+		keys.push(format!("phone-verify-guard-{}", phone));
+	}
+	for key in keys.iter() {
+		ctx.kv.del(key).await?;
+	}
+	let uid = ObjectId::from_str(target).ok();
+	audit(ctx, operator, uid.as_ref(), "clear-rate-limits", Some(target.to_string())).await;
+	Ok(())
+}
+
+/// Log a voter out everywhere
+pub async fn revoke_sessions(ctx: &AppContext, operator: &str, uid: &ObjectId) -> Result<(), Error> {
+	get_voter(ctx, uid).await?;
+	ctx.revoke_sessions(uid).await?;
+	audit(ctx, operator, Some(uid), "revoke-sessions", None).await;
+	Ok(())
+}
@@ -0,0 +1,107 @@
+//! Support operations for operators, run with `admin --operator <name> <command> [args]`
+
+use std::str::FromStr;
+
+use bson::oid::ObjectId;
+use thvote_user_manager::{admin::{self, Contact}, connect_database, context::AppContext, error::Error, models::AdminRole, production_context};
+
+const USAGE: &'static str = "Usage: admin --operator <name> <command> [args]
+
+Commands:
+    lookup <uid|vote_id|email|phone>
+    log <uid> [limit]
+    verify <uid> <email|phone>
+    unverify <uid> <email|phone>
+    reset-password <uid> [new password]
+    remove <uid>
+    restore <uid>
+    purge
+    clear-rate-limits <uid|ip|email|phone>
+    revoke-sessions <uid>
+    ban <uid> <reason> [hours]
+    unban <uid>
+    issue-admin-token <name> <viewer|moderator|superadmin>
+
+The operator can also be given in THVOTE_OPERATOR.";
+
+fn uid(arg: Option<&String>) -> Result<ObjectId, Error> {
+	arg.and_then(|s| ObjectId::from_str(s).ok()).ok_or(Error::Validation("INVALID_UID"))
+}
+
+fn contact(arg: Option<&String>) -> Result<Contact, Error> {
+	arg.ok_or(Error::Validation("UNKNOWN_CONTACT"))?.parse()
+}
+
+async fn run(ctx: &AppContext, operator: &str, command: &str, args: &[String]) -> Result<(), Error> {
+	match command {
+		"lookup" => {
+			let key = args.get(0).ok_or(Error::Validation("MISSING_KEY"))?;
+			match admin::lookup_voter(ctx, operator, key).await? {
+				Some(voter) => println!("{}", serde_json::to_string_pretty(&voter).map_err(Error::internal)?),
+				None => println!("No voter found for {}", key)
+			}
+		},
+		"log" => {
+			let limit = args.get(1).and_then(|s| s.parse().ok()).unwrap_or(50);
+			for entry in admin::voter_log(ctx, operator, &uid(args.get(0))?, limit).await? {
+				println!("{}", serde_json::to_string(&entry).map_err(Error::internal)?);
+			}
+		},
+		"verify" => admin::set_verified(ctx, operator, &uid(args.get(0))?, contact(args.get(1))?, true).await?,
+		"unverify" => admin::set_verified(ctx, operator, &uid(args.get(0))?, contact(args.get(1))?, false).await?,
+		"reset-password" => {
+			let password = admin::reset_password(ctx, operator, &uid(args.get(0))?, args.get(1).cloned()).await?;
+			println!("New password: {}", password);
+		},
+		"remove" => admin::remove_voter(ctx, operator, &uid(args.get(0))?).await?,
+		"restore" => admin::restore_voter(ctx, operator, &uid(args.get(0))?).await?,
+		"purge" => println!("Purged {} voters", admin::purge_removed_voters(ctx, operator).await?),
+		"clear-rate-limits" => admin::clear_rate_limits(ctx, operator, args.get(0).ok_or(Error::Validation("MISSING_TARGET"))?).await?,
+		"revoke-sessions" => admin::revoke_sessions(ctx, operator, &uid(args.get(0))?).await?,
+		"ban" => {
+			// a mistyped duration must not turn into a permanent ban
+			let hours = match args.get(2) {
+				Some(h) => Some(h.parse().map_err(|_| Error::Validation("INVALID_HOURS"))?),
+				None => None
+			};
+			admin::ban_voter(ctx, operator, &uid(args.get(0))?, args.get(1).ok_or(Error::Validation("MISSING_REASON"))?, hours).await?
+		},
+		"unban" => admin::unban_voter(ctx, operator, &uid(args.get(0))?).await?,
+		"issue-admin-token" => {
+			let name = args.get(0).ok_or(Error::Validation("MISSING_NAME"))?;
+			let role: AdminRole = args.get(1).ok_or(Error::Validation("UNKNOWN_ROLE"))?.parse()?;
+			println!("{}", admin::issue_admin_token(ctx, operator, name, role).await);
+		},
+		_ => {
+			println!("{}", USAGE);
+			return Ok(());
+		}
+	}
+	println!("Done");
+	Ok(())
+}
+
+#[actix_web::main]
+async fn main() {
+	let mut args: Vec<String> = std::env::args().skip(1).collect();
+	let mut operator = std::env::var("THVOTE_OPERATOR").ok().filter(|s| !s.is_empty());
+	if let Some(i) = args.iter().position(|a| a == "--operator") {
+		if i + 1 < args.len() {
+			operator = Some(args.remove(i + 1));
+		}
+		args.remove(i);
+	}
+	let (operator, command) = match (operator, args.get(0)) {
+		(Some(operator), Some(command)) => (operator, command.clone()),
+		_ => {
+			println!("{}", USAGE);
+			std::process::exit(2);
+		}
+	};
+	let db = connect_database().await;
+	let ctx = production_context(&db).await;
+	if let Err(e) = run(&ctx, &operator, &command, &args[1..]).await {
+		println!("Failed: {}", e);
+		std::process::exit(1);
+	}
+}
@@ -0,0 +1,129 @@
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::HttpRequest;
+
+use crate::{context::AppContext, models::UserEventMeta};
+
+/// Header an internal gateway uses to prove it is allowed to forward `meta.user_ip`
+pub const GATEWAY_TOKEN_HEADER: &'static str = "X-Gateway-Token";
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8`
+#[derive(Clone, Debug)]
+pub struct Cidr {
+	addr: IpAddr,
+	prefix: u8
+}
+
+impl Cidr {
+	pub fn parse(s: &str) -> Option<Cidr> {
+		let (addr, prefix) = match s.split_once('/') {
+			Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+			None => (s.parse::<IpAddr>().ok()?, None)
+		};
+		let max = if addr.is_ipv4() { 32 } else { 128 };
+		let prefix = prefix.unwrap_or(max);
+		if prefix > max {
+			return None;
+		}
+		Some(Cidr { addr, prefix })
+	}
+	pub fn contains(&self, ip: &IpAddr) -> bool {
+		match (self.addr, normalize(*ip)) {
+			(IpAddr::V4(net), IpAddr::V4(ip)) => {
+				let mask = if self.prefix == 0 { 0 } else { u32::MAX << (32 - self.prefix) };
+				u32::from(net) & mask == u32::from(ip) & mask
+			},
+			(IpAddr::V6(net), IpAddr::V6(ip)) => {
+				let mask = if self.prefix == 0 { 0 } else { u128::MAX << (128 - self.prefix) };
+				u128::from(net) & mask == u128::from(ip) & mask
+			},
+			_ => false
+		}
+	}
+}
+
+/// Parse the configured trusted proxy list, invalid entries are reported and skipped
+pub fn parse_trusted_proxies(cidrs: &[&str]) -> Vec<Cidr> {
+	cidrs.iter().filter_map(|s| {
+		let cidr = Cidr::parse(s);
+		if cidr.is_none() {
+			println!("Ignoring invalid trusted proxy CIDR {}", s);
+		}
+		cidr
+	}).collect()
+}
+
+/// Turn IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) back into IPv4
+fn normalize(ip: IpAddr) -> IpAddr {
+	if let IpAddr::V6(v6) = ip {
+		let seg = v6.segments();
+		if seg[0..5].iter().all(|s| *s == 0) && seg[5] == 0xffff {
+			if let Some(v4) = v6.to_ipv4() {
+				return IpAddr::V4(v4);
+			}
+		}
+	}
+	ip
+}
+
+fn parse_hop(s: &str) -> Option<IpAddr> {
+	let s = s.trim();
+	s.parse::<IpAddr>().ok().or_else(|| s.parse::<SocketAddr>().ok().map(|a| a.ip())).map(normalize)
+}
+
+fn is_trusted(ctx: &AppContext, ip: &IpAddr) -> bool {
+	ctx.trusted_proxies.iter().any(|c| c.contains(ip))
+}
+
+fn from_authenticated_gateway(ctx: &AppContext, request: &HttpRequest) -> bool {
+	let secret = match ctx.gateway_secret.as_ref() {
+		Some(secret) => secret,
+		None => return false
+	};
+	match request.headers().get(GATEWAY_TOKEN_HEADER).and_then(|v| v.to_str().ok()) {
+		// compare without short-circuiting on the first differing byte
+		Some(token) => token.len() == secret.len() && token.bytes().zip(secret.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0,
+		None => false
+	}
+}
+
+/// Resolve the real client IP of a request
+///
+/// Forwarding headers are only honoured when the connection comes from a trusted proxy,
+/// `meta.user_ip` from the request body is only honoured when the request comes from an authenticated internal gateway.
+pub fn client_ip(ctx: &AppContext, request: &HttpRequest, meta: &UserEventMeta) -> Option<String> {
+	let peer = match request.peer_addr() {
+		Some(addr) => normalize(addr.ip()),
+		None => return None
+	};
+	if !is_trusted(ctx, &peer) {
+		return Some(peer.to_string());
+	}
+	if from_authenticated_gateway(ctx, request) && !meta.user_ip.is_empty() {
+		if let Some(ip) = parse_hop(&meta.user_ip) {
+			return Some(ip.to_string());
+		}
+	}
+	if let Some(xff) = request.headers().get("X-Forwarded-For").and_then(|v| v.to_str().ok()) {
+		// walk from the closest hop, the first address not belonging to us is the client
+		let mut client = None;
+		for hop in xff.rsplit(',') {
+			match parse_hop(hop) {
+				Some(ip) => {
+					client = Some(ip);
+					if !is_trusted(ctx, &ip) {
+						break;
+					}
+				},
+				None => break
+			}
+		}
+		if let Some(ip) = client {
+			return Some(ip.to_string());
+		}
+	}
+	if let Some(ip) = request.headers().get("X-Real-IP").and_then(|v| v.to_str().ok()).and_then(parse_hop) {
+		return Some(ip.to_string());
+	}
+	Some(peer.to_string())
+}
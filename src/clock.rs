@@ -0,0 +1,40 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+
+/// Source of the current time
+pub trait Clock: Send + Sync {
+	fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> DateTime<Utc> {
+		Utc::now()
+	}
+}
+
+/// A clock which only moves when told to, used by tests
+pub struct FakeClock {
+	now: Mutex<DateTime<Utc>>
+}
+
+impl FakeClock {
+	pub fn new(start: DateTime<Utc>) -> FakeClock {
+		FakeClock { now: Mutex::new(start) }
+	}
+	pub fn advance(&self, by: Duration) {
+		let mut now = self.now.lock().unwrap();
+		*now = *now + by;
+	}
+	pub fn set(&self, to: DateTime<Utc>) {
+		*self.now.lock().unwrap() = to;
+	}
+}
+
+impl Clock for FakeClock {
+	fn now(&self) -> DateTime<Utc> {
+		*self.now.lock().unwrap()
+	}
+}
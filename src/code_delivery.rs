@@ -0,0 +1,135 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use pvrustlib::{EmptyJSON, json_request};
+
+use crate::{common::SERVICE_NAME, error::Error, models::Voter};
+
+/// Security notices sent to voters, rendered by the SMS and email services
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Notice {
+	/// The password was reset through another contact
+	PasswordReset,
+	/// Password logins were locked after repeated failures
+	AccountLocked,
+	/// A recovery code was used
+	RecoveryCodeUsed
+}
+
+impl Notice {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Notice::PasswordReset => "password-reset",
+			Notice::AccountLocked => "account-locked",
+			Notice::RecoveryCodeUsed => "recovery-code-used",
+		}
+	}
+}
+
+/// Delivers verify codes and notices to voters
+#[async_trait]
+pub trait CodeSender: Send + Sync {
+	async fn send_sms_code(&self, phone: &str, code: &str) -> Result<(), Error>;
+	async fn send_email_code(&self, email: &str, code: &str) -> Result<(), Error>;
+	async fn send_sms_notice(&self, phone: &str, notice: Notice) -> Result<(), Error>;
+	async fn send_email_notice(&self, email: &str, notice: Notice) -> Result<(), Error>;
+}
+
+/// Send a notice to the voter's email, or to its phone if it has none
+pub async fn notify_voter(sender: &dyn CodeSender, voter: &Voter, notice: Notice) -> Result<(), Error> {
+	match (voter.email.as_ref(), voter.phone.as_ref()) {
+		(Some(email), _) => sender.send_email_notice(email, notice).await,
+		(None, Some(phone)) => sender.send_sms_notice(phone, notice).await,
+		(None, None) => Ok(())
+	}
+}
+
+/// Sends codes through the SMS and email services
+pub struct HttpCodeSender;
+
+#[async_trait]
+impl CodeSender for HttpCodeSender {
+	async fn send_sms_code(&self, phone: &str, code: &str) -> Result<(), Error> {
+		let req = crate::sms_service::SMSRequest {
+			code: code.to_string(),
+			mobile: phone.to_string()
+		};
+		let _: EmptyJSON = json_request(SERVICE_NAME, &format!("{}/v1/vote-code", crate::comm::SERVICE_SMS_ADDRESS), req).await.map_err(|e| Error::upstream("sms-service", e))?;
+		Ok(())
+	}
+	async fn send_email_code(&self, email: &str, code: &str) -> Result<(), Error> {
+		let req = crate::email_service::EmailRequest {
+			code: code.to_string(),
+			email: email.to_string()
+		};
+		let _: EmptyJSON = json_request(SERVICE_NAME, &format!("{}/v1/vote-code", crate::comm::SERVICE_EMAIL_ADDRESS), req).await.map_err(|e| Error::upstream("email-service", e))?;
+		Ok(())
+	}
+	async fn send_sms_notice(&self, phone: &str, notice: Notice) -> Result<(), Error> {
+		let req = crate::sms_service::SMSNoticeRequest {
+			notice: notice.name().to_string(),
+			mobile: phone.to_string()
+		};
+		let _: EmptyJSON = json_request(SERVICE_NAME, &format!("{}/v1/notice", crate::comm::SERVICE_SMS_ADDRESS), req).await.map_err(|e| Error::upstream("sms-service", e))?;
+		Ok(())
+	}
+	async fn send_email_notice(&self, email: &str, notice: Notice) -> Result<(), Error> {
+		let req = crate::email_service::EmailNoticeRequest {
+			notice: notice.name().to_string(),
+			email: email.to_string()
+		};
+		let _: EmptyJSON = json_request(SERVICE_NAME, &format!("{}/v1/notice", crate::comm::SERVICE_EMAIL_ADDRESS), req).await.map_err(|e| Error::upstream("email-service", e))?;
+		Ok(())
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SentCode {
+	SMS { phone: String, code: String },
+	Email { email: String, code: String },
+	/// A notice sent to a phone number or email address
+	Notice { target: String, notice: Notice }
+}
+
+/// Keeps codes instead of sending them, used by tests
+#[derive(Default)]
+pub struct CapturingCodeSender {
+	sent: Mutex<Vec<SentCode>>
+}
+
+impl CapturingCodeSender {
+	pub fn new() -> CapturingCodeSender {
+		Default::default()
+	}
+	pub fn sent(&self) -> Vec<SentCode> {
+		self.sent.lock().unwrap().clone()
+	}
+	/// Most recent code sent to a phone number or email address
+	pub fn last_code_for(&self, target: &str) -> Option<String> {
+		self.sent.lock().unwrap().iter().rev().find_map(|s| match s {
+			SentCode::SMS { phone, code } if phone == target => Some(code.clone()),
+			SentCode::Email { email, code } if email == target => Some(code.clone()),
+			_ => None
+		})
+	}
+}
+
+#[async_trait]
+impl CodeSender for CapturingCodeSender {
+	async fn send_sms_code(&self, phone: &str, code: &str) -> Result<(), Error> {
+		self.sent.lock().unwrap().push(SentCode::SMS { phone: phone.to_string(), code: code.to_string() });
+		Ok(())
+	}
+	async fn send_email_code(&self, email: &str, code: &str) -> Result<(), Error> {
+		self.sent.lock().unwrap().push(SentCode::Email { email: email.to_string(), code: code.to_string() });
+		Ok(())
+	}
+	async fn send_sms_notice(&self, phone: &str, notice: Notice) -> Result<(), Error> {
+		self.sent.lock().unwrap().push(SentCode::Notice { target: phone.to_string(), notice: notice });
+		Ok(())
+	}
+	async fn send_email_notice(&self, email: &str, notice: Notice) -> Result<(), Error> {
+		self.sent.lock().unwrap().push(SentCode::Notice { target: email.to_string(), notice: notice });
+		Ok(())
+	}
+}
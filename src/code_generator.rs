@@ -0,0 +1,51 @@
+use std::sync::Mutex;
+
+use rand::{Rng, RngCore, rngs::OsRng};
+
+/// Source of verify codes and session ids
+pub trait CodeGenerator: Send + Sync {
+	/// Zero padded numeric code with `digits` digits
+	fn numeric_code(&self, digits: u32) -> String;
+	/// Hex encoded random token made of `bytes` bytes
+	fn token(&self, bytes: usize) -> String;
+}
+
+pub struct OsCodeGenerator;
+
+impl CodeGenerator for OsCodeGenerator {
+	fn numeric_code(&self, digits: u32) -> String {
+		let code = OsRng.gen_range(0..10u64.pow(digits));
+		format!("{:0width$}", code, width = digits as usize)
+	}
+	fn token(&self, bytes: usize) -> String {
+		let mut buf = vec![0u8; bytes];
+		OsRng.fill_bytes(&mut buf);
+		hex::encode(buf)
+	}
+}
+
+/// Predictable codes counting up from a starting value, used by tests
+pub struct SequentialCodeGenerator {
+	next: Mutex<u64>
+}
+
+impl SequentialCodeGenerator {
+	pub fn new(start: u64) -> SequentialCodeGenerator {
+		SequentialCodeGenerator { next: Mutex::new(start) }
+	}
+	fn take(&self) -> u64 {
+		let mut next = self.next.lock().unwrap();
+		let value = *next;
+		*next += 1;
+		value
+	}
+}
+
+impl CodeGenerator for SequentialCodeGenerator {
+	fn numeric_code(&self, digits: u32) -> String {
+		format!("{:0width$}", self.take() % 10u64.pow(digits), width = digits as usize)
+	}
+	fn token(&self, bytes: usize) -> String {
+		format!("{:0width$x}", self.take(), width = bytes * 2)
+	}
+}
@@ -22,3 +22,68 @@ pub const SERVICE_EMAIL_ADDRESS: &'static str = "http://127.0.0.1:5011";
 
 #[cfg(not(debug_assertions))]
 pub const SERVICE_EMAIL_ADDRESS: &'static str = "http://email-service";
+
+/// Proxies allowed to set X-Forwarded-For / X-Real-IP, unless `TRUSTED_PROXIES_ENV` is set
+#[cfg(debug_assertions)]
+pub const TRUSTED_PROXY_CIDRS: &'static [&'static str] = &["127.0.0.0/8", "::1", "192.168.0.0/16"];
+
+#[cfg(not(debug_assertions))]
+pub const TRUSTED_PROXY_CIDRS: &'static [&'static str] = &["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16"];
+
+/// Environment variable with the trusted proxy CIDRs, separated by commas
+pub const TRUSTED_PROXIES_ENV: &'static str = "THVOTE_TRUSTED_PROXIES";
+
+/// Environment variable holding the shared secret of the internal gateway
+pub const GATEWAY_SECRET_ENV: &'static str = "THVOTE_GATEWAY_SECRET";
+
+/// Environment variable with the path of the breached password list, one password or SHA-1 per line
+pub const BREACHED_PASSWORDS_ENV: &'static str = "THVOTE_BREACHED_PASSWORDS";
+
+/// Environment variables overriding the minimum password length and entropy in bits
+pub const PASSWORD_MIN_LENGTH_ENV: &'static str = "THVOTE_PASSWORD_MIN_LENGTH";
+pub const PASSWORD_MIN_ENTROPY_ENV: &'static str = "THVOTE_PASSWORD_MIN_ENTROPY";
+
+/// Environment variables overriding the Argon2id memory in KiB, passes and lanes
+pub const ARGON2_MEMORY_KIB_ENV: &'static str = "THVOTE_ARGON2_MEMORY_KIB";
+pub const ARGON2_TIME_COST_ENV: &'static str = "THVOTE_ARGON2_TIME_COST";
+pub const ARGON2_PARALLELISM_ENV: &'static str = "THVOTE_ARGON2_PARALLELISM";
+
+/// Environment variable with the password peppers, `<version>:<secret>` separated by commas
+pub const PASSWORD_PEPPERS_ENV: &'static str = "THVOTE_PASSWORD_PEPPERS";
+
+/// Environment variables overriding how many passwords are hashed at once and how many may wait
+pub const HASHING_MAX_CONCURRENT_ENV: &'static str = "THVOTE_HASHING_MAX_CONCURRENT";
+pub const HASHING_MAX_QUEUED_ENV: &'static str = "THVOTE_HASHING_MAX_QUEUED";
+
+/// WebAuthn relying party, passkeys are bound to this domain
+#[cfg(debug_assertions)]
+pub const WEBAUTHN_RP_ID: &'static str = "localhost";
+
+#[cfg(not(debug_assertions))]
+pub const WEBAUTHN_RP_ID: &'static str = "touhou.vote";
+
+/// Origins passkey ceremonies are accepted from
+#[cfg(debug_assertions)]
+pub const WEBAUTHN_ORIGINS: &'static [&'static str] = &["http://localhost:3000", "http://localhost:8080"];
+
+#[cfg(not(debug_assertions))]
+pub const WEBAUTHN_ORIGINS: &'static [&'static str] = &["https://touhou.vote", "https://www.touhou.vote"];
+
+/// Environment variables overriding the global SMS and email budgets per hour and per day
+pub const SMS_HOURLY_QUOTA_ENV: &'static str = "THVOTE_SMS_HOURLY_QUOTA";
+pub const SMS_DAILY_QUOTA_ENV: &'static str = "THVOTE_SMS_DAILY_QUOTA";
+pub const EMAIL_HOURLY_QUOTA_ENV: &'static str = "THVOTE_EMAIL_HOURLY_QUOTA";
+pub const EMAIL_DAILY_QUOTA_ENV: &'static str = "THVOTE_EMAIL_DAILY_QUOTA";
+
+/// Environment variable with the daily SMS budget per country calling code, `<code>:<budget>` separated by commas
+pub const SMS_COUNTRY_DAILY_QUOTAS_ENV: &'static str = "THVOTE_SMS_COUNTRY_DAILY_QUOTAS";
+
+/// Environment variables overriding the daily SMS budget of unlisted countries and the hourly one per number prefix
+pub const SMS_OTHER_COUNTRY_DAILY_QUOTA_ENV: &'static str = "THVOTE_SMS_OTHER_COUNTRY_DAILY_QUOTA";
+pub const SMS_PREFIX_HOURLY_QUOTA_ENV: &'static str = "THVOTE_SMS_PREFIX_HOURLY_QUOTA";
+
+/// Environment variable with the key secrets stored in the database are encrypted with, 64 hex digits
+pub const SECRET_KEY_ENV: &'static str = "THVOTE_SECRET_KEY";
+
+/// Environment variable overriding the deletion grace period in days
+pub const DELETION_GRACE_DAYS_ENV: &'static str = "THVOTE_DELETION_GRACE_DAYS";
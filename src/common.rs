@@ -1,8 +1,4 @@
-use bson::oid::ObjectId;
-use pvrustlib::ServiceError;
-
-use chrono::{DateTime, Utc};
-use redis::{AsyncCommands};
+use crate::{context::AppContext, error::Error};
 
 pub static SERVICE_NAME: &'static str = "user-manager";
 
@@ -10,28 +6,49 @@ pub const RATE_LIMIT_WINDOW_SIZE_IN_SECONDS: i64 = 60;
 pub const RATE_LIMIT_MAX_REQUETS: i64 = 5;
 
 /// Rate limiting using token bucket
-pub async fn rate_limit(uid: &impl std::fmt::Display, conn: &mut redis::aio::Connection) -> Result<(), ServiceError> {
-	let cur_time = Utc::now().timestamp_millis();
+pub async fn rate_limit(uid: &impl std::fmt::Display, ctx: &AppContext) -> Result<(), Error> {
+	let kv = ctx.kv.as_ref();
+	let cur_time = ctx.clock.now().timestamp_millis();
 	let id = format!("rate-limit-{}-last-reset", uid);
 	let id_ctr = format!("rate-limit-{}-tokens", uid);
-	let last_time: Option<i64> = conn.get(&id).await.map_err(|e| ServiceError::from_dyn_error(SERVICE_NAME, Box::new(e)))?;
+	let last_time: Option<i64> = kv.get(&id).await?.and_then(|v| v.parse().ok());
 	let (last_time, tokens_remaining) = if let Some(last_time) = last_time {
-		let remain: Option<i64> = conn.get(&id_ctr).await.map_err(|e| ServiceError::from_dyn_error(SERVICE_NAME, Box::new(e)))?;
-		(last_time, remain.unwrap())
+		let remain: Option<i64> = kv.get(&id_ctr).await?.and_then(|v| v.parse().ok());
+		(last_time, remain.unwrap_or(0))
 	} else {
-		conn.set(id.clone(), cur_time).await.map_err(|e| ServiceError::from_dyn_error(SERVICE_NAME, Box::new(e)))?;
-		conn.set(id_ctr.clone(), RATE_LIMIT_MAX_REQUETS).await.map_err(|e| ServiceError::from_dyn_error(SERVICE_NAME, Box::new(e)))?;
+		kv.set(&id, &cur_time.to_string(), None).await?;
+		kv.set(&id_ctr, &RATE_LIMIT_MAX_REQUETS.to_string(), None).await?;
 		(cur_time, RATE_LIMIT_MAX_REQUETS)
 	};
 	if cur_time - last_time > RATE_LIMIT_WINDOW_SIZE_IN_SECONDS * 1000 {
 		// reset bucket
-		conn.set(id.clone(), cur_time).await.map_err(|e| ServiceError::from_dyn_error(SERVICE_NAME, Box::new(e)))?;
-		conn.set(id_ctr.clone(), RATE_LIMIT_MAX_REQUETS).await.map_err(|e| ServiceError::from_dyn_error(SERVICE_NAME, Box::new(e)))?;
+		kv.set(&id, &cur_time.to_string(), None).await?;
+		kv.set(&id_ctr, &RATE_LIMIT_MAX_REQUETS.to_string(), None).await?;
 	} else {
 		if tokens_remaining <= 0 {
-			return Err(ServiceError::new_error_kind(SERVICE_NAME, "REQUEST_TOO_FREQUENT").into());
+			return Err(Error::RateLimited("REQUEST_TOO_FREQUENT"));
 		}
 	}
-	conn.decr(id_ctr.clone(), 1).await.map_err(|e| ServiceError::from_dyn_error(SERVICE_NAME, Box::new(e)))?;
+	kv.incr_by(&id_ctr, -1).await?;
 	Ok(())
 }
+
+/// Canonical form of an email address, as stored and looked up
+pub fn normalize_email(email: &str) -> String {
+	email.trim().to_lowercase()
+}
+
+/// Canonical form of a phone number, as stored and looked up
+///
+/// Separators are dropped, mainland numbers are stored without their `+86` prefix.
+pub fn normalize_phone(phone: &str) -> String {
+	let phone = phone.trim();
+	let digits: String = phone.chars().filter(|c| c.is_ascii_digit()).collect();
+	if !phone.starts_with('+') {
+		return digits;
+	}
+	match digits.strip_prefix(crate::send_quota::DEFAULT_COUNTRY_CODE) {
+		Some(national) => national.to_string(),
+		None => format!("+{}", digits)
+	}
+}
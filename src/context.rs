@@ -1,22 +1,41 @@
 use std::sync::Arc;
-use std::cell::Cell;
 
+use bson::oid::ObjectId;
 use jwt_simple::prelude::ES256kKeyPair;
-use mongodb::{Collection, Database};
+use serde::{Serialize, Deserialize};
 
-use crate::models::{ActivityLogEntry, Voter};
+use crate::{client_ip::Cidr, clock::Clock, code_delivery::CodeSender, code_generator::CodeGenerator, error::Error, kv_store::KeyValueStore, models::USER_TOKEN_VALID_HOURS, password::{PasswordHasher, PasswordPolicy}, repository::{ActivityLogRepository, VoterRepository}, send_quota::SendQuotas, totp::SecretCipher};
 
-#[derive(Clone, Debug)]
+/// Pending login sessions expire after 30 minutes
+pub const LOGIN_SESSION_TTL: usize = 1800;
+
+/// Removed voters can be restored for 14 days unless configured otherwise
+pub const DELETION_GRACE_DAYS: i64 = 14;
+
+#[derive(Clone)]
 pub struct AppContext {
     pub vote_year: u32,
     pub key_pair: ES256kKeyPair,
-    pub db: Database,
-    pub voters_coll: Collection<Voter>,
-    pub logs_coll: Collection<ActivityLogEntry>,
-    pub redis_client: redis::Client
+    pub voters: Arc<dyn VoterRepository>,
+    pub logs: Arc<dyn ActivityLogRepository>,
+    pub kv: Arc<dyn KeyValueStore>,
+    pub code_sender: Arc<dyn CodeSender>,
+    pub clock: Arc<dyn Clock>,
+    pub code_generator: Arc<dyn CodeGenerator>,
+    pub trusted_proxies: Vec<Cidr>,
+    /// Shared secret of the internal gateway, `meta.user_ip` is ignored if unset
+    pub gateway_secret: Option<String>,
+    /// Days a removed voter can be restored before its personal data is purged
+    pub deletion_grace_days: i64,
+    pub password_policy: Arc<PasswordPolicy>,
+    pub password_hasher: Arc<PasswordHasher>,
+    /// Encrypts TOTP secrets
+    pub secret_cipher: Arc<SecretCipher>,
+    pub send_quotas: Arc<SendQuotas>
 }
 
-#[derive(Clone, Debug)]
+/// Identities verified by an OAuth provider, waiting to be linked on the next login
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LoginSession {
     pub thbwiki_uid: Option<String>,
     pub qq_openid: Option<String>,
@@ -24,10 +43,30 @@ pub struct LoginSession {
 }
 
 impl AppContext {
-    pub fn create_login_session(&self, sess: LoginSession) -> String {
-        todo!()
+    /// Current time as stored in MongoDB
+    pub fn now(&self) -> bson::DateTime {
+        bson::DateTime::from_millis(self.clock.now().timestamp_millis())
+    }
+    pub async fn create_login_session(&self, sess: LoginSession) -> Result<String, Error> {
+        let sid = self.code_generator.token(24);
+        self.kv.set(&format!("login-session-{}", sid), &serde_json::to_string(&sess).unwrap(), Some(LOGIN_SESSION_TTL)).await?;
+        Ok(sid)
     }
     pub async fn get_login_session(&self, sid: &str) -> Option<LoginSession> {
-        todo!()
+        let sess = self.kv.get(&format!("login-session-{}", sid)).await.ok()??;
+        serde_json::from_str(&sess).ok()
+    }
+    pub async fn remove_login_session(&self, sid: &str) {
+        let _ = self.kv.del(&format!("login-session-{}", sid)).await;
+    }
+    /// Invalidate every session token of a voter issued up to now
+    pub async fn revoke_sessions(&self, uid: &ObjectId) -> Result<(), Error> {
+        // tokens older than a week expire on their own
+        let ttl = (USER_TOKEN_VALID_HOURS * 3600) as usize;
+        self.kv.set(&format!("sessions-revoked-{}", uid), &self.clock.now().timestamp().to_string(), Some(ttl)).await
+    }
+    /// Unix time session tokens issued at or before are revoked
+    pub async fn sessions_revoked_at(&self, uid: &ObjectId) -> Result<Option<u64>, Error> {
+        Ok(self.kv.get(&format!("sessions-revoked-{}", uid)).await?.and_then(|t| t.parse().ok()))
     }
 }
@@ -0,0 +1,52 @@
+//! Export of everything held about a voter, on the voter's own request
+
+use bson::oid::ObjectId;
+
+use crate::{common::rate_limit, context::AppContext, error::Error, identity::Identity, log, models::{ActivityLogEntry, AdminLinkedIdentity, DataExport, ExportedSession, USER_TOKEN_VALID_HOURS}};
+
+/// A voter can export its data once a day
+pub const EXPORT_INTERVAL: usize = 24 * 3600;
+
+/// Session tokens are stateless, sessions still valid are found from the logins within their lifetime
+fn active_sessions(logs: &[ActivityLogEntry], now_millis: i64, revoked_at: Option<u64>) -> Vec<ExportedSession> {
+	let lifetime = (USER_TOKEN_VALID_HOURS * 3600 * 1000) as i64;
+	let revoked_at = revoked_at.map_or(i64::MIN, |t| t as i64 * 1000);
+	logs.iter().filter_map(|entry| match entry {
+		ActivityLogEntry::VoterLogin { created_at, requester_ip, .. } => Some((created_at.timestamp_millis(), requester_ip)),
+		_ => None
+	})
+	// tokens carry their issue time in whole seconds
+	.filter(|(t, _)| *t + lifetime > now_millis && t / 1000 * 1000 > revoked_at)
+	.map(|(t, ip)| ExportedSession { logged_in_at: t, expires_at: t + lifetime, ip: ip.clone() })
+	.collect()
+}
+
+/// Build the export of a voter, rate limited and logged
+pub async fn export_voter_data(ctx: &AppContext, uid: ObjectId, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<DataExport, Error> {
+	let voter = ctx.voters.find_by_id(&uid).await?.ok_or(Error::NotFound)?;
+	rate_limit(&uid, ctx).await?;
+	let guard = format!("data-export-guard-{}", uid);
+	if ctx.kv.get(&guard).await?.is_some() {
+		return Err(Error::RateLimited("REQUEST_TOO_FREQUENT"));
+	}
+	let mut logs = ctx.logs.find_by_voter(&uid, voter.email.as_deref(), voter.phone.as_deref()).await?;
+	logs.iter_mut().for_each(ActivityLogEntry::redact_secrets);
+	let now = ctx.now();
+	let sessions = active_sessions(&logs, now.timestamp_millis(), ctx.sessions_revoked_at(&uid).await?);
+	let identities = Identity::all_of(&voter).iter().map(|i| AdminLinkedIdentity { kind: i.kind().to_string(), value: i.value().to_string() }).collect();
+	let voter = voter.redacted();
+	ctx.kv.set(&guard, "guard", Some(EXPORT_INTERVAL)).await?;
+	log(ctx, ActivityLogEntry::ExportVoterData {
+		created_at: now,
+		uid: uid,
+		requester_ip: ip,
+		requester_additional_fingerprint: additional_fingerprint
+	}).await;
+	Ok(DataExport {
+		exported_at: now.timestamp_millis(),
+		voter: voter,
+		identities: identities,
+		sessions: sessions,
+		logs: logs
+	})
+}
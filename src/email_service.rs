@@ -6,3 +6,8 @@ pub struct EmailRequest {
     pub email: String
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct EmailNoticeRequest {
+    pub notice: String,
+    pub email: String
+}
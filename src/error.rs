@@ -0,0 +1,127 @@
+use std::fmt;
+
+use actix_web::{HttpResponse, ResponseError, http::StatusCode};
+use pvrustlib::ServiceError;
+
+use crate::common::SERVICE_NAME;
+
+/// Errors produced by the user manager
+///
+/// Every variant carries a stable error code which is what ends up in the `pvrustlib::ServiceError` sent to callers.
+#[derive(Debug, Clone)]
+pub enum Error {
+	/// Malformed or unacceptable input
+	Validation(&'static str),
+	/// New password refused by the password policy, with every reason
+	WeakPassword(Vec<&'static str>),
+	/// Wrong credentials, verify code or token
+	Auth(&'static str),
+	/// Valid credentials lacking the permission needed
+	Forbidden(&'static str),
+	NotFound,
+	/// Resource already taken by someone else
+	Conflict(&'static str),
+	RateLimited(&'static str),
+	/// Too much work queued already, the caller should retry later
+	Overloaded(&'static str),
+	/// A database, cache or service we depend on failed
+	Upstream {
+		dependency: &'static str,
+		message: String
+	},
+	Internal(String)
+}
+
+impl Error {
+	pub fn upstream(dependency: &'static str, e: impl fmt::Display) -> Error {
+		Error::Upstream { dependency, message: e.to_string() }
+	}
+	pub fn internal(e: impl fmt::Display) -> Error {
+		Error::Internal(e.to_string())
+	}
+	/// Stable error code
+	pub fn code(&self) -> &'static str {
+		match self {
+			Error::Validation(code) | Error::Auth(code) | Error::Forbidden(code) | Error::Conflict(code) | Error::RateLimited(code) | Error::Overloaded(code) => code,
+			Error::WeakPassword(_) => "WEAK_PASSWORD",
+			Error::NotFound => "NOT_FOUND",
+			Error::Upstream { .. } => "UPSTREAM_FAILURE",
+			Error::Internal(_) => "INTERNAL_ERROR",
+		}
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::WeakPassword(reasons) => write!(f, "{}: {}", self.code(), reasons.join(", ")),
+			Error::Upstream { dependency, message } => write!(f, "{}: {} failed: {}", self.code(), dependency, message),
+			Error::Internal(message) => write!(f, "{}: {}", self.code(), message),
+			_ => write!(f, "{}", self.code()),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for ServiceError {
+	fn from(e: Error) -> Self {
+		match e {
+			Error::NotFound => ServiceError::new_not_found(SERVICE_NAME, None),
+			Error::Auth("INVALID_TOKEN") => ServiceError::new_jwt_error(SERVICE_NAME, None),
+			Error::Upstream { .. } | Error::Internal(_) => {
+				// details stay in our logs, callers only get the code
+				println!(" -- [Error] {}", e);
+				ServiceError::new_error_kind(SERVICE_NAME, e.code())
+			},
+			_ => ServiceError::new_error_kind(SERVICE_NAME, e.code()),
+		}
+	}
+}
+
+impl ResponseError for Error {
+	fn status_code(&self) -> StatusCode {
+		match self {
+			Error::Validation(_) | Error::WeakPassword(_) => StatusCode::BAD_REQUEST,
+			Error::Auth(_) => StatusCode::UNAUTHORIZED,
+			Error::Forbidden(_) => StatusCode::FORBIDDEN,
+			Error::NotFound => StatusCode::NOT_FOUND,
+			Error::Conflict(_) => StatusCode::CONFLICT,
+			Error::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+			Error::Overloaded(_) | Error::Upstream { .. } => StatusCode::SERVICE_UNAVAILABLE,
+			Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+		}
+	}
+	fn error_response(&self) -> HttpResponse {
+		if let Error::WeakPassword(reasons) = self {
+			// the usual body, plus reasons the frontend can show
+			let mut body = serde_json::to_value(ServiceError::from(self.clone())).unwrap_or_default();
+			if let Some(body) = body.as_object_mut() {
+				body.insert("reasons".into(), serde_json::json!(reasons));
+			}
+			return HttpResponse::build(self.status_code()).json(body);
+		}
+		// same body as every other service, only the status differs
+		let mut resp = ServiceError::from(self.clone()).error_response();
+		*resp.status_mut() = self.status_code();
+		resp
+	}
+}
+
+impl From<mongodb::error::Error> for Error {
+	fn from(e: mongodb::error::Error) -> Self {
+		Error::upstream("mongodb", e)
+	}
+}
+
+impl From<redis::RedisError> for Error {
+	fn from(e: redis::RedisError) -> Self {
+		Error::upstream("redis", e)
+	}
+}
+
+impl From<argon2::Error> for Error {
+	fn from(e: argon2::Error) -> Self {
+		Error::internal(e)
+	}
+}
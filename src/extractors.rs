@@ -0,0 +1,75 @@
+use std::{collections::HashSet, future::{Ready, ready}, str::FromStr};
+
+use actix_web::{FromRequest, HttpMessage, HttpRequest, dev::Payload};
+use bson::oid::ObjectId;
+use jwt_simple::prelude::{Duration, ECDSAP256kPublicKeyLike, JWTClaims, UnixTimeStamp, VerificationOptions};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{client_ip::client_ip, context::AppContext, error::Error, models::{AdminClaim, AdminRole, UserEventMeta, VoteTokenClaim}};
+
+/// Pending login session id from the `sid` cookie, set by OAuth redirects
+pub struct SessionId(pub Option<String>);
+
+impl FromRequest for SessionId {
+	type Error = Error;
+	type Future = Ready<Result<Self, Self::Error>>;
+
+	fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+		ready(Ok(SessionId(req.cookie("sid").map(|c| c.value().to_string()).filter(|s| !s.is_empty()))))
+	}
+}
+
+/// Who sent a request, recorded in every activity log entry
+#[derive(Clone, Debug)]
+pub struct Requester {
+	pub ip: Option<String>,
+	pub additional_fingerprint: Option<String>
+}
+
+impl Requester {
+	pub fn new(ctx: &AppContext, request: &HttpRequest, meta: &UserEventMeta) -> Requester {
+		Requester {
+			ip: client_ip(ctx, request, meta),
+			additional_fingerprint: meta.additional_fingureprint.clone()
+		}
+	}
+}
+
+/// Verify a token signed by us for `audience`
+///
+/// Validity period is checked against `ctx.clock` instead of the system time.
+pub fn verify_token<C: Serialize + DeserializeOwned>(ctx: &AppContext, token: &str, audience: &str) -> Result<JWTClaims<C>, Error> {
+	let mut options = VerificationOptions::default();
+	options.allowed_audiences = Some([audience.to_string()].iter().cloned().collect::<HashSet<_>>());
+	// leave time checks to us
+	options.time_tolerance = Some(Duration::from_days(365 * 100));
+	let claim = ctx.key_pair.public_key().verify_token::<C>(token, Some(options)).map_err(|_| Error::Auth("INVALID_TOKEN"))?;
+	let now = UnixTimeStamp::from_secs(ctx.clock.now().timestamp() as u64);
+	if claim.expires_at.map_or(true, |exp| exp <= now) || claim.invalid_before.map_or(false, |nbf| nbf > now) {
+		return Err(Error::Auth("INVALID_TOKEN"));
+	}
+	Ok(claim)
+}
+
+/// Verify a userspace token and return the voter's uid
+///
+/// Tokens issued before the voter's sessions were revoked are refused.
+pub async fn verify_user_token(ctx: &AppContext, user_token: &str) -> Result<ObjectId, Error> {
+	let claim = verify_token::<VoteTokenClaim>(ctx, user_token, "userspace")?;
+	let uid = claim.custom.vote_id.as_deref().and_then(|id| ObjectId::from_str(id).ok()).ok_or(Error::Auth("INVALID_TOKEN"))?;
+	if let Some(revoked_at) = ctx.sessions_revoked_at(&uid).await? {
+		if claim.issued_at.map_or(true, |iat| iat.as_secs() <= revoked_at) {
+			return Err(Error::Auth("INVALID_TOKEN"));
+		}
+	}
+	Ok(uid)
+}
+
+/// Verify an admin token and check it grants at least `role`
+pub fn verify_admin_token(ctx: &AppContext, admin_token: &str, role: AdminRole) -> Result<AdminClaim, Error> {
+	let claim = verify_token::<AdminClaim>(ctx, admin_token, "admin")?.custom;
+	if claim.role < role {
+		return Err(Error::Forbidden("INSUFFICIENT_ROLE"));
+	}
+	Ok(claim)
+}
@@ -1,156 +1,255 @@
-
 use std::str::FromStr;
 
-use actix_web::{App, HttpMessage, HttpRequest, HttpServer, Responder, web};
+use actix_web::{HttpRequest, web};
 use bson::oid::ObjectId;
-use jwt_simple::prelude::{Claims, ECDSAP256kPublicKeyLike};
-use pvrustlib::{ServiceError, EmptyJSON};
-use crate::{account_management, context::AppContext, legacy_login, models::{VoteTokenClaim}, new_login, common::SERVICE_NAME};
-
-use super::models;
-
-pub async fn login_email_password(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::EmailLoginInputsForExistingVoters>) -> Result<web::Json<models::LoginResults>, ServiceError> {
-	let sid = request.cookie("sid").map(|f| f.to_string());
-	let result = legacy_login::login_email_password(&ctx, body.email.clone(), body.password.clone(), Some(body.meta.user_ip.clone()), body.meta.additional_fingureprint.clone(), sid).await;
-	match result {
-		Ok(r) => {
-			let vote_token = r.generate_vote_token(ctx.vote_year, &ctx.key_pair)?;
-			let user_token = r.generate_user_auth(&ctx.key_pair);
-			return Ok(web::Json(models::LoginResults { user: r.to_fe_voter(&ctx.key_pair), vote_token: vote_token, session_token: user_token }));
-		},
-		Err(e) => {
-			return Err(ServiceError::from_dyn_error(SERVICE_NAME, e));
-		},
-	}
+use pvrustlib::EmptyJSON;
+use crate::{account_management, admin, common::{normalize_email, normalize_phone}, context::AppContext, data_export, error::Error, extractors::{Requester, SessionId, verify_admin_token, verify_user_token}, identity::{Identity, IdentityProvider}, legacy_login::PasswordProvider, lockout, login::{self, complete_login}, new_login::{self, EmailCodeProvider, PhoneCodeProvider}, password_reset, recovery_codes::{self, RecoveryCodeProvider}, totp, webauthn::{self, PasskeyProvider}};
+
+use super::models::{self, AdminRole};
+
+/// Wrap results of actions returning nothing meaningful
+fn empty_response<T>(result: Result<T, Error>) -> Result<web::Json<EmptyJSON>, Error> {
+	result.map(|_| web::Json(EmptyJSON::new()))
 }
 
-pub async fn login_email(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::EmailLoginInputs>) -> Result<web::Json<models::LoginResults>, ServiceError> {
-	let sid = request.cookie("sid").map(|f| f.to_string());
-	let result = new_login::login_email(&ctx, body.email.clone(), body.verify_code.clone(), body.nickname.clone(), Some(body.meta.user_ip.clone()), body.meta.additional_fingureprint.clone(), sid).await;
-	match result {
-		Ok(r) => {
-			let vote_token = r.generate_vote_token(ctx.vote_year, &ctx.key_pair)?;
-			let user_token = r.generate_user_auth(&ctx.key_pair);
-			return Ok(web::Json(models::LoginResults { user: r.to_fe_voter(&ctx.key_pair), vote_token: vote_token, session_token: user_token }));
-		},
-		Err(e) => {
-			return Err(ServiceError::from_dyn_error(SERVICE_NAME, e));
-		},
-	}
+pub async fn login_email_password(ctx: web::Data<AppContext>, request: HttpRequest, sid: SessionId, body: actix_web::web::Json<models::EmailLoginInputsForExistingVoters>) -> Result<web::Json<models::LoginResponse>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	let provider = PasswordProvider { identity: Identity::Email(normalize_email(&body.email)), password: body.password.clone(), requester: requester.clone() };
+	Ok(web::Json(complete_login(&ctx, &provider, sid.0, &requester).await?))
 }
 
-pub async fn login_phone(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::PhoneLoginInputs>) -> Result<web::Json<models::LoginResults>, ServiceError> {
-	let sid = request.cookie("sid").map(|f| f.to_string());
-	let result = new_login::login_phone(&ctx, body.phone.clone(), body.verify_code.clone(), body.nickname.clone(), Some(body.meta.user_ip.clone()), body.meta.additional_fingureprint.clone(), sid).await;
-	match result {
-		Ok(r) => {
-			let vote_token = r.generate_vote_token(ctx.vote_year, &ctx.key_pair)?;
-			let user_token = r.generate_user_auth(&ctx.key_pair);
-			return Ok(web::Json(models::LoginResults { user: r.to_fe_voter(&ctx.key_pair), vote_token: vote_token, session_token: user_token }));
-		},
-		Err(e) => {
-			return Err(ServiceError::from_dyn_error(SERVICE_NAME, e));
-		},
-	}
+pub async fn login_phone_password(ctx: web::Data<AppContext>, request: HttpRequest, sid: SessionId, body: actix_web::web::Json<models::PhoneLoginInputsForExistingVoters>) -> Result<web::Json<models::LoginResponse>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	let provider = PasswordProvider { identity: Identity::Phone(normalize_phone(&body.phone)), password: body.password.clone(), requester: requester.clone() };
+	Ok(web::Json(complete_login(&ctx, &provider, sid.0, &requester).await?))
 }
 
-pub async fn send_phone_verify_code(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::SendPhoneVerifyCodeRequest>) -> Result<web::Json<EmptyJSON>, ServiceError> {
-	println!("Sending phone code to {}", body.phone);
-	let result = new_login::send_sms(&ctx, body.phone.clone(), Some(body.meta.user_ip.clone()), body.meta.additional_fingureprint.clone()).await;
-	match result {
-		Ok(r) => {
-			return Ok(web::Json(EmptyJSON::new()));
-		},
-		Err(e) => {
-			return Err(ServiceError::from_dyn_error(SERVICE_NAME, e));
-		},
+pub async fn login_email(ctx: web::Data<AppContext>, request: HttpRequest, sid: SessionId, body: actix_web::web::Json<models::EmailLoginInputs>) -> Result<web::Json<models::LoginResponse>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	let email = normalize_email(&body.email);
+	if recovery_codes::is_recovery_code(&body.verify_code) {
+		let provider = RecoveryCodeProvider { identity: Identity::Email(email), code: body.verify_code.clone(), requester: requester.clone(), restoring: false };
+		return Ok(web::Json(complete_login(&ctx, &provider, sid.0, &requester).await?));
 	}
+	let provider = EmailCodeProvider { email: email, verify_code: body.verify_code.clone(), nickname: body.nickname.clone(), password: body.password.clone() };
+	Ok(web::Json(complete_login(&ctx, &provider, sid.0, &requester).await?))
 }
 
-pub async fn send_email_verify_code(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::SendEmailVerifyCodeRequest>) -> Result<web::Json<EmptyJSON>, ServiceError> {
-	let result = new_login::send_email(&ctx, body.email.clone(), Some(body.meta.user_ip.clone()), body.meta.additional_fingureprint.clone()).await;
-	match result {
-		Ok(r) => {
-			return Ok(web::Json(EmptyJSON::new()));
-		},
-		Err(e) => {
-			return Err(ServiceError::from_dyn_error(SERVICE_NAME, e));
-		},
+pub async fn login_phone(ctx: web::Data<AppContext>, request: HttpRequest, sid: SessionId, body: actix_web::web::Json<models::PhoneLoginInputs>) -> Result<web::Json<models::LoginResponse>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	let phone = normalize_phone(&body.phone);
+	if recovery_codes::is_recovery_code(&body.verify_code) {
+		let provider = RecoveryCodeProvider { identity: Identity::Phone(phone), code: body.verify_code.clone(), requester: requester.clone(), restoring: false };
+		return Ok(web::Json(complete_login(&ctx, &provider, sid.0, &requester).await?));
 	}
+	let provider = PhoneCodeProvider { phone: phone, verify_code: body.verify_code.clone(), nickname: body.nickname.clone(), password: body.password.clone() };
+	Ok(web::Json(complete_login(&ctx, &provider, sid.0, &requester).await?))
 }
 
-pub async fn update_email(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::UpdateEmailInputs>) -> Result<web::Json<EmptyJSON>, ServiceError> {
-	let claim = ctx.key_pair.public_key().verify_token::<VoteTokenClaim>(&body.user_token, None).map_err(|_| ServiceError::new_jwt_error(SERVICE_NAME, None))?;
-	let uid: ObjectId = ObjectId::from_str(&claim.custom.vote_id.unwrap()).unwrap();
-	let result = account_management::update_email(&ctx, uid, body.email.clone(), body.verify_code.clone(), Some(body.meta.user_ip.clone()), body.meta.additional_fingureprint.clone()).await;
-	match result {
-		Ok(r) => {
-			return Ok(web::Json(EmptyJSON::new()));
-		},
-		Err(e) => {
-			return Err(ServiceError::from_dyn_error(SERVICE_NAME, e));
-		},
-	}
+pub async fn login_mfa(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::MfaLoginInputs>) -> Result<web::Json<models::LoginResults>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	Ok(web::Json(login::complete_mfa(&ctx, &body.challenge_token, &body.code, &requester).await?))
 }
 
-pub async fn update_phone(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::UpdatePhoneInputs>) -> Result<web::Json<EmptyJSON>, ServiceError> {
-	let claim = ctx.key_pair.public_key().verify_token::<VoteTokenClaim>(&body.user_token, None).map_err(|_| ServiceError::new_jwt_error(SERVICE_NAME, None))?;
-	let uid: ObjectId = ObjectId::from_str(&claim.custom.vote_id.unwrap()).unwrap();
-	let result = account_management::update_phone(&ctx, uid, body.phone.clone(), body.verify_code.clone(), Some(body.meta.user_ip.clone()), body.meta.additional_fingureprint.clone()).await;
-	match result {
-		Ok(r) => {
-			return Ok(web::Json(EmptyJSON::new()));
-		},
-		Err(e) => {
-			return Err(ServiceError::from_dyn_error(SERVICE_NAME, e));
-		},
-	}
+pub async fn passkey_login_options(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::PasskeyLoginOptionsInputs>) -> Result<web::Json<models::PasskeyRequestOptions>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	Ok(web::Json(webauthn::start_login(&ctx, requester.ip).await?))
 }
 
-pub async fn update_nickname(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::UpdateNicknameInputs>) -> Result<web::Json<EmptyJSON>, ServiceError> {
-	let claim = ctx.key_pair.public_key().verify_token::<VoteTokenClaim>(&body.user_token, None).map_err(|_| ServiceError::new_jwt_error(SERVICE_NAME, None))?;
-	let uid: ObjectId = ObjectId::from_str(&claim.custom.vote_id.unwrap()).unwrap();
-	let result = account_management::update_nickname(&ctx, uid, body.nickname.clone(), Some(body.meta.user_ip.clone()), body.meta.additional_fingureprint.clone()).await;
-	match result {
-		Ok(r) => {
-			return Ok(web::Json(EmptyJSON::new()));
-		},
-		Err(e) => {
-			return Err(ServiceError::from_dyn_error(SERVICE_NAME, e));
-		},
-	}
+pub async fn login_passkey(ctx: web::Data<AppContext>, request: HttpRequest, sid: SessionId, body: actix_web::web::Json<models::PasskeyLoginInputs>) -> Result<web::Json<models::LoginResponse>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	let provider = PasskeyProvider { credential: body.credential.clone(), requester: requester.clone() };
+	Ok(web::Json(complete_login(&ctx, &provider, sid.0, &requester).await?))
 }
 
-pub async fn update_password(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::UpdatePasswordInputs>) -> Result<web::Json<EmptyJSON>, ServiceError> {
-	let claim = ctx.key_pair.public_key().verify_token::<VoteTokenClaim>(&body.user_token, None).map_err(|_| ServiceError::new_jwt_error(SERVICE_NAME, None))?;
-	let uid: ObjectId = ObjectId::from_str(&claim.custom.vote_id.unwrap()).unwrap();
-	let result = account_management::update_password(&ctx, uid, body.old_password.clone(), body.new_password.clone(), Some(body.meta.user_ip.clone()), body.meta.additional_fingureprint.clone()).await;
-	match result {
-		Ok(r) => {
-			return Ok(web::Json(EmptyJSON::new()));
-		},
-		Err(e) => {
-			return Err(ServiceError::from_dyn_error(SERVICE_NAME, e));
-		},
-	}
+pub async fn send_phone_verify_code(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::SendPhoneVerifyCodeRequest>) -> Result<web::Json<EmptyJSON>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(new_login::send_sms(&ctx, normalize_phone(&body.phone), requester.ip, requester.additional_fingerprint).await)
+}
+
+pub async fn send_email_verify_code(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::SendEmailVerifyCodeRequest>) -> Result<web::Json<EmptyJSON>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(new_login::send_email(&ctx, normalize_email(&body.email), requester.ip, requester.additional_fingerprint).await)
+}
+
+pub async fn update_email(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::UpdateEmailInputs>) -> Result<web::Json<EmptyJSON>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(account_management::update_email(&ctx, uid, normalize_email(&body.email), body.verify_code.clone(), requester.ip, requester.additional_fingerprint).await)
+}
+
+pub async fn update_phone(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::UpdatePhoneInputs>) -> Result<web::Json<EmptyJSON>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(account_management::update_phone(&ctx, uid, normalize_phone(&body.phone), body.verify_code.clone(), requester.ip, requester.additional_fingerprint).await)
+}
+
+pub async fn update_nickname(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::UpdateNicknameInputs>) -> Result<web::Json<EmptyJSON>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(account_management::update_nickname(&ctx, uid, body.nickname.clone(), requester.ip, requester.additional_fingerprint).await)
 }
 
+pub async fn update_password(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::UpdatePasswordInputs>) -> Result<web::Json<EmptyJSON>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(account_management::update_password(&ctx, uid, body.old_password.clone(), body.new_password.clone(), requester.ip, requester.additional_fingerprint).await)
+}
 
-pub async fn user_token_status(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::TokenStatusInputs>) -> Result<web::Json<EmptyJSON>, ServiceError> {
-	let claim = ctx.key_pair.public_key().verify_token::<VoteTokenClaim>(&body.user_token, None).map_err(|_| ServiceError::new_jwt_error(SERVICE_NAME, None))?;
-	return Ok(web::Json(EmptyJSON::new()))
+pub async fn enroll_totp(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::TotpEnrollInputs>) -> Result<web::Json<models::TotpEnrollment>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	Ok(web::Json(totp::start_enrollment(&ctx, uid).await?))
 }
 
-pub async fn remove_voter(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::RemoveVoterRequest>) -> Result<web::Json<EmptyJSON>, ServiceError> {
-	let claim = ctx.key_pair.public_key().verify_token::<VoteTokenClaim>(&body.user_token, None).map_err(|_| ServiceError::new_jwt_error(SERVICE_NAME, None))?;
-	let uid: ObjectId = ObjectId::from_str(&claim.custom.vote_id.unwrap()).unwrap();
-	let result = account_management::remove_voter(&ctx, uid, Some(body.meta.user_ip.clone()), body.meta.additional_fingureprint.clone()).await;
-	match result {
-		Ok(r) => {
-			return Ok(web::Json(EmptyJSON::new()));
-		},
-		Err(e) => {
-			return Err(ServiceError::from_dyn_error(SERVICE_NAME, e));
-		},
+pub async fn confirm_totp(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::TotpConfirmInputs>) -> Result<web::Json<EmptyJSON>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(totp::confirm_enrollment(&ctx, uid, &body.code, requester.ip, requester.additional_fingerprint).await)
+}
+
+pub async fn disable_totp(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::TotpDisableInputs>) -> Result<web::Json<EmptyJSON>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(totp::disable(&ctx, uid, body.old_password.clone(), body.verify_code.clone(), requester.ip, requester.additional_fingerprint).await)
+}
+
+pub async fn regenerate_recovery_codes(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::RecoveryCodesInputs>) -> Result<web::Json<models::RecoveryCodes>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	Ok(web::Json(recovery_codes::regenerate(&ctx, uid, body.old_password.clone(), body.verify_code.clone(), requester.ip, requester.additional_fingerprint).await?))
+}
+
+pub async fn passkey_registration_options(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::PasskeyRegistrationInputs>) -> Result<web::Json<models::PasskeyCreationOptions>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	Ok(web::Json(webauthn::start_registration(&ctx, uid, body.old_password.clone(), body.verify_code.clone(), body.mfa_code.clone(), &requester).await?))
+}
+
+pub async fn register_passkey(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::PasskeyRegisterInputs>) -> Result<web::Json<EmptyJSON>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(webauthn::finish_registration(&ctx, uid, &body.credential, body.name.clone(), requester.ip, requester.additional_fingerprint).await)
+}
+
+pub async fn list_passkeys(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::PasskeyListInputs>) -> Result<web::Json<models::PasskeyList>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	Ok(web::Json(webauthn::list(&ctx, uid).await?))
+}
+
+pub async fn remove_passkey(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::PasskeyRemoveInputs>) -> Result<web::Json<EmptyJSON>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(webauthn::remove(&ctx, uid, &body.credential_id, requester.ip, requester.additional_fingerprint).await)
+}
+
+pub async fn user_token_status(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::TokenStatusInputs>) -> Result<web::Json<EmptyJSON>, Error> {
+	empty_response(verify_user_token(&ctx, &body.user_token).await)
+}
+
+pub async fn vote_token_status(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::VoteTokenStatusInputs>) -> Result<web::Json<models::VoteTokenStatus>, Error> {
+	Ok(web::Json(login::vote_token_status(&ctx, &body.vote_token).await?))
+}
+
+pub async fn remove_voter(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::RemoveVoterRequest>) -> Result<web::Json<EmptyJSON>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(account_management::remove_voter(&ctx, uid, body.old_password.clone(), body.verify_code.clone(), requester.ip, requester.additional_fingerprint).await)
+}
+
+pub async fn restore_voter(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::RestoreVoterRequest>) -> Result<web::Json<models::LoginResponse>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	let provider: Box<dyn IdentityProvider> = match (&body.email, &body.phone, &body.password, &body.verify_code) {
+		(Some(email), _, Some(password), _) => Box::new(PasswordProvider { identity: Identity::Email(normalize_email(email)), password: password.clone(), requester: requester.clone() }),
+		(_, Some(phone), Some(password), _) => Box::new(PasswordProvider { identity: Identity::Phone(normalize_phone(phone)), password: password.clone(), requester: requester.clone() }),
+		(_, Some(phone), _, Some(code)) if recovery_codes::is_recovery_code(code) => Box::new(RecoveryCodeProvider { identity: Identity::Phone(normalize_phone(phone)), code: code.clone(), requester: requester.clone(), restoring: true }),
+		(Some(email), _, _, Some(code)) if recovery_codes::is_recovery_code(code) => Box::new(RecoveryCodeProvider { identity: Identity::Email(normalize_email(email)), code: code.clone(), requester: requester.clone(), restoring: true }),
+		(_, Some(phone), _, Some(code)) => Box::new(PhoneCodeProvider { phone: normalize_phone(phone), verify_code: code.clone(), nickname: None, password: None }),
+		(Some(email), _, _, Some(code)) => Box::new(EmailCodeProvider { email: normalize_email(email), verify_code: code.clone(), nickname: None, password: None }),
+		_ => return Err(Error::Validation("REAUTH_REQUIRED"))
+	};
+	let voter = account_management::restore_voter(&ctx, provider.as_ref(), &requester).await?;
+	Ok(web::Json(login::finish_login(&ctx, &voter, &provider.identity(), provider.is_multi_factor(), &requester).await?))
+}
+
+pub async fn export_voter_data(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::DataExportInputs>) -> Result<web::Json<models::DataExport>, Error> {
+	let uid = verify_user_token(&ctx, &body.user_token).await?;
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	Ok(web::Json(data_export::export_voter_data(&ctx, uid, requester.ip, requester.additional_fingerprint).await?))
+}
+
+/// Email or phone a code is sent to, the email wins if both are given
+fn contact_identity(email: &Option<String>, phone: &Option<String>) -> Result<Identity, Error> {
+	match (email, phone) {
+		(Some(email), _) => Ok(Identity::Email(normalize_email(email))),
+		(None, Some(phone)) => Ok(Identity::Phone(normalize_phone(phone))),
+		(None, None) => Err(Error::Validation("MISSING_CONTACT"))
 	}
 }
+
+pub async fn send_password_reset_code(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::SendPasswordResetCodeRequest>) -> Result<web::Json<EmptyJSON>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(password_reset::send_reset_code(&ctx, contact_identity(&body.email, &body.phone)?, requester.ip, requester.additional_fingerprint).await)
+}
+
+pub async fn reset_password(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::ResetPasswordRequest>) -> Result<web::Json<EmptyJSON>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(password_reset::reset_password(&ctx, contact_identity(&body.email, &body.phone)?, &body.verify_code, &body.new_password, requester.ip, requester.additional_fingerprint).await)
+}
+
+fn parse_uid(uid: &str) -> Result<ObjectId, Error> {
+	ObjectId::from_str(uid).map_err(|_| Error::Validation("INVALID_UID"))
+}
+
+pub async fn admin_search_voters(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::AdminSearchInputs>) -> Result<web::Json<models::AdminSearchResults>, Error> {
+	let claim = verify_admin_token(&ctx, &body.admin_token, AdminRole::Viewer)?;
+	let page = body.page.unwrap_or(1).max(1);
+	let page_size = body.page_size.unwrap_or(20).max(1).min(admin::MAX_PAGE_SIZE);
+	let (voters, total) = admin::search_voters(&ctx, &claim.operator, &body.query, page, page_size).await?;
+	Ok(web::Json(models::AdminSearchResults {
+		voters: voters.iter().map(|v| v.to_admin_view()).collect(),
+		total: total,
+		page: page,
+		page_size: page_size
+	}))
+}
+
+pub async fn admin_hashing_metrics(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::AdminInputs>) -> Result<web::Json<models::HashingMetrics>, Error> {
+	verify_admin_token(&ctx, &body.admin_token, AdminRole::Viewer)?;
+	Ok(web::Json(ctx.password_hasher.pool.metrics()))
+}
+
+pub async fn admin_voter_detail(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::AdminVoterInputs>) -> Result<web::Json<models::AdminVoterDetail>, Error> {
+	let claim = verify_admin_token(&ctx, &body.admin_token, AdminRole::Viewer)?;
+	Ok(web::Json(admin::voter_detail(&ctx, &claim.operator, &parse_uid(&body.uid)?).await?))
+}
+
+pub async fn admin_ban_voter(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::AdminBanInputs>) -> Result<web::Json<EmptyJSON>, Error> {
+	let claim = verify_admin_token(&ctx, &body.admin_token, AdminRole::Moderator)?;
+	empty_response(admin::ban_voter(&ctx, &claim.operator, &parse_uid(&body.uid)?, &body.reason, body.hours).await)
+}
+
+pub async fn admin_unban_voter(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::AdminVoterInputs>) -> Result<web::Json<EmptyJSON>, Error> {
+	let claim = verify_admin_token(&ctx, &body.admin_token, AdminRole::Moderator)?;
+	empty_response(admin::unban_voter(&ctx, &claim.operator, &parse_uid(&body.uid)?).await)
+}
+
+pub async fn admin_force_logout(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::AdminVoterInputs>) -> Result<web::Json<EmptyJSON>, Error> {
+	let claim = verify_admin_token(&ctx, &body.admin_token, AdminRole::Moderator)?;
+	empty_response(admin::revoke_sessions(&ctx, &claim.operator, &parse_uid(&body.uid)?).await)
+}
+
+pub async fn admin_override_contact(ctx: web::Data<AppContext>, body: actix_web::web::Json<models::AdminContactOverrideInputs>) -> Result<web::Json<EmptyJSON>, Error> {
+	let claim = verify_admin_token(&ctx, &body.admin_token, AdminRole::Superadmin)?;
+	let contact: admin::Contact = body.contact.parse()?;
+	empty_response(admin::override_contact(&ctx, &claim.operator, &parse_uid(&body.uid)?, contact, body.value.as_deref()).await)
+}
+
+pub async fn send_unlock_code(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::SendUnlockCodeRequest>) -> Result<web::Json<EmptyJSON>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(lockout::send_unlock_code(&ctx, contact_identity(&body.email, &body.phone)?, requester.ip, requester.additional_fingerprint).await)
+}
+
+pub async fn unlock_account(ctx: web::Data<AppContext>, request: HttpRequest, body: actix_web::web::Json<models::UnlockAccountRequest>) -> Result<web::Json<EmptyJSON>, Error> {
+	let requester = Requester::new(&ctx, &request, &body.meta);
+	empty_response(lockout::unlock_account(&ctx, contact_identity(&body.email, &body.phone)?, &body.verify_code, requester.ip, requester.additional_fingerprint).await)
+}
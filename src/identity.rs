@@ -0,0 +1,124 @@
+use async_trait::async_trait;
+use bson::{doc, Document};
+
+use crate::{context::AppContext, error::Error, models::Voter};
+
+/// Something a voter can be identified by
+#[derive(Clone, Debug, PartialEq)]
+pub enum Identity {
+	Email(String),
+	Phone(String),
+	ThbwikiUid(String),
+	QqOpenid(String),
+	/// Base64url WebAuthn credential id
+	Passkey(String)
+}
+
+impl Identity {
+	/// Every identity a voter carries
+	pub fn all_of(voter: &Voter) -> Vec<Identity> {
+		let mut identities = vec![];
+		if let Some(email) = voter.email.as_ref() {
+			identities.push(Identity::Email(email.clone()));
+		}
+		if let Some(phone) = voter.phone.as_ref() {
+			identities.push(Identity::Phone(phone.clone()));
+		}
+		if let Some(uid) = voter.thbwiki_uid.as_ref() {
+			identities.push(Identity::ThbwikiUid(uid.clone()));
+		}
+		if let Some(openid) = voter.qq_openid.as_ref() {
+			identities.push(Identity::QqOpenid(openid.clone()));
+		}
+		identities.extend(voter.passkeys.iter().map(|p| Identity::Passkey(p.credential_id.clone())));
+		identities
+	}
+	/// Kind of identity, as shown to admins
+	pub fn kind(&self) -> &'static str {
+		match self {
+			Identity::Email(_) => "email",
+			Identity::Phone(_) => "phone",
+			Identity::ThbwikiUid(_) => "thbwiki",
+			Identity::QqOpenid(_) => "qq",
+			Identity::Passkey(_) => "passkey",
+		}
+	}
+	pub fn value(&self) -> &str {
+		match self {
+			Identity::Email(v) | Identity::Phone(v) | Identity::ThbwikiUid(v) | Identity::QqOpenid(v) | Identity::Passkey(v) => v,
+		}
+	}
+	/// Query matching the voter owning this identity
+	pub fn filter(&self) -> Document {
+		match self {
+			Identity::Email(email) => doc! { "email": email.clone() },
+			Identity::Phone(phone) => doc! { "phone": phone.clone() },
+			Identity::ThbwikiUid(uid) => doc! { "thbwiki_uid": uid.clone() },
+			Identity::QqOpenid(openid) => doc! { "qq_openid": openid.clone() },
+			Identity::Passkey(id) => doc! { "passkeys.credential_id": id.clone() },
+		}
+	}
+	/// Attach this (verified) identity to a voter
+	pub fn link_to(&self, voter: &mut Voter) {
+		match self {
+			Identity::Email(email) => {
+				voter.email = Some(email.clone());
+				voter.email_verified = true;
+			},
+			Identity::Phone(phone) => {
+				voter.phone = Some(phone.clone());
+				voter.phone_verified = true;
+			},
+			Identity::ThbwikiUid(uid) => voter.thbwiki_uid = Some(uid.clone()),
+			Identity::QqOpenid(openid) => voter.qq_openid = Some(openid.clone()),
+			// only added with its public key, by `webauthn::finish_registration`
+			Identity::Passkey(_) => {},
+		}
+	}
+	/// Whether the voter already carries this identity
+	pub fn is_linked_to(&self, voter: &Voter) -> bool {
+		match self {
+			Identity::Email(email) => voter.email.as_ref() == Some(email),
+			Identity::Phone(phone) => voter.phone.as_ref() == Some(phone),
+			Identity::ThbwikiUid(uid) => voter.thbwiki_uid.as_ref() == Some(uid),
+			Identity::QqOpenid(openid) => voter.qq_openid.as_ref() == Some(openid),
+			Identity::Passkey(id) => voter.passkeys.iter().any(|p| p.credential_id == *id),
+		}
+	}
+	/// (email, phone) recorded in activity logs
+	pub fn log_identifiers(&self) -> (Option<String>, Option<String>) {
+		match self {
+			Identity::Email(email) => (Some(email.clone()), None),
+			Identity::Phone(phone) => (None, Some(phone.clone())),
+			_ => (None, None),
+		}
+	}
+}
+
+/// A login method
+///
+/// Providers only verify credentials, looking up or creating the voter, linking identities
+/// and logging is done by `login::resolve_account`.
+#[async_trait]
+pub trait IdentityProvider: Send + Sync {
+	/// Identity the credentials are for
+	fn identity(&self) -> Identity;
+	/// Verify the credentials against `voter`, the current owner of the identity if any
+	///
+	/// Returns a modified voter if it has to be persisted, e.g. after upgrading a password hash.
+	async fn verify(&self, ctx: &AppContext, voter: Option<&Voter>) -> Result<Option<Voter>, Error>;
+	/// Whether a new voter is created when nobody owns the identity yet
+	fn allows_signup(&self) -> bool;
+	/// Nickname of newly created voters
+	fn nickname(&self) -> Option<String> {
+		None
+	}
+	/// Password of newly created voters
+	fn signup_password(&self) -> Option<String> {
+		None
+	}
+	/// Whether the credentials prove more than one factor, so no second factor is asked for
+	fn is_multi_factor(&self) -> bool {
+		false
+	}
+}
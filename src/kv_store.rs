@@ -0,0 +1,109 @@
+use async_trait::async_trait;
+use redis::{AsyncCommands, aio::MultiplexedConnection};
+
+use crate::error::Error;
+
+/// The subset of Redis we rely on
+#[async_trait]
+pub trait KeyValueStore: Send + Sync {
+	async fn get(&self, key: &str) -> Result<Option<String>, Error>;
+	/// Set a key, expiring after `ttl` seconds if given
+	async fn set(&self, key: &str, value: &str, ttl: Option<usize>) -> Result<(), Error>;
+	/// Set a key only if it does not exist yet, returns whether it was set
+	async fn set_nx(&self, key: &str, value: &str, ttl: usize) -> Result<bool, Error>;
+	async fn del(&self, key: &str) -> Result<(), Error>;
+	/// Add `delta` to an integer value, returns the new value
+	async fn incr_by(&self, key: &str, delta: i64) -> Result<i64, Error>;
+	/// Add one to an integer value, returns the new value
+	///
+	/// The key expires `ttl` seconds after it was created, later increments keep that expiry.
+	async fn incr_with_ttl(&self, key: &str, ttl: usize) -> Result<i64, Error>;
+	/// Atomically consume one unit of every `(key, limit, ttl)` budget
+	///
+	/// Nothing is consumed if any budget is exhausted, in which case the index of the first exhausted budget is returned.
+	async fn consume_budgets(&self, budgets: &[(String, u64, usize)]) -> Result<Option<usize>, Error>;
+	async fn publish(&self, channel: &str, message: &str) -> Result<(), Error>;
+}
+
+/// Check every budget first and only consume if none of them is exhausted,
+/// returns 1-based index of the first exhausted budget or 0 on success
+const CONSUME_BUDGETS_SCRIPT: &'static str = r#"
+for i, key in ipairs(KEYS) do
+	local used = tonumber(redis.call('GET', key) or '0')
+	if used >= tonumber(ARGV[i * 2 - 1]) then
+		return i
+	end
+end
+for i, key in ipairs(KEYS) do
+	if redis.call('INCR', key) == 1 then
+		redis.call('EXPIRE', key, ARGV[i * 2])
+	end
+end
+return 0
+"#;
+
+/// INCR, starting the expiry only when the key is created
+const INCR_WITH_TTL_SCRIPT: &'static str = r#"
+local value = redis.call('INCR', KEYS[1])
+if value == 1 then
+	redis.call('EXPIRE', KEYS[1], ARGV[1])
+end
+return value
+"#;
+
+pub struct RedisStore {
+	conn: MultiplexedConnection
+}
+
+impl RedisStore {
+	pub async fn new(client: &redis::Client) -> Result<RedisStore, Error> {
+		Ok(RedisStore { conn: client.get_multiplexed_async_connection().await? })
+	}
+}
+
+#[async_trait]
+impl KeyValueStore for RedisStore {
+	async fn get(&self, key: &str) -> Result<Option<String>, Error> {
+		let mut conn = self.conn.clone();
+		Ok(conn.get(key).await?)
+	}
+	async fn set(&self, key: &str, value: &str, ttl: Option<usize>) -> Result<(), Error> {
+		let mut conn = self.conn.clone();
+		match ttl {
+			Some(ttl) => conn.set_ex(key, value, ttl).await?,
+			None => conn.set(key, value).await?,
+		}
+		Ok(())
+	}
+	async fn set_nx(&self, key: &str, value: &str, ttl: usize) -> Result<bool, Error> {
+		let mut conn = self.conn.clone();
+		let reply: Option<String> = redis::cmd("SET").arg(key).arg(value).arg("NX").arg("EX").arg(ttl).query_async(&mut conn).await?;
+		Ok(reply.is_some())
+	}
+	async fn del(&self, key: &str) -> Result<(), Error> {
+		let mut conn = self.conn.clone();
+		Ok(conn.del(key).await?)
+	}
+	async fn incr_by(&self, key: &str, delta: i64) -> Result<i64, Error> {
+		let mut conn = self.conn.clone();
+		Ok(conn.incr(key, delta).await?)
+	}
+	async fn incr_with_ttl(&self, key: &str, ttl: usize) -> Result<i64, Error> {
+		let mut conn = self.conn.clone();
+		Ok(redis::Script::new(INCR_WITH_TTL_SCRIPT).key(key).arg(ttl).invoke_async(&mut conn).await?)
+	}
+	async fn consume_budgets(&self, budgets: &[(String, u64, usize)]) -> Result<Option<usize>, Error> {
+		let mut conn = self.conn.clone();
+		let script = redis::Script::new(CONSUME_BUDGETS_SCRIPT);
+		let mut invocation = script.prepare_invoke();
+		for (key, limit, ttl) in budgets {
+			invocation.key(key).arg(*limit).arg(*ttl);
+		}
+		let exhausted: usize = invocation.invoke_async(&mut conn).await?;
+		Ok(if exhausted == 0 { None } else { Some(exhausted - 1) })
+	}
+	async fn publish(&self, channel: &str, message: &str) -> Result<(), Error> {
+		let mut conn = self.conn.clone();
+		Ok(conn.publish(channel, message).await?)
+	}
+}
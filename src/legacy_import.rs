@@ -0,0 +1,238 @@
+//! Import of voters exported from the previous years' system
+//!
+//! Exports are CSV (with a header row) or JSONL files with the columns of `LegacyVoter`.
+//! Every processed record is remembered in `legacy_imports` by its legacy id, so an interrupted
+//! import can simply be run again.
+
+use std::{fs::File, io::{BufRead, BufReader, Write}, path::Path, sync::Arc};
+
+use bson::{DateTime, doc, oid::ObjectId};
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+
+use crate::{common::{normalize_email, normalize_phone}, error::Error, identity::Identity, models::{VOTER_SCHEMA_VERSION, Voter}, repository::{DocumentStore, MongoDocumentStore, MongoVoterRepository, VoterRepository}};
+
+const IMPORTS_COLLECTION: &'static str = "legacy_imports";
+/// Print progress every this many records
+const PROGRESS_INTERVAL: usize = 1000;
+
+/// One account of a legacy export
+#[derive(Debug, Clone, Deserialize)]
+pub struct LegacyVoter {
+	/// Id in the legacy system
+	pub legacy_id: String,
+	pub email: Option<String>,
+	pub phone: Option<String>,
+	pub nickname: Option<String>,
+	/// bcrypt hash of `password + salt`
+	pub password_hashed: Option<String>,
+	pub salt: Option<String>,
+	/// RFC 3339
+	pub created_at: Option<String>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum ImportStatus {
+	/// Voter id reserved, voter may not be written yet
+	Pending,
+	Imported,
+	Conflict,
+	Invalid
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ImportRecord {
+	#[serde(rename = "_id")]
+	legacy_id: String,
+	uid: Option<ObjectId>,
+	status: ImportStatus,
+	updated_at: DateTime
+}
+
+/// A line of the conflict report
+#[derive(Debug, Serialize)]
+struct ConflictReport<'a> {
+	legacy_id: &'a str,
+	reason: &'static str,
+	email: Option<&'a str>,
+	phone: Option<&'a str>,
+	/// Existing voters owning the email or phone
+	existing_uids: Vec<String>
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ImportSummary {
+	pub imported: usize,
+	/// Already processed by a previous run
+	pub skipped: usize,
+	pub conflicts: usize,
+	pub invalid: usize
+}
+
+fn non_empty(s: &Option<String>) -> Option<&str> {
+	s.as_deref().map(str::trim).filter(|s| !s.is_empty())
+}
+
+/// Read every record of a `.csv` or `.jsonl` export
+pub fn read_export(path: &Path) -> Result<Vec<LegacyVoter>, Error> {
+	match path.extension().and_then(|e| e.to_str()) {
+		Some("csv") => {
+			let mut reader = csv::Reader::from_path(path).map_err(Error::internal)?;
+			reader.deserialize::<LegacyVoter>().collect::<Result<Vec<LegacyVoter>, _>>().map_err(Error::internal)
+		},
+		Some("jsonl") => {
+			let file = File::open(path).map_err(Error::internal)?;
+			let mut records = vec![];
+			for (i, line) in BufReader::new(file).lines().enumerate() {
+				let line = line.map_err(Error::internal)?;
+				if line.trim().is_empty() {
+					continue;
+				}
+				records.push(serde_json::from_str(&line).map_err(|e| Error::internal(format!("line {}: {}", i + 1, e)))?);
+			}
+			Ok(records)
+		},
+		_ => Err(Error::internal("legacy export must be a .csv or .jsonl file"))
+	}
+}
+
+fn to_voter(record: &LegacyVoter, uid: ObjectId) -> Voter {
+	let created_at = record.created_at.as_deref()
+		.and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+		.map(|t| DateTime::from_millis(t.timestamp_millis()))
+		.unwrap_or_else(DateTime::now);
+	Voter {
+		_id: Some(uid),
+		phone: non_empty(&record.phone).map(normalize_phone),
+		// contacts were never verified by us
+		phone_verified: false,
+		email: non_empty(&record.email).map(normalize_email),
+		email_verified: false,
+		password_hashed: non_empty(&record.password_hashed).map(|s| s.to_string()),
+		salt: non_empty(&record.salt).map(|s| s.to_string()),
+		pepper_version: None,
+		created_at: created_at,
+		nickname: non_empty(&record.nickname).map(|s| s.to_string()),
+		signup_ip: None,
+		qq_openid: None,
+		pfp: None,
+		thbwiki_uid: None,
+		removed: None,
+		schema_version: VOTER_SCHEMA_VERSION,
+		ban: None,
+		purge_after: None,
+		purged_at: None,
+		totp_secret: None,
+		recovery_codes: vec![],
+		passkeys: vec![]
+	}
+}
+
+pub struct LegacyImporter {
+	voters: Arc<dyn VoterRepository>,
+	/// Keeps the `legacy_imports` records
+	documents: Arc<dyn DocumentStore>
+}
+
+impl LegacyImporter {
+	pub fn new(db: &Database) -> LegacyImporter {
+		LegacyImporter::with_stores(Arc::new(MongoVoterRepository::new(db)), Arc::new(MongoDocumentStore::new(db)))
+	}
+
+	pub fn with_stores(voters: Arc<dyn VoterRepository>, documents: Arc<dyn DocumentStore>) -> LegacyImporter {
+		LegacyImporter { voters: voters, documents: documents }
+	}
+
+	async fn find_record(&self, legacy_id: &str) -> Result<Option<ImportRecord>, Error> {
+		match self.documents.find_one(IMPORTS_COLLECTION, doc! { "_id": legacy_id }).await? {
+			Some(record) => Ok(Some(bson::from_document(record).map_err(Error::internal)?)),
+			None => Ok(None)
+		}
+	}
+
+	async fn set_status(&self, legacy_id: &str, uid: Option<ObjectId>, status: ImportStatus) -> Result<(), Error> {
+		let record = ImportRecord { legacy_id: legacy_id.to_string(), uid: uid, status: status, updated_at: DateTime::now() };
+		self.documents.upsert(IMPORTS_COLLECTION, bson::to_document(&record).map_err(Error::internal)?).await
+	}
+
+	/// Import every record, appending conflicts and invalid records to `report` as JSON lines
+	pub async fn import(&self, records: &[LegacyVoter], report: &mut impl Write) -> Result<ImportSummary, Error> {
+		let mut summary = ImportSummary::default();
+		for (i, record) in records.iter().enumerate() {
+			if i > 0 && i % PROGRESS_INTERVAL == 0 {
+				println!(" -- [Import] {}/{} records, {:?}", i, records.len(), summary);
+			}
+			let previous = self.find_record(&record.legacy_id).await?;
+			let uid = match previous {
+				Some(ImportRecord { status: ImportStatus::Pending, uid: Some(uid), .. }) => {
+					// interrupted after reserving the id, finish the job
+					if self.voters.find_by_id(&uid).await?.is_some() {
+						self.set_status(&record.legacy_id, Some(uid), ImportStatus::Imported).await?;
+						summary.imported += 1;
+						continue;
+					}
+					uid
+				},
+				Some(_) => {
+					summary.skipped += 1;
+					continue;
+				},
+				None => ObjectId::new()
+			};
+			let voter = to_voter(record, uid.clone());
+			let invalid = if voter.email.is_none() && voter.phone.is_none() {
+				Some("NO_CONTACT")
+			} else if voter.password_hashed.is_some() && voter.salt.is_none() {
+				Some("PASSWORD_WITHOUT_SALT")
+			} else {
+				None
+			};
+			if let Some(reason) = invalid {
+				self.write_report(report, record, reason, vec![])?;
+				self.set_status(&record.legacy_id, None, ImportStatus::Invalid).await?;
+				summary.invalid += 1;
+				continue;
+			}
+			let mut owners = vec![];
+			for identity in Identity::all_of(&voter) {
+				if let Some(owner) = self.voters.find_by_identity(&identity).await? {
+					owners.push(owner._id.map(|id| id.to_hex()).unwrap_or_default());
+				}
+			}
+			if !owners.is_empty() {
+				owners.dedup();
+				self.write_report(report, record, "CONTACT_IN_USE", owners)?;
+				self.set_status(&record.legacy_id, None, ImportStatus::Conflict).await?;
+				summary.conflicts += 1;
+				continue;
+			}
+			self.set_status(&record.legacy_id, Some(uid.clone()), ImportStatus::Pending).await?;
+			match self.voters.insert(&voter).await {
+				Ok(_) => {},
+				// taken by a concurrent signup since we looked
+				Err(Error::Conflict(_)) => {
+					self.write_report(report, record, "CONTACT_IN_USE", vec![])?;
+					self.set_status(&record.legacy_id, None, ImportStatus::Conflict).await?;
+					summary.conflicts += 1;
+					continue;
+				},
+				Err(e) => return Err(e)
+			}
+			self.set_status(&record.legacy_id, Some(uid), ImportStatus::Imported).await?;
+			summary.imported += 1;
+		}
+		println!(" -- [Import] done, {:?}", summary);
+		Ok(summary)
+	}
+
+	fn write_report(&self, report: &mut impl Write, record: &LegacyVoter, reason: &'static str, existing_uids: Vec<String>) -> Result<(), Error> {
+		let line = ConflictReport {
+			legacy_id: &record.legacy_id,
+			reason: reason,
+			email: non_empty(&record.email),
+			phone: non_empty(&record.phone),
+			existing_uids: existing_uids
+		};
+		writeln!(report, "{}", serde_json::to_string(&line).map_err(Error::internal)?).map_err(Error::internal)
+	}
+}
@@ -0,0 +1,200 @@
+pub mod models;
+pub mod context;
+pub mod jwt;
+pub mod comm;
+pub mod common;
+pub mod error;
+pub mod client_ip;
+pub mod handlers;
+pub mod extractors;
+pub mod login;
+pub mod identity;
+pub mod clock;
+pub mod code_generator;
+
+pub mod sms_service;
+pub mod email_service;
+pub mod send_quota;
+pub mod code_delivery;
+
+pub mod legacy_login;
+pub mod new_login;
+pub mod thbwiki_login;
+pub mod qq_binding;
+
+pub mod account_management;
+pub mod password;
+pub mod data_export;
+pub mod password_reset;
+pub mod lockout;
+pub mod totp;
+pub mod recovery_codes;
+pub mod webauthn;
+
+pub mod repository;
+pub mod kv_store;
+pub mod memory_store;
+pub mod migrations;
+pub mod legacy_import;
+pub mod admin;
+
+use std::sync::Arc;
+
+use actix_web::web;
+use context::AppContext;
+use models::ActivityLogEntry;
+use mongodb::{Client, Database, options::ClientOptions};
+
+use crate::{clock::SystemClock, code_delivery::HttpCodeSender, code_generator::OsCodeGenerator, jwt::load_keys, kv_store::RedisStore, password::{BreachedPasswords, HashParams, HashingLimits, HashingPool, PasswordHasher, PasswordPolicy, Pepper}, repository::{MongoActivityLogRepository, MongoVoterRepository}, send_quota::SendQuotas, totp::SecretCipher};
+
+pub async fn log(ctx: &AppContext, log: ActivityLogEntry) {
+    if let Err(e) = ctx.logs.insert(&log).await {
+        println!("Failed to write activity log: {}", e);
+    }
+}
+
+pub async fn connect_database() -> Database {
+    let client_options = ClientOptions::parse(comm::MONGO_ADDRESS).await.expect("Failed to parse MongoDB parameters");
+    let client = Client::with_options(client_options).expect("Failed to connect to MongoDB");
+    client.database("thvote_users")
+}
+
+/// Default password policy with overrides and the breached password list from the environment
+fn password_policy_from_env() -> PasswordPolicy {
+    let mut policy = PasswordPolicy::default();
+    if let Some(min_length) = std::env::var(comm::PASSWORD_MIN_LENGTH_ENV).ok().and_then(|s| s.parse().ok()) {
+        policy.min_length = min_length;
+    }
+    if let Some(min_entropy) = std::env::var(comm::PASSWORD_MIN_ENTROPY_ENV).ok().and_then(|s| s.parse().ok()) {
+        policy.min_entropy_bits = min_entropy;
+    }
+    if let Some(path) = std::env::var(comm::BREACHED_PASSWORDS_ENV).ok().filter(|s| !s.is_empty()) {
+        let breached = BreachedPasswords::load(std::path::Path::new(&path)).expect("Failed to load breached passwords");
+        println!(" -- [Startup] {} breached passwords loaded", breached.len());
+        policy.breached = Some(breached);
+    }
+    policy
+}
+
+/// Argon2 parameters, peppers and hashing pool limits from the environment, peppers are given as `<version>:<secret>,...`
+fn password_hasher_from_env() -> PasswordHasher {
+    let env_u32 = |name: &str| std::env::var(name).ok().and_then(|s| s.parse().ok());
+    let defaults = password::DEFAULT_HASH_PARAMS;
+    let params = HashParams {
+        mem_cost_kib: env_u32(comm::ARGON2_MEMORY_KIB_ENV).unwrap_or(defaults.mem_cost_kib),
+        time_cost: env_u32(comm::ARGON2_TIME_COST_ENV).unwrap_or(defaults.time_cost),
+        parallelism: env_u32(comm::ARGON2_PARALLELISM_ENV).unwrap_or(defaults.parallelism),
+    };
+    let peppers = std::env::var(comm::PASSWORD_PEPPERS_ENV).unwrap_or_default().split(',').filter(|s| !s.is_empty()).map(|entry| {
+        let (version, secret) = entry.split_at(entry.find(':').expect("Password peppers must be given as <version>:<secret>"));
+        Pepper { version: version.parse().expect("Invalid pepper version"), secret: secret[1..].as_bytes().to_vec() }
+    }).collect();
+    let env_usize = |name: &str| std::env::var(name).ok().and_then(|s| s.parse().ok());
+    let limits = HashingLimits {
+        max_concurrent: env_usize(comm::HASHING_MAX_CONCURRENT_ENV).unwrap_or(password::DEFAULT_HASHING_LIMITS.max_concurrent),
+        max_queued: env_usize(comm::HASHING_MAX_QUEUED_ENV).unwrap_or(password::DEFAULT_HASHING_LIMITS.max_queued),
+    };
+    PasswordHasher { params, peppers, pool: HashingPool::new(limits) }
+}
+
+/// Default send budgets with overrides from the environment, country budgets are given as `<code>:<budget>,...`
+fn send_quotas_from_env() -> SendQuotas {
+    let env_u64 = |name: &str| std::env::var(name).ok().and_then(|s| s.parse().ok());
+    let defaults = SendQuotas::default();
+    let sms_country_daily = match std::env::var(comm::SMS_COUNTRY_DAILY_QUOTAS_ENV).ok().filter(|s| !s.is_empty()) {
+        Some(entries) => entries.split(',').map(|entry| {
+            let (code, budget) = entry.split_at(entry.find(':').expect("Country budgets must be given as <code>:<budget>"));
+            (code.trim().to_string(), budget[1..].trim().parse().expect("Invalid country budget"))
+        }).collect(),
+        None => defaults.sms_country_daily
+    };
+    SendQuotas {
+        sms_hourly: env_u64(comm::SMS_HOURLY_QUOTA_ENV).unwrap_or(defaults.sms_hourly),
+        sms_daily: env_u64(comm::SMS_DAILY_QUOTA_ENV).unwrap_or(defaults.sms_daily),
+        email_hourly: env_u64(comm::EMAIL_HOURLY_QUOTA_ENV).unwrap_or(defaults.email_hourly),
+        email_daily: env_u64(comm::EMAIL_DAILY_QUOTA_ENV).unwrap_or(defaults.email_daily),
+        sms_country_daily: sms_country_daily,
+        sms_other_country_daily: env_u64(comm::SMS_OTHER_COUNTRY_DAILY_QUOTA_ENV).unwrap_or(defaults.sms_other_country_daily),
+        sms_prefix_hourly: env_u64(comm::SMS_PREFIX_HOURLY_QUOTA_ENV).unwrap_or(defaults.sms_prefix_hourly),
+    }
+}
+
+/// Trusted proxy CIDRs from the environment, given as `<cidr>,...`, or the built-in ones
+fn trusted_proxies_from_env() -> Vec<client_ip::Cidr> {
+    match std::env::var(comm::TRUSTED_PROXIES_ENV).ok().filter(|s| !s.trim().is_empty()) {
+        Some(cidrs) => client_ip::parse_trusted_proxies(&cidrs.split(',').map(str::trim).filter(|s| !s.is_empty()).collect::<Vec<_>>()),
+        None => client_ip::parse_trusted_proxies(comm::TRUSTED_PROXY_CIDRS)
+    }
+}
+
+/// Key encrypting TOTP secrets, 64 hex digits, losing it disables every second factor
+fn secret_cipher_from_env() -> SecretCipher {
+    let key = std::env::var(comm::SECRET_KEY_ENV).ok().and_then(|s| hex::decode(s).ok()).filter(|k| k.len() == 32).expect("THVOTE_SECRET_KEY must be set to 64 hex digits");
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&key);
+    SecretCipher::new(&bytes)
+}
+
+/// Context backed by MongoDB, Redis and the real SMS and email services
+pub async fn production_context(db: &Database) -> AppContext {
+    let redis_client = redis::Client::open(comm::REDIS_ADDRESS).unwrap();
+    AppContext {
+        vote_year: 10,
+        voters: Arc::new(MongoVoterRepository::new(db)),
+        logs: Arc::new(MongoActivityLogRepository::new(db)),
+        kv: Arc::new(RedisStore::new(&redis_client).await.expect("Failed to connect to Redis")),
+        code_sender: Arc::new(HttpCodeSender),
+        clock: Arc::new(SystemClock),
+        code_generator: Arc::new(OsCodeGenerator),
+        key_pair: load_keys().await.unwrap(),
+        trusted_proxies: trusted_proxies_from_env(),
+        gateway_secret: std::env::var(comm::GATEWAY_SECRET_ENV).ok().filter(|s| !s.is_empty()),
+        deletion_grace_days: std::env::var(comm::DELETION_GRACE_DAYS_ENV).ok().and_then(|s| s.parse().ok()).unwrap_or(context::DELETION_GRACE_DAYS),
+        password_policy: Arc::new(password_policy_from_env()),
+        password_hasher: Arc::new(password_hasher_from_env()),
+        secret_cipher: Arc::new(secret_cipher_from_env()),
+        send_quotas: Arc::new(send_quotas_from_env()),
+    }
+}
+
+/// Every route served by the user manager
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        .route("/v1/login-email-password", web::post().to(handlers::login_email_password))
+        .route("/v1/login-email", web::post().to(handlers::login_email))
+        .route("/v1/login-phone-password", web::post().to(handlers::login_phone_password))
+        .route("/v1/login-phone", web::post().to(handlers::login_phone))
+        .route("/v1/login-mfa", web::post().to(handlers::login_mfa))
+        .route("/v1/passkey-login-options", web::post().to(handlers::passkey_login_options))
+        .route("/v1/login-passkey", web::post().to(handlers::login_passkey))
+        .route("/v1/update-email", web::post().to(handlers::update_email))
+        .route("/v1/update-phone", web::post().to(handlers::update_phone))
+        .route("/v1/update-nickname", web::post().to(handlers::update_nickname))
+        .route("/v1/update-password", web::post().to(handlers::update_password))
+        .route("/v1/enroll-totp", web::post().to(handlers::enroll_totp))
+        .route("/v1/confirm-totp", web::post().to(handlers::confirm_totp))
+        .route("/v1/disable-totp", web::post().to(handlers::disable_totp))
+        .route("/v1/regenerate-recovery-codes", web::post().to(handlers::regenerate_recovery_codes))
+        .route("/v1/passkey-registration-options", web::post().to(handlers::passkey_registration_options))
+        .route("/v1/register-passkey", web::post().to(handlers::register_passkey))
+        .route("/v1/list-passkeys", web::post().to(handlers::list_passkeys))
+        .route("/v1/remove-passkey", web::post().to(handlers::remove_passkey))
+        .route("/v1/send-sms-code", web::post().to(handlers::send_phone_verify_code))
+        .route("/v1/send-email-code", web::post().to(handlers::send_email_verify_code))
+        .route("/v1/user-token-status", web::post().to(handlers::user_token_status))
+        .route("/v1/vote-token-status", web::post().to(handlers::vote_token_status))
+        .route("/v1/remove-voter", web::post().to(handlers::remove_voter))
+        .route("/v1/restore-voter", web::post().to(handlers::restore_voter))
+        .route("/v1/export-voter-data", web::post().to(handlers::export_voter_data))
+        .route("/v1/send-password-reset-code", web::post().to(handlers::send_password_reset_code))
+        .route("/v1/reset-password", web::post().to(handlers::reset_password))
+        .route("/v1/send-unlock-code", web::post().to(handlers::send_unlock_code))
+        .route("/v1/unlock-account", web::post().to(handlers::unlock_account))
+        .route("/v1/admin/search-voters", web::post().to(handlers::admin_search_voters))
+        .route("/v1/admin/voter-detail", web::post().to(handlers::admin_voter_detail))
+        .route("/v1/admin/ban", web::post().to(handlers::admin_ban_voter))
+        .route("/v1/admin/unban", web::post().to(handlers::admin_unban_voter))
+        .route("/v1/admin/force-logout", web::post().to(handlers::admin_force_logout))
+        .route("/v1/admin/override-contact", web::post().to(handlers::admin_override_contact))
+        .route("/v1/admin/hashing-metrics", web::post().to(handlers::admin_hashing_metrics));
+}
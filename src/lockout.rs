@@ -0,0 +1,145 @@
+//! Progressive lockout after repeated incorrect passwords, per account and per source IP
+
+use bson::oid::ObjectId;
+
+use crate::{code_delivery::{Notice, notify_voter}, context::AppContext, error::Error, extractors::Requester, identity::Identity, log, models::{ActivityLogEntry, Voter}, new_login::check_verify_code, password_reset::{claim_resend_guard, send_code_to_owner}};
+
+/// Failed attempts are forgotten a day after the first one
+const FAILURE_WINDOW: usize = 86400;
+/// (failed attempts, lock in seconds), every failure from a step on locks for that long
+const ACCOUNT_LOCK_STEPS: &'static [(i64, usize)] = &[(5, 60), (10, 15 * 60), (15, 3600), (20, 86400)];
+/// Same for a source IP, higher since many voters may share an address
+const IP_LOCK_STEPS: &'static [(i64, usize)] = &[(20, 60), (50, 15 * 60), (100, 3600), (200, 86400)];
+/// An unlock code can be requested every 2 minutes per contact
+const UNLOCK_CODE_INTERVAL: usize = 120;
+/// Unlock codes expire after 15 minutes
+const UNLOCK_CODE_TTL: usize = 900;
+
+fn account_scope(uid: &ObjectId) -> String {
+	format!("voter-{}", uid)
+}
+
+fn ip_scope(ip: &str) -> String {
+	format!("ip-{}", ip)
+}
+
+fn unlock_code_key(identity: &Identity) -> String {
+	format!("account-unlock-{}-{}", identity.kind(), identity.value())
+}
+
+async fn is_locked(ctx: &AppContext, scope: &str) -> Result<bool, Error> {
+	Ok(ctx.kv.get(&format!("login-lock-{}", scope)).await?.is_some())
+}
+
+/// Count a failure, returns the lock applied and whether a new step was reached
+async fn add_failure(ctx: &AppContext, scope: &str, steps: &[(i64, usize)]) -> Result<Option<(usize, bool)>, Error> {
+	let key = format!("login-failures-{}", scope);
+	let failures = ctx.kv.incr_with_ttl(&key, FAILURE_WINDOW).await?;
+	let (threshold, lock) = match steps.iter().rev().find(|(threshold, _)| failures >= *threshold) {
+		Some(step) => *step,
+		None => return Ok(None)
+	};
+	ctx.kv.set(&format!("login-lock-{}", scope), "locked", Some(lock)).await?;
+	Ok(Some((lock, failures == threshold)))
+}
+
+async fn clear(ctx: &AppContext, scope: &str) -> Result<(), Error> {
+	ctx.kv.del(&format!("login-failures-{}", scope)).await?;
+	ctx.kv.del(&format!("login-lock-{}", scope)).await
+}
+
+/// Log a new lockout step of the voter, or of the source IP without `uid`
+async fn log_lock(ctx: &AppContext, uid: Option<&ObjectId>, lock: usize, requester: &Requester) {
+	log(ctx, ActivityLogEntry::LoginLocked {
+		created_at: ctx.now(),
+		uid: uid.cloned(),
+		seconds: lock as i64,
+		requester_ip: requester.ip.clone(),
+		requester_additional_fingerprint: requester.additional_fingerprint.clone()
+	}).await;
+}
+
+/// Refuse password logins from a locked IP or to a locked account
+pub async fn check(ctx: &AppContext, uid: Option<&ObjectId>, ip: Option<&str>) -> Result<(), Error> {
+	if let Some(ip) = ip {
+		if is_locked(ctx, &ip_scope(ip)).await? {
+			return Err(Error::RateLimited("TOO_MANY_FAILED_LOGINS"));
+		}
+	}
+	if let Some(uid) = uid {
+		if is_locked(ctx, &account_scope(uid)).await? {
+			return Err(Error::RateLimited("ACCOUNT_LOCKED"));
+		}
+	}
+	Ok(())
+}
+
+/// Record an incorrect password for `identity`, owned by `voter` if anyone
+///
+/// The voter is notified once per lockout step reached.
+pub async fn record_failure(ctx: &AppContext, voter: Option<&Voter>, identity: &Identity, requester: &Requester) -> Result<(), Error> {
+	if let Some(ip) = requester.ip.as_ref() {
+		if let Some((lock, true)) = add_failure(ctx, &ip_scope(ip), IP_LOCK_STEPS).await? {
+			log_lock(ctx, None, lock, requester).await;
+		}
+	}
+	let voter = match voter {
+		Some(voter) => voter,
+		None => return Ok(())
+	};
+	let uid = voter._id.clone().unwrap();
+	let (email, phone) = identity.log_identifiers();
+	log(ctx, ActivityLogEntry::LoginFailed {
+		created_at: ctx.now(),
+		uid: uid.clone(),
+		email: email,
+		phone: phone,
+		reason: "INCORRECT_PASSWORD".to_string(),
+		requester_ip: requester.ip.clone(),
+		requester_additional_fingerprint: requester.additional_fingerprint.clone()
+	}).await;
+	if let Some((lock, true)) = add_failure(ctx, &account_scope(&uid), ACCOUNT_LOCK_STEPS).await? {
+		log_lock(ctx, Some(&uid), lock, requester).await;
+		if let Err(e) = notify_voter(ctx.code_sender.as_ref(), voter, Notice::AccountLocked).await {
+			println!(" -- [Lockout] failed to notify voter {}: {}", uid, e);
+		}
+	}
+	Ok(())
+}
+
+/// Forget failed attempts on an account after a successful login
+pub async fn record_success(ctx: &AppContext, uid: &ObjectId) -> Result<(), Error> {
+	ctx.kv.del(&format!("login-failures-{}", account_scope(uid))).await
+}
+
+/// Send an unlock code to `identity`, an email or phone, if it belongs to a locked account
+pub async fn send_unlock_code(ctx: &AppContext, identity: Identity, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Error> {
+	let key = unlock_code_key(&identity);
+	claim_resend_guard(ctx, &key, UNLOCK_CODE_INTERVAL).await?;
+	let locked = match ctx.voters.find_by_identity(&identity).await? {
+		Some(voter) => is_locked(ctx, &account_scope(voter._id.as_ref().unwrap())).await?,
+		None => false
+	};
+	// nothing tells the requester whether the account exists or is locked
+	if locked {
+		send_code_to_owner(ctx, key, identity, UNLOCK_CODE_TTL, ip, additional_fingerprint).await?;
+	}
+	Ok(())
+}
+
+/// Lift the lock on an account with the code sent by `send_unlock_code`
+pub async fn unlock_account(ctx: &AppContext, identity: Identity, verify_code: &str, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Error> {
+	// unknown contacts never got a code, answered the same as a wrong one
+	let voter = ctx.voters.find_by_identity(&identity).await?.ok_or(Error::Auth("INCORRECT_VERIFY_CODE"))?;
+	check_verify_code(ctx, unlock_code_key(&identity), identity.value(), verify_code).await?;
+	let uid = voter._id.unwrap();
+	clear(ctx, &account_scope(&uid)).await?;
+	log(ctx, ActivityLogEntry::AccountUnlocked {
+		created_at: ctx.now(),
+		uid: uid,
+		channel: identity.kind().to_string(),
+		requester_ip: ip,
+		requester_additional_fingerprint: additional_fingerprint
+	}).await;
+	Ok(())
+}
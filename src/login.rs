@@ -0,0 +1,243 @@
+use std::str::FromStr;
+
+use bson::oid::ObjectId;
+use serde::{Serialize, Deserialize};
+
+use crate::{context::AppContext, error::Error, extractors::{Requester, verify_token}, identity::{Identity, IdentityProvider}, log, models::{ActivityLogEntry, LoginResponse, LoginResults, MfaChallenge, VOTER_SCHEMA_VERSION, VoteTokenClaim, VoteTokenStatus, Voter}, recovery_codes, totp};
+
+/// A second factor has to be given within 5 minutes of the first
+const MFA_CHALLENGE_TTL: usize = 300;
+/// A challenge is dropped after this many wrong codes
+const MFA_MAX_ATTEMPTS: i64 = 5;
+
+fn new_voter(ctx: &AppContext, identity: &Identity, nickname: Option<String>, requester: &Requester) -> Voter {
+	let mut voter = Voter {
+		_id: None,
+		email: None,
+		email_verified: false,
+		phone: None,
+		phone_verified: false,
+		password_hashed: None,
+		salt: None,
+		pepper_version: None,
+		created_at: ctx.now(),
+		nickname: nickname,
+		signup_ip: requester.ip.clone(),
+		qq_openid: None,
+		pfp: None,
+		thbwiki_uid: None,
+		removed: None,
+		schema_version: VOTER_SCHEMA_VERSION,
+		ban: None,
+		purge_after: None,
+		purged_at: None,
+		totp_secret: None,
+		recovery_codes: vec![],
+		passkeys: vec![]
+	};
+	identity.link_to(&mut voter);
+	voter
+}
+
+/// Create a voter owning `identity`, or return the existing owner if a concurrent signup won
+async fn create_voter(ctx: &AppContext, identity: &Identity, provider: &dyn IdentityProvider, requester: &Requester) -> Result<Voter, Error> {
+	let mut voter = new_voter(ctx, identity, provider.nickname(), requester);
+	if let Some(password) = provider.signup_password() {
+		ctx.password_policy.check(&password, &voter.personal_info())?;
+		ctx.password_hasher.set_password(&mut voter, &password).await?;
+	}
+	let (voter, created) = ctx.voters.insert_or_get(identity, &voter).await?;
+	if !created {
+		return Ok(voter);
+	}
+	log(ctx, ActivityLogEntry::VoterCreation {
+		created_at: ctx.now(),
+		uid: voter._id.as_ref().unwrap().clone(),
+		nickname: voter.nickname.clone(),
+		phone: voter.phone.clone(),
+		email: voter.email.clone(),
+		requester_ip: requester.ip.clone(),
+		requester_additional_fingerprint: requester.additional_fingerprint.clone()
+	}).await;
+	Ok(voter)
+}
+
+/// Link identities from a pending login session (e.g. THBWiki or QQ) to the voter
+///
+/// Returns whether the voter was modified. Identities already owned by another voter are refused.
+async fn link_login_session(ctx: &AppContext, voter: &mut Voter, sid: Option<String>) -> Result<bool, Error> {
+	let sess = match sid.as_ref() {
+		Some(sid) => ctx.get_login_session(sid).await,
+		None => None
+	};
+	let sess = match sess {
+		Some(sess) => sess,
+		None => return Ok(false)
+	};
+	let mut identities = vec![];
+	if let Some(thbwiki_uid) = sess.thbwiki_uid {
+		identities.push(Identity::ThbwikiUid(thbwiki_uid));
+	}
+	if let Some(qq_openid) = sess.qq_openid {
+		identities.push(Identity::QqOpenid(qq_openid));
+	}
+	let mut changed = false;
+	for identity in identities {
+		if identity.is_linked_to(voter) {
+			continue;
+		}
+		if let Some(owner) = ctx.voters.find_by_identity(&identity).await? {
+			if owner._id != voter._id {
+				return Err(Error::Conflict("IDENTITY_ALREADY_LINKED"));
+			}
+		}
+		identity.link_to(voter);
+		changed = true;
+	}
+	ctx.remove_login_session(sid.as_ref().unwrap()).await;
+	Ok(changed)
+}
+
+/// Turn verified credentials into a voter
+///
+/// Looks up the owner of the identity, creates one if the provider allows signup
+/// and links the pending login session.
+pub async fn resolve_account(ctx: &AppContext, provider: &dyn IdentityProvider, sid: Option<String>, requester: &Requester) -> Result<Voter, Error> {
+	let identity = provider.identity();
+	let existing = ctx.voters.find_by_identity(&identity).await?;
+	let updated = provider.verify(ctx, existing.as_ref()).await?;
+	let (mut voter, mut changed) = match (existing, updated) {
+		(Some(_), Some(updated)) => (updated, true),
+		(Some(existing), None) => (existing, false),
+		(None, _) => {
+			if !provider.allows_signup() {
+				return Err(Error::NotFound);
+			}
+			(create_voter(ctx, &identity, provider, requester).await?, false)
+		}
+	};
+	voter.check_usable(ctx.now())?;
+	changed |= link_login_session(ctx, &mut voter, sid).await?;
+	if changed {
+		ctx.voters.replace(&voter).await?;
+	}
+	Ok(voter)
+}
+
+async fn log_login(ctx: &AppContext, uid: &ObjectId, email: Option<String>, phone: Option<String>, requester: &Requester) {
+	log(ctx, ActivityLogEntry::VoterLogin {
+		created_at: ctx.now(),
+		uid: uid.clone(),
+		email: email,
+		phone: phone,
+		requester_ip: requester.ip.clone(),
+		requester_additional_fingerprint: requester.additional_fingerprint.clone()
+	}).await;
+}
+
+/// Tokens and frontend view of a logged in voter
+pub fn issue_login_results(ctx: &AppContext, voter: &Voter) -> Result<LoginResults, Error> {
+	let now = ctx.clock.now();
+	let vote_token = voter.generate_vote_token(ctx.vote_year, &ctx.key_pair, now)?;
+	let session_token = voter.generate_user_auth(&ctx.key_pair, now);
+	Ok(LoginResults {
+		user: voter.to_fe_voter(&ctx.key_pair),
+		vote_token: vote_token,
+		session_token: session_token
+	})
+}
+
+/// Voter and identity a second factor challenge was issued for
+#[derive(Serialize, Deserialize)]
+struct MfaChallengeState {
+	uid: ObjectId,
+	email: Option<String>,
+	phone: Option<String>
+}
+
+/// Factors a voter can answer a challenge with, none if it has no second factor
+fn mfa_methods(voter: &Voter) -> Vec<String> {
+	let mut methods = vec![];
+	if voter.totp_secret.is_some() {
+		methods.push("totp".to_string());
+		// only a way around the second factor, not one by themselves
+		if !voter.recovery_codes.is_empty() {
+			methods.push("recovery_code".to_string());
+		}
+	}
+	methods
+}
+
+/// Issue tokens to a voter whose first factor was verified, or a challenge if it has a second one
+///
+/// No challenge is issued when `multi_factor` credentials were verified. The login is logged once tokens are issued.
+pub async fn finish_login(ctx: &AppContext, voter: &Voter, identity: &Identity, multi_factor: bool, requester: &Requester) -> Result<LoginResponse, Error> {
+	let (email, phone) = identity.log_identifiers();
+	let uid = voter._id.as_ref().unwrap();
+	let methods = if multi_factor { vec![] } else { mfa_methods(voter) };
+	if methods.is_empty() {
+		log_login(ctx, uid, email, phone, requester).await;
+		return Ok(LoginResponse::Complete(issue_login_results(ctx, voter)?));
+	}
+	let challenge_token = ctx.code_generator.token(24);
+	let state = MfaChallengeState { uid: uid.clone(), email: email, phone: phone };
+	ctx.kv.set(&format!("mfa-challenge-{}", challenge_token), &serde_json::to_string(&state).unwrap(), Some(MFA_CHALLENGE_TTL)).await?;
+	Ok(LoginResponse::MfaRequired(MfaChallenge {
+		mfa_required: true,
+		challenge_token: challenge_token,
+		methods: methods
+	}))
+}
+
+/// Answer a challenge from `finish_login` with a TOTP or recovery code
+pub async fn complete_mfa(ctx: &AppContext, challenge_token: &str, code: &str, requester: &Requester) -> Result<LoginResults, Error> {
+	let key = format!("mfa-challenge-{}", challenge_token);
+	let state: MfaChallengeState = ctx.kv.get(&key).await?.and_then(|s| serde_json::from_str(&s).ok()).ok_or(Error::Auth("INVALID_MFA_CHALLENGE"))?;
+	let mut voter = ctx.voters.find_by_id(&state.uid).await?.ok_or(Error::NotFound)?;
+	voter.check_usable(ctx.now())?;
+	// one answer is checked at a time, so a challenge issues tokens at most once
+	let claim = format!("{}-claimed", key);
+	if !ctx.kv.set_nx(&claim, "claimed", MFA_CHALLENGE_TTL).await? {
+		return Err(Error::Auth("INVALID_MFA_CHALLENGE"));
+	}
+	let checked = if recovery_codes::is_recovery_code(code) {
+		recovery_codes::consume(ctx, &mut voter, code, requester).await
+	} else {
+		totp::check_code(ctx, &voter, code).await
+	};
+	if let Err(e) = checked {
+		if let Error::Auth(_) = e {
+			let failures = ctx.kv.incr_with_ttl(&format!("{}-failures", key), MFA_CHALLENGE_TTL).await?;
+			if failures >= MFA_MAX_ATTEMPTS {
+				ctx.kv.del(&key).await?;
+			}
+		}
+		ctx.kv.del(&claim).await?;
+		return Err(e);
+	}
+	ctx.kv.del(&key).await?;
+	log_login(ctx, &state.uid, state.email, state.phone, requester).await;
+	issue_login_results(ctx, &voter)
+}
+
+/// Every login method goes through here
+pub async fn complete_login(ctx: &AppContext, provider: &dyn IdentityProvider, sid: Option<String>, requester: &Requester) -> Result<LoginResponse, Error> {
+	let voter = resolve_account(ctx, provider, sid, requester).await?;
+	finish_login(ctx, &voter, &provider.identity(), provider.is_multi_factor(), requester).await
+}
+
+/// Check the voter behind a vote token is still allowed to vote, used by the vote service
+pub async fn vote_token_status(ctx: &AppContext, vote_token: &str) -> Result<VoteTokenStatus, Error> {
+	let claim = verify_token::<VoteTokenClaim>(ctx, vote_token, "vote")?;
+	let vote_id = claim.custom.vote_id.ok_or(Error::Auth("INVALID_TOKEN"))?;
+	// thvote-<year>-<uid>
+	let uid = vote_id.rsplit('-').next().and_then(|uid| ObjectId::from_str(uid).ok()).ok_or(Error::Auth("INVALID_TOKEN"))?;
+	let voter = ctx.voters.find_by_id(&uid).await?.ok_or(Error::NotFound)?;
+	let ban = voter.active_ban(ctx.now());
+	Ok(VoteTokenStatus {
+		vote_id: vote_id.clone(),
+		removed: voter.removed == Some(true),
+		suspended: ban.is_some(),
+		suspended_until: ban.and_then(|b| b.expires_at).map(|t| t.timestamp_millis())
+	})
+}
@@ -1,67 +1,70 @@
+use actix_web::{App, HttpServer, web::Data};
+use thvote_user_manager::{account_management, connect_database, legacy_import, migrations, production_context, repository::{MongoDocumentStore, MongoVoterRepository}, routes};
 
-pub mod models;
-pub mod context;
-pub mod jwt;
-pub mod comm;
-pub mod common;
-pub mod handlers;
+/// Removed voters are checked for purging every hour
+const PURGE_INTERVAL_SECS: u64 = 3600;
+/// Only the replica holding this lease purges, it runs out a minute before the next round
+const PURGE_LEASE_KEY: &'static str = "purge-lock";
+const PURGE_LEASE_SECS: usize = PURGE_INTERVAL_SECS as usize - 60;
 
-pub mod sms_service;
-pub mod email_service;
-
-pub mod legacy_login;
-pub mod new_login;
-pub mod thbwiki_login;
-pub mod qq_binding;
-
-pub mod account_management;
-
-use std::{cell::Cell, sync::Arc};
-
-use actix_web::{App, HttpRequest, HttpServer, Responder, web::{self, Data}};
-use context::AppContext;
-use jwt::load_keys;
-use models::ActivityLogEntry;
-use mongodb::{Client, options::ClientOptions};
-
-use redis::AsyncCommands;
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
 
-pub async fn log(ctx: &AppContext, log: ActivityLogEntry) {
-    ctx.logs_coll.insert_one(log, None).await;
-}
+    let db = connect_database().await;
+    let documents = MongoDocumentStore::new(&db);
 
+    // `migrate [--dry-run]` only runs pending migrations
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|s| s.as_str()) == Some("migrate") {
+        let dry_run = args.iter().any(|a| a == "--dry-run");
+        let reports = migrations::run_pending(&documents, dry_run).await.expect("Migration failed");
+        if reports.is_empty() {
+            println!(" -- [Migration] nothing to migrate");
+        }
+        return Ok(());
+    }
+    migrations::run_pending(&documents, false).await.expect("Migration failed");
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
+    // `import <export.csv|export.jsonl> [--report <path>]` imports legacy voters
+    if args.get(1).map(|s| s.as_str()) == Some("import") {
+        let path = std::path::PathBuf::from(args.get(2).expect("Usage: import <export.csv|export.jsonl> [--report <path>]"));
+        let report_path = args.iter().position(|a| a == "--report").and_then(|i| args.get(i + 1)).map(std::path::PathBuf::from)
+            .unwrap_or_else(|| path.with_extension("conflicts.jsonl"));
+        let records = legacy_import::read_export(&path).expect("Failed to read legacy export");
+        let mut report = std::fs::OpenOptions::new().create(true).append(true).open(&report_path)?;
+        legacy_import::LegacyImporter::new(&db).import(&records, &mut report).await.expect("Import failed");
+        println!(" -- [Import] conflicts written to {}", report_path.display());
+        return Ok(());
+    }
 
-    let client_options = ClientOptions::parse(comm::MONGO_ADDRESS).await.expect("Failed to parse MongoDB parameters");
-	let client = Client::with_options(client_options).expect("Failed to connect to MongoDB");
+    let voters = MongoVoterRepository::new(&db);
+    let duplicates = voters.ensure_indexes().await.expect("Failed to create voter indexes");
+    for dup in duplicates.iter() {
+        println!(" -- [Startup] {} voters share {} = {}, unique index not created: {:?}", dup.uids.len(), dup.field, dup.value, dup.uids);
+    }
 
-	let db = client.database("thvote_users");
+    let ctx = production_context(&db).await;
 
-    let redis_client = redis::Client::open(comm::REDIS_ADDRESS).unwrap();
+    // purge voters whose deletion grace period ended
+    let purge_ctx = ctx.clone();
+    actix_web::rt::spawn(async move {
+        loop {
+            match purge_ctx.kv.set_nx(PURGE_LEASE_KEY, "leased", PURGE_LEASE_SECS).await {
+                Ok(true) => match account_management::purge_removed_voters(&purge_ctx).await {
+                    Ok(0) => {},
+                    Ok(n) => println!(" -- [Purge] purged {} removed voters", n),
+                    Err(e) => println!(" -- [Purge] failed: {}", e)
+                },
+                Ok(false) => {},
+                Err(e) => println!(" -- [Purge] failed to take lease: {}", e)
+            }
+            actix_web::rt::time::sleep(std::time::Duration::from_secs(PURGE_INTERVAL_SECS)).await;
+        }
+    });
 
-    let ctx = context::AppContext {
-        vote_year: 10,
-        db: db.clone(),
-        voters_coll: db.collection("voters"),
-        logs_coll: db.collection("voter_logs"),
-        redis_client: redis_client,
-        key_pair: load_keys().await.unwrap(),
-    };
     HttpServer::new(move || {
         App::new().app_data(Data::new(ctx.clone()))
-            .route("/v1/login-email-password", web::post().to(handlers::login_email_password))
-            .route("/v1/login-email", web::post().to(handlers::login_email))
-            .route("/v1/login-phone", web::post().to(handlers::login_phone))
-            .route("/v1/update-email", web::post().to(handlers::update_email))
-            .route("/v1/update-phone", web::post().to(handlers::update_phone))
-            .route("/v1/update-nickname", web::post().to(handlers::update_nickname))
-            .route("/v1/update-password", web::post().to(handlers::update_password))
-            .route("/v1/send-sms-code", web::post().to(handlers::send_phone_verify_code))
-            .route("/v1/send-email-code", web::post().to(handlers::send_email_verify_code))
-            .route("/v1/user-token-status", web::post().to(handlers::user_token_status))
-            .route("/v1/remove-voter", web::post().to(handlers::remove_voter))
+            .configure(routes)
     })
     .bind("0.0.0.0:80")?
     .run()
@@ -0,0 +1,320 @@
+//! In-memory stand-ins for MongoDB and Redis, used by tests
+
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use async_trait::async_trait;
+use bson::{Bson, DateTime as BsonDateTime, Document, oid::ObjectId};
+use chrono::{DateTime, Duration, Utc};
+use futures::{StreamExt, stream::BoxStream};
+
+use crate::{clock::{Clock, SystemClock}, error::Error, identity::Identity, kv_store::KeyValueStore, models::{ActivityLogEntry, Voter}, repository::{ActivityLogRepository, DocumentStore, VoterQuery, VoterRepository}};
+
+#[derive(Default)]
+pub struct MemoryVoterRepository {
+	voters: Mutex<Vec<Voter>>
+}
+
+impl MemoryVoterRepository {
+	pub fn new() -> MemoryVoterRepository {
+		Default::default()
+	}
+	/// Snapshot of every stored voter
+	pub fn all(&self) -> Vec<Voter> {
+		self.voters.lock().unwrap().clone()
+	}
+}
+
+#[async_trait]
+impl VoterRepository for MemoryVoterRepository {
+	async fn find_by_id(&self, uid: &ObjectId) -> Result<Option<Voter>, Error> {
+		Ok(self.voters.lock().unwrap().iter().find(|v| v._id.as_ref() == Some(uid)).cloned())
+	}
+	async fn find_by_identity(&self, identity: &Identity) -> Result<Option<Voter>, Error> {
+		Ok(self.voters.lock().unwrap().iter().find(|v| identity.is_linked_to(v)).cloned())
+	}
+	async fn insert(&self, voter: &Voter) -> Result<ObjectId, Error> {
+		let mut voter = voter.clone();
+		let uid = voter._id.clone().unwrap_or_else(ObjectId::new);
+		voter._id = Some(uid.clone());
+		self.voters.lock().unwrap().push(voter);
+		Ok(uid)
+	}
+	async fn insert_or_get(&self, identity: &Identity, voter: &Voter) -> Result<(Voter, bool), Error> {
+		let mut voters = self.voters.lock().unwrap();
+		if let Some(owner) = voters.iter().find(|v| identity.is_linked_to(v)) {
+			return Ok((owner.clone(), false));
+		}
+		let mut voter = voter.clone();
+		voter._id = Some(ObjectId::new());
+		voters.push(voter.clone());
+		Ok((voter, true))
+	}
+	async fn replace(&self, voter: &Voter) -> Result<(), Error> {
+		let mut voters = self.voters.lock().unwrap();
+		// same as the unique indexes in MongoDB
+		let taken = Identity::all_of(voter).iter().any(|identity| voters.iter().any(|v| v._id != voter._id && identity.is_linked_to(v)));
+		if taken {
+			return Err(Error::Conflict("IDENTITY_ALREADY_LINKED"));
+		}
+		if let Some(existing) = voters.iter_mut().find(|v| v._id.is_some() && v._id == voter._id) {
+			*existing = voter.clone();
+		}
+		Ok(())
+	}
+	async fn search(&self, query: &VoterQuery, now: BsonDateTime, skip: u64, limit: i64) -> Result<(Vec<Voter>, u64), Error> {
+		let voters = self.voters.lock().unwrap();
+		let matched: Vec<&Voter> = voters.iter().rev().filter(|v| query.matches(v, now)).collect();
+		let page = matched.iter().skip(skip as usize).take(limit.max(0) as usize).map(|v| (*v).clone()).collect();
+		Ok((page, matched.len() as u64))
+	}
+	async fn find_purgeable(&self, now: BsonDateTime) -> Result<Vec<Voter>, Error> {
+		Ok(self.voters.lock().unwrap().iter().filter(|v| v.removed == Some(true) && v.purge_after.map_or(false, |t| t <= now)).cloned().collect())
+	}
+	async fn remove_recovery_code(&self, uid: &ObjectId, hashed: &str) -> Result<bool, Error> {
+		let mut voters = self.voters.lock().unwrap();
+		let voter = match voters.iter_mut().find(|v| v._id.as_ref() == Some(uid)) {
+			Some(voter) => voter,
+			None => return Ok(false)
+		};
+		let before = voter.recovery_codes.len();
+		voter.recovery_codes.retain(|c| c != hashed);
+		Ok(voter.recovery_codes.len() < before)
+	}
+}
+
+#[derive(Default)]
+pub struct MemoryActivityLogRepository {
+	entries: Mutex<Vec<ActivityLogEntry>>
+}
+
+impl MemoryActivityLogRepository {
+	pub fn new() -> MemoryActivityLogRepository {
+		Default::default()
+	}
+	/// Snapshot of every logged entry, oldest first
+	pub fn entries(&self) -> Vec<ActivityLogEntry> {
+		self.entries.lock().unwrap().clone()
+	}
+}
+
+/// Whether an entry is about a voter or a code sent to its contacts
+fn is_about_voter(entry: &ActivityLogEntry, uid: &ObjectId, email: Option<&str>, phone: Option<&str>) -> bool {
+	match entry {
+		ActivityLogEntry::SendEmail { target_email, .. } => Some(target_email.as_str()) == email,
+		ActivityLogEntry::SendSMS { target_phone, .. } => Some(target_phone.as_str()) == phone,
+		_ => entry.uid() == Some(uid)
+	}
+}
+
+#[async_trait]
+impl ActivityLogRepository for MemoryActivityLogRepository {
+	async fn insert(&self, entry: &ActivityLogEntry) -> Result<(), Error> {
+		self.entries.lock().unwrap().push(entry.clone());
+		Ok(())
+	}
+	async fn find_by_uid(&self, uid: &ObjectId, limit: i64) -> Result<Vec<ActivityLogEntry>, Error> {
+		Ok(self.entries.lock().unwrap().iter().rev().filter(|e| e.uid() == Some(uid)).take(limit.max(0) as usize).cloned().collect())
+	}
+	async fn find_by_voter(&self, uid: &ObjectId, email: Option<&str>, phone: Option<&str>) -> Result<Vec<ActivityLogEntry>, Error> {
+		Ok(self.entries.lock().unwrap().iter().filter(|e| is_about_voter(e, uid, email, phone)).cloned().collect())
+	}
+	async fn pseudonymize(&self, uid: &ObjectId, email: Option<&str>, phone: Option<&str>) -> Result<u64, Error> {
+		let mut changed = 0;
+		for entry in self.entries.lock().unwrap().iter_mut() {
+			if is_about_voter(entry, uid, email, phone) {
+				entry.pseudonymize();
+				changed += 1;
+			}
+		}
+		Ok(changed)
+	}
+}
+
+/// Collections of untyped documents, without unique indexes
+#[derive(Default)]
+pub struct MemoryDocumentStore {
+	collections: Mutex<HashMap<String, Vec<Document>>>
+}
+
+impl MemoryDocumentStore {
+	pub fn new() -> MemoryDocumentStore {
+		Default::default()
+	}
+	/// Snapshot of every document of a collection, in insertion order
+	pub fn all(&self, collection: &str) -> Vec<Document> {
+		self.collections.lock().unwrap().get(collection).cloned().unwrap_or_default()
+	}
+	fn matching(&self, collection: &str, filter: &Document) -> Vec<Document> {
+		self.all(collection).into_iter().filter(|d| matches_filter(d, filter)).collect()
+	}
+}
+
+/// Whether `document` matches `filter`, only top level fields, equality, `$or`, `$ne`, `$exists`
+/// and `$type: "string"` are supported
+fn matches_filter(document: &Document, filter: &Document) -> bool {
+	filter.iter().all(|(key, condition)| match key.as_str() {
+		"$or" => condition.as_array().map_or(false, |filters| filters.iter().any(|f| f.as_document().map_or(false, |f| matches_filter(document, f)))),
+		field => matches_condition(document.get(field), condition)
+	})
+}
+
+fn matches_condition(value: Option<&Bson>, condition: &Bson) -> bool {
+	let operators = match condition.as_document() {
+		Some(operators) if operators.keys().all(|k| k.starts_with('$')) => operators,
+		_ => return value == Some(condition)
+	};
+	operators.iter().all(|(operator, argument)| match operator.as_str() {
+		"$ne" => value != Some(argument),
+		"$exists" => value.is_some() == argument.as_bool().unwrap_or(true),
+		"$type" => argument.as_str() == Some("string") && matches!(value, Some(Bson::String(_))),
+		_ => panic!("unsupported query operator {}", operator)
+	})
+}
+
+/// Set a field by its dotted path, like `$set`
+fn set_path(document: &mut Document, path: &str, value: Bson) {
+	match path.split_once('.') {
+		Some((field, rest)) => {
+			if !matches!(document.get(field), Some(Bson::Document(_))) {
+				document.insert(field, Document::new());
+			}
+			if let Some(Bson::Document(nested)) = document.get_mut(field) {
+				set_path(nested, rest, value);
+			}
+		},
+		None => { document.insert(path, value); }
+	}
+}
+
+#[async_trait]
+impl DocumentStore for MemoryDocumentStore {
+	async fn count(&self, collection: &str, filter: Document) -> Result<u64, Error> {
+		Ok(self.matching(collection, &filter).len() as u64)
+	}
+	async fn find(&self, collection: &str, filter: Document) -> Result<BoxStream<'static, Result<Document, Error>>, Error> {
+		Ok(futures::stream::iter(self.matching(collection, &filter).into_iter().map(Ok)).boxed())
+	}
+	async fn find_one(&self, collection: &str, filter: Document) -> Result<Option<Document>, Error> {
+		Ok(self.matching(collection, &filter).into_iter().next())
+	}
+	async fn set_fields(&self, collection: &str, id: &Bson, set: Document) -> Result<(), Error> {
+		let mut collections = self.collections.lock().unwrap();
+		if let Some(document) = collections.entry(collection.to_string()).or_default().iter_mut().find(|d| d.get("_id") == Some(id)) {
+			for (path, value) in set {
+				set_path(document, &path, value);
+			}
+		}
+		Ok(())
+	}
+	async fn upsert(&self, collection: &str, document: Document) -> Result<(), Error> {
+		let id = document.get("_id").cloned().ok_or_else(|| Error::internal("upserting document without _id"))?;
+		let mut collections = self.collections.lock().unwrap();
+		let documents = collections.entry(collection.to_string()).or_default();
+		match documents.iter_mut().find(|d| d.get("_id") == Some(&id)) {
+			Some(existing) => *existing = document,
+			None => documents.push(document)
+		}
+		Ok(())
+	}
+}
+
+pub struct MemoryKeyValueStore {
+	data: Mutex<HashMap<String, (String, Option<DateTime<Utc>>)>>,
+	published: Mutex<Vec<(String, String)>>,
+	clock: Arc<dyn Clock>
+}
+
+impl MemoryKeyValueStore {
+	pub fn new() -> MemoryKeyValueStore {
+		MemoryKeyValueStore::with_clock(Arc::new(SystemClock))
+	}
+	/// Keys expire according to `clock`
+	pub fn with_clock(clock: Arc<dyn Clock>) -> MemoryKeyValueStore {
+		MemoryKeyValueStore {
+			data: Mutex::new(HashMap::new()),
+			published: Mutex::new(vec![]),
+			clock: clock
+		}
+	}
+	/// Every `(channel, message)` published so far
+	pub fn published(&self) -> Vec<(String, String)> {
+		self.published.lock().unwrap().clone()
+	}
+	fn expiry(&self, ttl: usize) -> Option<DateTime<Utc>> {
+		Some(self.clock.now() + Duration::seconds(ttl as i64))
+	}
+	fn live_value(&self, data: &mut HashMap<String, (String, Option<DateTime<Utc>>)>, key: &str) -> Option<String> {
+		let expired = match data.get(key) {
+			Some((_, Some(expires_at))) => *expires_at <= self.clock.now(),
+			Some((_, None)) => false,
+			None => return None
+		};
+		if expired {
+			data.remove(key);
+			return None;
+		}
+		data.get(key).map(|(v, _)| v.clone())
+	}
+}
+
+#[async_trait]
+impl KeyValueStore for MemoryKeyValueStore {
+	async fn get(&self, key: &str) -> Result<Option<String>, Error> {
+		Ok(self.live_value(&mut self.data.lock().unwrap(), key))
+	}
+	async fn set(&self, key: &str, value: &str, ttl: Option<usize>) -> Result<(), Error> {
+		let expires_at = ttl.and_then(|ttl| self.expiry(ttl));
+		self.data.lock().unwrap().insert(key.to_string(), (value.to_string(), expires_at));
+		Ok(())
+	}
+	async fn set_nx(&self, key: &str, value: &str, ttl: usize) -> Result<bool, Error> {
+		let mut data = self.data.lock().unwrap();
+		if self.live_value(&mut data, key).is_some() {
+			return Ok(false);
+		}
+		data.insert(key.to_string(), (value.to_string(), self.expiry(ttl)));
+		Ok(true)
+	}
+	async fn del(&self, key: &str) -> Result<(), Error> {
+		self.data.lock().unwrap().remove(key);
+		Ok(())
+	}
+	async fn incr_by(&self, key: &str, delta: i64) -> Result<i64, Error> {
+		let mut data = self.data.lock().unwrap();
+		let current = match self.live_value(&mut data, key) {
+			Some(v) => v.parse::<i64>().map_err(|_| Error::internal("value is not an integer"))?,
+			None => 0
+		};
+		let expires_at = data.get(key).and_then(|(_, e)| *e);
+		data.insert(key.to_string(), ((current + delta).to_string(), expires_at));
+		Ok(current + delta)
+	}
+	async fn incr_with_ttl(&self, key: &str, ttl: usize) -> Result<i64, Error> {
+		let mut data = self.data.lock().unwrap();
+		let (value, expires_at) = match self.live_value(&mut data, key) {
+			Some(v) => (v.parse::<i64>().map_err(|_| Error::internal("value is not an integer"))? + 1, data.get(key).and_then(|(_, e)| *e)),
+			None => (1, self.expiry(ttl))
+		};
+		data.insert(key.to_string(), (value.to_string(), expires_at));
+		Ok(value)
+	}
+	async fn consume_budgets(&self, budgets: &[(String, u64, usize)]) -> Result<Option<usize>, Error> {
+		let mut data = self.data.lock().unwrap();
+		for (i, (key, limit, _)) in budgets.iter().enumerate() {
+			let used = self.live_value(&mut data, key).and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+			if used >= *limit {
+				return Ok(Some(i));
+			}
+		}
+		for (key, _, ttl) in budgets {
+			let used = self.live_value(&mut data, key).and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+			let expires_at = if used == 0 { self.expiry(*ttl) } else { data.get(key).and_then(|(_, e)| *e) };
+			data.insert(key.clone(), ((used + 1).to_string(), expires_at));
+		}
+		Ok(None)
+	}
+	async fn publish(&self, channel: &str, message: &str) -> Result<(), Error> {
+		self.published.lock().unwrap().push((channel.to_string(), message.to_string()));
+		Ok(())
+	}
+}
@@ -0,0 +1,224 @@
+//! Versioned changes to stored documents
+//!
+//! Applied migrations are recorded in `schema_migrations` and never run twice.
+//! A migration interrupted halfway is not recorded, so every migration must be safe to run again.
+
+use async_trait::async_trait;
+use bson::{Bson, DateTime, Document, doc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{common::{normalize_email, normalize_phone}, error::Error, models::VOTER_SCHEMA_VERSION, repository::DocumentStore};
+
+const MIGRATIONS_COLLECTION: &'static str = "schema_migrations";
+/// Print progress every this many scanned documents
+const PROGRESS_INTERVAL: u64 = 1000;
+
+const EMAIL_FIELDS: [&'static str; 4] = ["email", "target_email", "old_email", "new_email"];
+const PHONE_FIELDS: [&'static str; 4] = ["phone", "target_phone", "old_phone", "new_phone"];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AppliedMigration {
+	#[serde(rename = "_id")]
+	id: String,
+	description: String,
+	applied_at: DateTime,
+	scanned: i64,
+	changed: i64,
+	conflicts: i64
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MigrationReport {
+	pub scanned: u64,
+	/// Documents changed, or which would be changed in a dry run
+	pub changed: u64,
+	/// Documents left untouched because the change would take a unique value owned by another document
+	pub conflicts: u64
+}
+
+#[async_trait]
+pub trait Migration: Send + Sync {
+	/// Unique id, prefixed with a sequence number
+	fn id(&self) -> &'static str;
+	fn description(&self) -> &'static str;
+	/// Apply the migration, or only count what would change if `dry_run`
+	async fn run(&self, store: &dyn DocumentStore, dry_run: bool) -> Result<MigrationReport, Error>;
+}
+
+/// Every migration, in the order they are applied
+pub fn all() -> Vec<Box<dyn Migration>> {
+	vec![
+		Box::new(NormalizeVoterContacts),
+		Box::new(NormalizeLogContacts),
+		Box::new(BackfillVoterFields),
+	]
+}
+
+/// Run every migration not applied yet, in order
+pub async fn run_pending(store: &dyn DocumentStore, dry_run: bool) -> Result<Vec<(&'static str, MigrationReport)>, Error> {
+	let applied: Vec<Document> = store.find(MIGRATIONS_COLLECTION, doc! {}).await?.try_collect().await?;
+	let applied: Vec<String> = applied.into_iter().filter_map(|m| bson::from_document::<AppliedMigration>(m).ok()).map(|m| m.id).collect();
+	let mut reports = vec![];
+	for migration in all() {
+		if applied.iter().any(|id| id == migration.id()) {
+			continue;
+		}
+		println!(" -- [Migration] {}{}: {}", if dry_run { "(dry run) " } else { "" }, migration.id(), migration.description());
+		let report = migration.run(store, dry_run).await?;
+		if !dry_run {
+			let applied = AppliedMigration {
+				id: migration.id().to_string(),
+				description: migration.description().to_string(),
+				applied_at: DateTime::now(),
+				scanned: report.scanned as i64,
+				changed: report.changed as i64,
+				conflicts: report.conflicts as i64
+			};
+			store.upsert(MIGRATIONS_COLLECTION, bson::to_document(&applied).map_err(Error::internal)?).await?;
+		}
+		reports.push((migration.id(), report));
+	}
+	Ok(reports)
+}
+
+/// Scan every document of `collection` matching `filter` and `$set` whatever `change` returns for it
+///
+/// Values of `unique_fields` already owned by another document are counted as conflicts and left alone.
+async fn rewrite<F>(id: &str, store: &dyn DocumentStore, collection: &str, filter: Document, unique_fields: &[&str], dry_run: bool, change: F) -> Result<MigrationReport, Error>
+where F: Fn(&Document) -> Option<Document> + Send + Sync {
+	let total = store.count(collection, filter.clone()).await?;
+	let mut report = MigrationReport::default();
+	let mut cursor = store.find(collection, filter).await?;
+	while let Some(document) = cursor.try_next().await? {
+		report.scanned += 1;
+		if report.scanned % PROGRESS_INTERVAL == 0 {
+			println!(" -- [Migration] {}: {}/{} scanned, {} changed", id, report.scanned, total, report.changed);
+		}
+		let set = match change(&document) {
+			Some(set) if !set.is_empty() => set,
+			_ => continue
+		};
+		let doc_id = document.get("_id").cloned().unwrap_or(Bson::Null);
+		let mut taken = false;
+		for field in unique_fields {
+			if let Some(value) = set.get(*field) {
+				if store.find_one(collection, doc! { *field: value.clone(), "_id": { "$ne": doc_id.clone() } }).await?.is_some() {
+					taken = true;
+				}
+			}
+		}
+		if taken {
+			println!(" -- [Migration] {}: {} conflicts with another document, skipped: {}", id, doc_id, set);
+			report.conflicts += 1;
+			continue;
+		}
+		if dry_run {
+			report.changed += 1;
+			continue;
+		}
+		match store.set_fields(collection, &doc_id, set).await {
+			Ok(_) => report.changed += 1,
+			Err(Error::Conflict(_)) => {
+				println!(" -- [Migration] {}: {} conflicts with another document, skipped", id, doc_id);
+				report.conflicts += 1;
+			},
+			Err(e) => return Err(e)
+		}
+	}
+	println!(" -- [Migration] {}: done, {} scanned, {} changed, {} conflicts", id, report.scanned, report.changed, report.conflicts);
+	Ok(report)
+}
+
+/// Add a `$set` of every email and phone field of `document` not in canonical form, paths prefixed with `prefix`
+fn normalize_contacts(document: &Document, prefix: &str, set: &mut Document) {
+	for (key, value) in document {
+		let normalized = match value.as_str() {
+			Some(v) if EMAIL_FIELDS.contains(&key.as_str()) => normalize_email(v),
+			Some(v) if PHONE_FIELDS.contains(&key.as_str()) => normalize_phone(v),
+			_ => continue
+		};
+		if Some(normalized.as_str()) != value.as_str() {
+			set.insert(format!("{}{}", prefix, key), normalized);
+		}
+	}
+}
+
+/// Lowercase emails and strip separators and the mainland prefix from phones of voters
+pub struct NormalizeVoterContacts;
+
+#[async_trait]
+impl Migration for NormalizeVoterContacts {
+	fn id(&self) -> &'static str {
+		"0001_normalize_voter_contacts"
+	}
+	fn description(&self) -> &'static str {
+		"normalize emails and phone numbers of voters"
+	}
+	async fn run(&self, store: &dyn DocumentStore, dry_run: bool) -> Result<MigrationReport, Error> {
+		let filter = doc! { "$or": [ { "email": { "$type": "string" } }, { "phone": { "$type": "string" } } ] };
+		rewrite(self.id(), store, "voters", filter, &["email", "phone"], dry_run, |voter| {
+			let mut set = Document::new();
+			normalize_contacts(voter, "", &mut set);
+			Some(set)
+		}).await
+	}
+}
+
+/// Same as `NormalizeVoterContacts` for activity logs, so logs can be searched by the stored contact
+pub struct NormalizeLogContacts;
+
+#[async_trait]
+impl Migration for NormalizeLogContacts {
+	fn id(&self) -> &'static str {
+		"0002_normalize_log_contacts"
+	}
+	fn description(&self) -> &'static str {
+		"normalize emails and phone numbers in voter_logs"
+	}
+	async fn run(&self, store: &dyn DocumentStore, dry_run: bool) -> Result<MigrationReport, Error> {
+		rewrite(self.id(), store, "voter_logs", doc! {}, &[], dry_run, |entry| {
+			// entries are stored as `{ "<variant>": { ...fields } }`
+			let mut set = Document::new();
+			for (variant, body) in entry {
+				if let Some(body) = body.as_document() {
+					normalize_contacts(body, &format!("{}.", variant), &mut set);
+				}
+			}
+			Some(set)
+		}).await
+	}
+}
+
+/// Fill fields missing from voters written by older versions
+pub struct BackfillVoterFields;
+
+#[async_trait]
+impl Migration for BackfillVoterFields {
+	fn id(&self) -> &'static str {
+		"0003_backfill_voter_fields"
+	}
+	fn description(&self) -> &'static str {
+		"backfill verified flags and schema_version of voters"
+	}
+	async fn run(&self, store: &dyn DocumentStore, dry_run: bool) -> Result<MigrationReport, Error> {
+		let filter = doc! { "$or": [
+			{ "schema_version": { "$exists": false } },
+			{ "email_verified": { "$exists": false } },
+			{ "phone_verified": { "$exists": false } },
+		] };
+		rewrite(self.id(), store, "voters", filter, &[], dry_run, |voter| {
+			let mut set = Document::new();
+			if !voter.contains_key("email_verified") {
+				set.insert("email_verified", false);
+			}
+			if !voter.contains_key("phone_verified") {
+				set.insert("phone_verified", false);
+			}
+			if !voter.contains_key("schema_version") {
+				set.insert("schema_version", VOTER_SCHEMA_VERSION);
+			}
+			Some(set)
+		}).await
+	}
+}
@@ -1,20 +1,86 @@
-
-use std::fmt;
-
-use actix_web::{HttpResponse, ResponseError, http::StatusCode};
-use chrono::{Utc};
+use chrono::Utc;
 use jwt_simple::prelude::{Claims, Duration, ECDSAP256kKeyPairLike, ES256kKeyPair, UnixTimeStamp};
-use pvrustlib::ServiceError;
 use serde::{Serialize, Deserialize};
 use bson::{DateTime, oid::ObjectId};
 
-use crate::{context::LoginSession, common::SERVICE_NAME};
+use crate::{context::LoginSession, error::Error, repository::VoterQuery};
+
+/// Lifetime of vote tokens
+pub const VOTE_TOKEN_VALID_HOURS: u64 = 7 * 24;
+/// Lifetime of userspace (session) tokens
+pub const USER_TOKEN_VALID_HOURS: u64 = 7 * 24;
+/// Stands in for purged personal data in fields which can not be empty
+pub const PURGED: &'static str = "[purged]";
+/// Stands in for secrets such as password hashes and codes in data shown to people
+pub const REDACTED: &'static str = "<redacted>";
+/// Lifetime of admin tokens
+pub const ADMIN_TOKEN_VALID_HOURS: u64 = 12;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct VoteTokenClaim {
 	pub vote_id: Option<String>
 }
 
+/// What an admin may do, each role includes the ones before it
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminRole {
+	/// Search and view voters
+	Viewer,
+	/// Ban, unban and log voters out
+	Moderator,
+	/// Override contacts
+	Superadmin
+}
+
+impl std::str::FromStr for AdminRole {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"viewer" => Ok(AdminRole::Viewer),
+			"moderator" => Ok(AdminRole::Moderator),
+			"superadmin" => Ok(AdminRole::Superadmin),
+			_ => Err(Error::Validation("UNKNOWN_ROLE"))
+		}
+	}
+}
+
+/// Claims of admin tokens, audience `admin`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AdminClaim {
+	pub operator: String,
+	pub role: AdminRole
+}
+
+/// Generate a signed JWT token for the admin API
+pub fn generate_admin_token(key: &ES256kKeyPair, operator: &str, role: AdminRole, now: chrono::DateTime<Utc>) -> String {
+	let claim = AdminClaim {
+		operator: operator.to_string(),
+		role: role
+	};
+	let claims = Claims::with_custom_claims_given_valid_period(claim, UnixTimeStamp::from_secs(now.timestamp() as u64), Duration::from_hours(ADMIN_TOKEN_VALID_HOURS))
+		.with_audience("admin");
+	key.sign(claims).unwrap()
+}
+
+/// Set on voters banned or suspended by a moderator
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Ban {
+	pub reason: String,
+	pub operator: String,
+	pub created_at: DateTime,
+	/// End of a suspension, bans without one are permanent
+	#[serde(default)]
+	pub expires_at: Option<DateTime>
+}
+
+impl Ban {
+	pub fn is_active(&self, now: DateTime) -> bool {
+		self.expires_at.map_or(true, |expires_at| expires_at > now)
+	}
+}
+
 
 #[derive(Clone, Serialize, Deserialize)]
 /// 给前端的投票人
@@ -25,9 +91,18 @@ pub struct VoterFE {
 	pub phone: Option<String>,
 	pub email: Option<String>,
 	pub thbwiki: bool,
-	pub patchyvideo: bool
+	pub patchyvideo: bool,
+	/// TOTP second factor enabled
+	pub totp: bool,
+	/// Unused recovery codes
+	pub recovery_codes: usize,
+	/// Registered passkeys
+	pub passkeys: usize
 }
 
+/// Layout version of newly written voter documents
+pub const VOTER_SCHEMA_VERSION: i32 = 1;
+
 /// 投票人
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Voter {
@@ -41,6 +116,9 @@ pub struct Voter {
 	pub password_hashed: Option<String>,
 	/// Used only in legacy login
 	pub salt: Option<String>,
+	/// Version of the pepper mixed into `password_hashed`, unset if none
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub pepper_version: Option<i32>,
 	/// 新版投票用户创建日期
 	pub created_at: DateTime,
 	pub nickname: Option<String>,
@@ -49,41 +127,132 @@ pub struct Voter {
 	pub pfp: Option<String>,
 	pub thbwiki_uid: Option<String>,
 	#[serde(skip_serializing_if = "Option::is_none")]
-	pub removed: Option<bool>
+	pub removed: Option<bool>,
+	/// Layout version of this document, see `migrations`
+	#[serde(default)]
+	pub schema_version: i32,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub ban: Option<Ban>,
+	/// Set while a removal can still be undone, personal data is purged afterwards
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub purge_after: Option<DateTime>,
+	/// Personal data is gone, the voter can not be restored
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub purged_at: Option<DateTime>,
+	/// TOTP secret encrypted with `SecretCipher`, set once enrollment is confirmed
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub totp_secret: Option<String>,
+	/// SHA-256 of every unused recovery code
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub recovery_codes: Vec<String>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub passkeys: Vec<Passkey>
+}
+
+/// WebAuthn credential a voter can log in with
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Passkey {
+	/// Base64url, as sent by browsers
+	pub credential_id: String,
+	/// Uncompressed SEC1 P-256 point, hex encoded
+	pub public_key: String,
+	/// Signature counter last reported by the authenticator, 0 if it keeps none
+	pub sign_count: u32,
+	/// Given by the voter to tell its passkeys apart
+	pub name: Option<String>,
+	pub created_at: DateTime,
+	pub last_used_at: Option<DateTime>
 }
 
 impl Voter {
+	/// Drop every piece of personal data, only the id and creation date are kept
+	pub fn pseudonymize(&mut self, now: DateTime) {
+		self.phone = None;
+		self.phone_verified = false;
+		self.email = None;
+		self.email_verified = false;
+		self.password_hashed = None;
+		self.salt = None;
+		self.pepper_version = None;
+		self.totp_secret = None;
+		self.recovery_codes.clear();
+		self.passkeys.clear();
+		self.nickname = None;
+		self.signup_ip = None;
+		self.qq_openid = None;
+		self.pfp = None;
+		self.thbwiki_uid = None;
+		self.ban = None;
+		self.removed = Some(true);
+		self.purge_after = None;
+		self.purged_at = Some(now);
+	}
+	/// Copy with password hashes, salts, second factor secrets and passkey keys replaced by `REDACTED`, for showing to people
+	pub fn redacted(&self) -> Voter {
+		let mut voter = self.clone();
+		if voter.password_hashed.is_some() {
+			voter.password_hashed = Some(REDACTED.to_string());
+		}
+		if voter.salt.is_some() {
+			voter.salt = Some(REDACTED.to_string());
+		}
+		if voter.totp_secret.is_some() {
+			voter.totp_secret = Some(REDACTED.to_string());
+		}
+		voter.recovery_codes.iter_mut().for_each(|c| *c = REDACTED.to_string());
+		voter.passkeys.iter_mut().for_each(|p| p.public_key = REDACTED.to_string());
+		voter
+	}
+	/// Ban or suspension in force at `now`
+	pub fn active_ban(&self, now: DateTime) -> Option<&Ban> {
+		self.ban.as_ref().filter(|ban| ban.is_active(now))
+	}
+	/// Email, phone and nickname, which passwords must not equal
+	pub fn personal_info(&self) -> [Option<&str>; 3] {
+		[self.email.as_deref(), self.phone.as_deref(), self.nickname.as_deref()]
+	}
+	/// Refuse removed and suspended voters
+	pub fn check_usable(&self, now: DateTime) -> Result<(), Error> {
+		if self.removed == Some(true) {
+			return Err(Error::Forbidden("VOTER_REMOVED"));
+		}
+		if self.active_ban(now).is_some() {
+			return Err(Error::Forbidden("VOTER_SUSPENDED"));
+		}
+		Ok(())
+	}
 	/// Generate a unqiue id connectted to voter for a given year
-	pub fn generate_vote_id(&self, vote_year: u32) -> Result<String, ServiceError> {
+	pub fn generate_vote_id(&self, vote_year: u32) -> Result<String, Error> {
 		if self.phone_verified || self.email_verified {
 			let id = self._id.as_ref().unwrap().clone().to_string();
 			return Ok(format!("thvote-{}-{}", vote_year, id));
 		}
-		return Err(ServiceError::new_error_kind(SERVICE_NAME, "USER_UNVERIFIED"));
+		return Err(Error::Auth("USER_UNVERIFIED"));
 	}
 	/// Generate a signed JWT token for voting with
 	/// 1. vote-id
 	/// 2. valid since
 	/// 3. valid until
 	/// 4. scope (vote or login)
-	pub fn generate_vote_token(&self, vote_year: u32, key: &ES256kKeyPair) -> Result<String, ServiceError> {
+	pub fn generate_vote_token(&self, vote_year: u32, key: &ES256kKeyPair, now: chrono::DateTime<Utc>) -> Result<String, Error> {
+		self.check_usable(DateTime::from_millis(now.timestamp_millis()))?;
 		let addtional_info = VoteTokenClaim {
 			vote_id: Some(self.generate_vote_id(vote_year)?)
 		};
 		// let claims = Claims::with_custom_claims_given_valid_period(addtional_info, UnixTimeStamp::new(1633060800, 0), Duration::from_hours(365 * 24))
 		// 	.with_audience("vote");
-		let claims = Claims::with_custom_claims(addtional_info, Duration::from_hours(7 * 24))
+		let claims = Claims::with_custom_claims_given_valid_period(addtional_info, UnixTimeStamp::from_secs(now.timestamp() as u64), Duration::from_hours(VOTE_TOKEN_VALID_HOURS))
 			.with_audience("vote");
 		Ok(key.sign(claims).unwrap())
 	}
 	/// Generate a signed JWT token for user space with
 	/// 1. valid until
 	/// 2. scope (vote or login)
-	pub fn generate_user_auth(&self, key: &ES256kKeyPair) -> String {
+	pub fn generate_user_auth(&self, key: &ES256kKeyPair, now: chrono::DateTime<Utc>) -> String {
 		let addtional_info = VoteTokenClaim {
 			vote_id: Some(self._id.as_ref().unwrap().clone().to_string())
 		};
-		let claims = Claims::with_custom_claims(addtional_info, Duration::from_hours(7 * 24))
+		let claims = Claims::with_custom_claims_given_valid_period(addtional_info, UnixTimeStamp::from_secs(now.timestamp() as u64), Duration::from_hours(USER_TOKEN_VALID_HOURS))
 			.with_audience("userspace");
 		key.sign(claims).unwrap()
 	}
@@ -95,13 +264,18 @@ impl Voter {
 			phone: self.phone.clone(),
 			email: self.email.clone(),
 			thbwiki: false,
-			patchyvideo: false
+			patchyvideo: false,
+			totp: self.totp_secret.is_some(),
+			recovery_codes: self.recovery_codes.len(),
+			passkeys: self.passkeys.len()
 		}
 	}
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct UserEventMeta {
+    /// Only trusted when sent by the internal gateway, see `client_ip`
+    #[serde(default)]
     pub user_ip: String,
     pub additional_fingureprint: Option<String>
 }
@@ -125,11 +299,20 @@ pub struct EmailLoginInputsForExistingVoters {
     pub meta: UserEventMeta
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PhoneLoginInputsForExistingVoters {
+    pub phone: String,
+    pub password: String,
+    pub meta: UserEventMeta
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct EmailLoginInputs {
     pub email: String,
     pub nickname: Option<String>,
     pub verify_code: String,
+    /// Password to set if this creates a new voter
+    pub password: Option<String>,
     pub meta: UserEventMeta
 }
 
@@ -168,11 +351,28 @@ pub struct TokenStatusInputs {
 	pub user_token: String
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VoteTokenStatusInputs {
+	pub vote_token: String
+}
+
+/// Whether the voter behind a valid vote token may still vote
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VoteTokenStatus {
+	pub vote_id: String,
+	pub removed: bool,
+	pub suspended: bool,
+	/// Unix millis, unset if not suspended or banned permanently
+	pub suspended_until: Option<i64>
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PhoneLoginInputs {
     pub phone: String,
     pub nickname: Option<String>,
     pub verify_code: String,
+    /// Password to set if this creates a new voter
+    pub password: Option<String>,
     pub meta: UserEventMeta
 }
 
@@ -186,6 +386,25 @@ pub struct LoginResults {
 	pub session_token: String
 }
 
+/// Second factor asked for before any token is issued
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MfaChallenge {
+	/// Always true, tells this apart from `LoginResults`
+	pub mfa_required: bool,
+	/// Sent back to `/v1/login-mfa` with the code, valid for 5 minutes
+	pub challenge_token: String,
+	/// Factors the voter can answer with
+	pub methods: Vec<String>
+}
+
+/// Outcome of a login, tokens or a second factor challenge
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+	Complete(LoginResults),
+	MfaRequired(MfaChallenge)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ActivityLogEntry {
 	SendEmail {
@@ -254,12 +473,601 @@ pub enum ActivityLogEntry {
 		uid: ObjectId,
 		requester_ip: Option<String>,
 		requester_additional_fingerprint: Option<String>
+	},
+	/// Admin alert, a send budget ran out and sending is paused for the rest of the window
+	SendQuotaExhausted {
+		created_at: DateTime,
+		channel: String,
+		scope: String,
+		limit: i64,
+		requester_ip: Option<String>
+	},
+	RestoreVoter {
+		created_at: DateTime,
+		uid: ObjectId,
+		requester_ip: Option<String>,
+		requester_additional_fingerprint: Option<String>
+	},
+	/// Forgotten password replaced using a code sent to `channel` (`email` or `phone`)
+	ResetPassword {
+		created_at: DateTime,
+		uid: ObjectId,
+		channel: String,
+		requester_ip: Option<String>,
+		requester_additional_fingerprint: Option<String>
+	},
+	/// A voter downloaded everything held about it
+	ExportVoterData {
+		created_at: DateTime,
+		uid: ObjectId,
+		requester_ip: Option<String>,
+		requester_additional_fingerprint: Option<String>
+	},
+	/// Personal data of a removed voter was purged after the grace period
+	PurgeVoter {
+		created_at: DateTime,
+		uid: ObjectId
+	},
+	/// Incorrect password for the voter, or a login refused because it is locked out
+	LoginFailed {
+		created_at: DateTime,
+		uid: ObjectId,
+		email: Option<String>,
+		phone: Option<String>,
+		reason: String,
+		requester_ip: Option<String>,
+		requester_additional_fingerprint: Option<String>
+	},
+	/// Failed password logins locked the voter, or the source IP when `uid` is unset, for `seconds`
+	LoginLocked {
+		created_at: DateTime,
+		uid: Option<ObjectId>,
+		seconds: i64,
+		requester_ip: Option<String>,
+		requester_additional_fingerprint: Option<String>
+	},
+	/// A locked voter lifted the lock with a code sent to `channel` (`email` or `phone`)
+	AccountUnlocked {
+		created_at: DateTime,
+		uid: ObjectId,
+		channel: String,
+		requester_ip: Option<String>,
+		requester_additional_fingerprint: Option<String>
+	},
+	/// A second factor or passkey was added or removed, `method` is e.g. `totp` or `passkey`
+	UpdateMfa {
+		created_at: DateTime,
+		uid: ObjectId,
+		method: String,
+		enabled: bool,
+		requester_ip: Option<String>,
+		requester_additional_fingerprint: Option<String>
+	},
+	/// A recovery code was used, `remaining` are left
+	UseRecoveryCode {
+		created_at: DateTime,
+		uid: ObjectId,
+		remaining: i32,
+		requester_ip: Option<String>,
+		requester_additional_fingerprint: Option<String>
+	},
+	/// Support action done by an operator through the admin tools
+	AdminAction {
+		created_at: DateTime,
+		operator: String,
+		/// Voter acted on, if any
+		uid: Option<ObjectId>,
+		action: String,
+		detail: Option<String>
+	}
+}
+
+impl ActivityLogEntry {
+	/// Voter the entry is about
+	pub fn uid(&self) -> Option<&ObjectId> {
+		match self {
+			ActivityLogEntry::VoterCreation { uid, .. } |
+			ActivityLogEntry::VoterLogin { uid, .. } |
+			ActivityLogEntry::LoginFailed { uid, .. } |
+			ActivityLogEntry::AccountUnlocked { uid, .. } |
+			ActivityLogEntry::UpdateEmail { uid, .. } |
+			ActivityLogEntry::UpdatePhone { uid, .. } |
+			ActivityLogEntry::UpdateNickname { uid, .. } |
+			ActivityLogEntry::UpdatePassword { uid, .. } |
+			ActivityLogEntry::ResetPassword { uid, .. } |
+			ActivityLogEntry::RemoveVoter { uid, .. } |
+			ActivityLogEntry::RestoreVoter { uid, .. } |
+			ActivityLogEntry::ExportVoterData { uid, .. } |
+			ActivityLogEntry::PurgeVoter { uid, .. } |
+			ActivityLogEntry::UpdateMfa { uid, .. } |
+			ActivityLogEntry::UseRecoveryCode { uid, .. } => Some(uid),
+			ActivityLogEntry::AdminAction { uid, .. } |
+			ActivityLogEntry::LoginLocked { uid, .. } => uid.as_ref(),
+			ActivityLogEntry::SendEmail { .. } | ActivityLogEntry::SendSMS { .. } | ActivityLogEntry::SendQuotaExhausted { .. } => None,
+		}
+	}
+	/// Drop personal data of a purged voter, required fields are overwritten with `PURGED`
+	pub fn pseudonymize(&mut self) {
+		let purged = || PURGED.to_string();
+		match self {
+			ActivityLogEntry::SendEmail { target_email, code, requester_ip, requester_additional_fingerprint, .. } => {
+				*target_email = purged();
+				*code = purged();
+				*requester_ip = None;
+				*requester_additional_fingerprint = None;
+			},
+			ActivityLogEntry::SendSMS { target_phone, code, requester_ip, requester_additional_fingerprint, .. } => {
+				*target_phone = purged();
+				*code = purged();
+				*requester_ip = None;
+				*requester_additional_fingerprint = None;
+			},
+			ActivityLogEntry::VoterCreation { email, phone, nickname, requester_ip, requester_additional_fingerprint, .. } => {
+				*email = None;
+				*phone = None;
+				*nickname = None;
+				*requester_ip = None;
+				*requester_additional_fingerprint = None;
+			},
+			ActivityLogEntry::VoterLogin { email, phone, requester_ip, requester_additional_fingerprint, .. } |
+			ActivityLogEntry::LoginFailed { email, phone, requester_ip, requester_additional_fingerprint, .. } => {
+				*email = None;
+				*phone = None;
+				*requester_ip = None;
+				*requester_additional_fingerprint = None;
+			},
+			ActivityLogEntry::UpdateEmail { old_email, new_email, requester_ip, requester_additional_fingerprint, .. } => {
+				*old_email = None;
+				*new_email = purged();
+				*requester_ip = None;
+				*requester_additional_fingerprint = None;
+			},
+			ActivityLogEntry::UpdatePhone { old_phone, new_phone, requester_ip, requester_additional_fingerprint, .. } => {
+				*old_phone = None;
+				*new_phone = purged();
+				*requester_ip = None;
+				*requester_additional_fingerprint = None;
+			},
+			ActivityLogEntry::UpdateNickname { old_nickname, new_nickname, requester_ip, requester_additional_fingerprint, .. } => {
+				*old_nickname = None;
+				*new_nickname = purged();
+				*requester_ip = None;
+				*requester_additional_fingerprint = None;
+			},
+			ActivityLogEntry::UpdatePassword { requester_ip, requester_additional_fingerprint, .. } |
+			ActivityLogEntry::ResetPassword { requester_ip, requester_additional_fingerprint, .. } |
+			ActivityLogEntry::RemoveVoter { requester_ip, requester_additional_fingerprint, .. } |
+			ActivityLogEntry::RestoreVoter { requester_ip, requester_additional_fingerprint, .. } |
+			ActivityLogEntry::ExportVoterData { requester_ip, requester_additional_fingerprint, .. } |
+			ActivityLogEntry::UpdateMfa { requester_ip, requester_additional_fingerprint, .. } |
+			ActivityLogEntry::UseRecoveryCode { requester_ip, requester_additional_fingerprint, .. } |
+			ActivityLogEntry::LoginLocked { requester_ip, requester_additional_fingerprint, .. } |
+			ActivityLogEntry::AccountUnlocked { requester_ip, requester_additional_fingerprint, .. } => {
+				*requester_ip = None;
+				*requester_additional_fingerprint = None;
+			},
+			ActivityLogEntry::AdminAction { detail, .. } => *detail = None,
+			ActivityLogEntry::PurgeVoter { .. } | ActivityLogEntry::SendQuotaExhausted { .. } => {},
+		}
+	}
+	/// Hide codes which were sent
+	pub fn redact_secrets(&mut self) {
+		match self {
+			ActivityLogEntry::SendEmail { code, .. } | ActivityLogEntry::SendSMS { code, .. } => *code = REDACTED.to_string(),
+			_ => {}
+		}
+	}
+}
+
+/// Voter as shown to admins, without credentials
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdminVoterView {
+	pub uid: String,
+	pub nickname: Option<String>,
+	pub email: Option<String>,
+	pub email_verified: bool,
+	pub phone: Option<String>,
+	pub phone_verified: bool,
+	pub has_password: bool,
+	/// Unix millis
+	pub created_at: i64,
+	pub signup_ip: Option<String>,
+	pub removed: bool,
+	pub ban: Option<AdminBanView>
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdminBanView {
+	pub reason: String,
+	pub operator: String,
+	/// Unix millis
+	pub created_at: i64,
+	/// Unix millis, unset for permanent bans
+	pub expires_at: Option<i64>
+}
+
+impl Voter {
+	pub fn to_admin_view(&self) -> AdminVoterView {
+		AdminVoterView {
+			uid: self._id.as_ref().map(|id| id.to_hex()).unwrap_or_default(),
+			nickname: self.nickname.clone(),
+			email: self.email.clone(),
+			email_verified: self.email_verified,
+			phone: self.phone.clone(),
+			phone_verified: self.phone_verified,
+			has_password: self.password_hashed.is_some(),
+			created_at: self.created_at.timestamp_millis(),
+			signup_ip: self.signup_ip.clone(),
+			removed: self.removed == Some(true),
+			ban: self.ban.as_ref().map(|ban| AdminBanView {
+				reason: ban.reason.clone(),
+				operator: ban.operator.clone(),
+				created_at: ban.created_at.timestamp_millis(),
+				expires_at: ban.expires_at.map(|t| t.timestamp_millis())
+			})
+		}
 	}
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AdminSearchInputs {
+	pub admin_token: String,
+	#[serde(default)]
+	pub query: VoterQuery,
+	/// Starts at 1
+	pub page: Option<u64>,
+	pub page_size: Option<i64>
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AdminSearchResults {
+	pub voters: Vec<AdminVoterView>,
+	pub total: u64,
+	pub page: u64,
+	pub page_size: i64
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AdminInputs {
+	pub admin_token: String
+}
+
+/// Load of the password hashing pool
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HashingMetrics {
+	/// Waiting for a free slot
+	pub queued: usize,
+	pub running: usize,
+	pub completed: u64,
+	/// Refused because the queue was full
+	pub rejected: u64,
+	pub max_concurrent: usize,
+	pub max_queued: usize
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AdminVoterInputs {
+	pub admin_token: String,
+	pub uid: String
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdminLinkedIdentity {
+	pub kind: String,
+	pub value: String
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AdminVoterDetail {
+	pub voter: AdminVoterView,
+	pub identities: Vec<AdminLinkedIdentity>,
+	/// Newest first
+	pub logs: Vec<ActivityLogEntry>
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AdminBanInputs {
+	pub admin_token: String,
+	pub uid: String,
+	pub reason: String,
+	/// Suspend for this many hours instead of banning permanently
+	pub hours: Option<i64>
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AdminContactOverrideInputs {
+	pub admin_token: String,
+	pub uid: String,
+	/// `email` or `phone`
+	pub contact: String,
+	/// New value, the contact is removed if unset
+	pub value: Option<String>
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct RemoveVoterRequest {
 	pub user_token: String,
+    /// Re-authentication, the password if the voter has one
     pub old_password: Option<String>,
+    /// Re-authentication, a code sent to the voter's phone or email otherwise
+    pub verify_code: Option<String>,
+    pub meta: UserEventMeta
+}
+
+/// Undo a removal during the grace period, with the same credentials as a login
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RestoreVoterRequest {
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub verify_code: Option<String>,
+    pub password: Option<String>,
+    pub meta: UserEventMeta
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DataExportInputs {
+    pub user_token: String,
+    pub meta: UserEventMeta
+}
+
+/// Everything held about a voter, as handed out to the voter
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DataExport {
+    /// Unix millis
+    pub exported_at: i64,
+    /// Password hash and salt are redacted
+    pub voter: Voter,
+    pub identities: Vec<AdminLinkedIdentity>,
+    pub sessions: Vec<ExportedSession>,
+    /// Activity log entries about the voter and codes sent to its contacts, oldest first
+    pub logs: Vec<ActivityLogEntry>
+}
+
+/// A login whose session token is still valid
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExportedSession {
+    /// Unix millis
+    pub logged_in_at: i64,
+    /// Unix millis
+    pub expires_at: i64,
+    pub ip: Option<String>
+}
+
+/// Ask for a password reset code, either `email` or `phone` is set
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SendPasswordResetCodeRequest {
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub meta: UserEventMeta
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub verify_code: String,
+    pub new_password: String,
+    pub meta: UserEventMeta
+}
+
+/// Ask for a code unlocking an account locked after failed logins, either `email` or `phone` is set
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SendUnlockCodeRequest {
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub meta: UserEventMeta
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UnlockAccountRequest {
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub verify_code: String,
+    pub meta: UserEventMeta
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MfaLoginInputs {
+    pub challenge_token: String,
+    pub code: String,
+    pub meta: UserEventMeta
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TotpEnrollInputs {
+    pub user_token: String,
+    pub meta: UserEventMeta
+}
+
+/// Secret to add to an authenticator app, shown once
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    /// Base32, for manual entry
+    pub secret: String,
+    pub otpauth_uri: String
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TotpConfirmInputs {
+    pub user_token: String,
+    pub code: String,
+    pub meta: UserEventMeta
+}
+
+/// Voters with a password confirm with it, others with a code sent to their phone or email
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TotpDisableInputs {
+    pub user_token: String,
+    pub old_password: Option<String>,
+    pub verify_code: Option<String>,
+    pub meta: UserEventMeta
+}
+
+/// Re-authentication as for `TotpDisableInputs`
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RecoveryCodesInputs {
+    pub user_token: String,
+    pub old_password: Option<String>,
+    pub verify_code: Option<String>,
+    pub meta: UserEventMeta
+}
+
+/// New recovery codes, shown once
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    pub codes: Vec<String>
+}
+
+/// Re-authentication as for `TotpDisableInputs`, plus a TOTP or recovery code if TOTP is enabled
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PasskeyRegistrationInputs {
+    pub user_token: String,
+    pub old_password: Option<String>,
+    pub verify_code: Option<String>,
+    pub mfa_code: Option<String>,
+    pub meta: UserEventMeta
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyUser {
+    /// Base64url of the voter's id
+    pub id: String,
+    pub name: String,
+    pub display_name: String
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// COSE algorithm
+    pub alg: i32
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub id: String
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    pub resident_key: String,
+    pub user_verification: String
+}
+
+/// `PublicKeyCredentialCreationOptions` for `navigator.credentials.create`, binary fields in base64url
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyCreationOptions {
+    pub challenge: String,
+    pub rp: RelyingParty,
+    pub user: PasskeyUser,
+    pub pub_key_cred_params: Vec<CredentialParameters>,
+    /// Milliseconds
+    pub timeout: u64,
+    pub attestation: String,
+    pub exclude_credentials: Vec<CredentialDescriptor>,
+    pub authenticator_selection: AuthenticatorSelection
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String
+}
+
+/// Credential returned by `navigator.credentials.create`, binary fields in base64url
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PasskeyRegisterInputs {
+    pub user_token: String,
+    pub credential: RegistrationCredential,
+    pub name: Option<String>,
+    pub meta: UserEventMeta
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PasskeyListInputs {
+    pub user_token: String
+}
+
+/// A registered passkey as shown to its owner
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PasskeyFE {
+    pub credential_id: String,
+    pub name: Option<String>,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PasskeyList {
+    pub passkeys: Vec<PasskeyFE>
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PasskeyRemoveInputs {
+    pub user_token: String,
+    pub credential_id: String,
+    pub meta: UserEventMeta
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PasskeyLoginOptionsInputs {
+    pub meta: UserEventMeta
+}
+
+/// `PublicKeyCredentialRequestOptions` for `navigator.credentials.get`, binary fields in base64url
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyRequestOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    /// Milliseconds
+    pub timeout: u64,
+    pub user_verification: String,
+    /// Empty, the authenticator offers the voter's discoverable credentials
+    pub allow_credentials: Vec<CredentialDescriptor>
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    /// DER encoded ECDSA signature
+    pub signature: String,
+    #[serde(rename = "userHandle")]
+    pub user_handle: Option<String>
+}
+
+/// Credential returned by `navigator.credentials.get`, binary fields in base64url
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AssertionCredential {
+    pub id: String,
+    pub response: AssertionResponse
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PasskeyLoginInputs {
+    pub credential: AssertionCredential,
     pub meta: UserEventMeta
 }
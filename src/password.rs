@@ -0,0 +1,336 @@
+//! Password hashing, verification and policy shared by every password login and update
+
+use std::{fs::File, io::{BufRead, BufReader}, path::Path, sync::{Arc, atomic::{AtomicU64, AtomicUsize, Ordering}}};
+
+use argon2::{Config, ThreadMode, Variant, Version};
+use rand::{RngCore, rngs::OsRng};
+use sha1::{Digest, Sha1};
+use tokio::sync::Semaphore;
+
+use crate::{error::Error, models::{HashingMetrics, Voter}};
+
+/// Argon2id cost parameters
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HashParams {
+	pub mem_cost_kib: u32,
+	pub time_cost: u32,
+	pub parallelism: u32
+}
+
+/// 19 MiB, 2 passes, 1 lane, as recommended by OWASP
+pub const DEFAULT_HASH_PARAMS: HashParams = HashParams { mem_cost_kib: 19456, time_cost: 2, parallelism: 1 };
+
+/// Server-side secret mixed into every hash, versioned so it can be rotated
+#[derive(Clone)]
+pub struct Pepper {
+	pub version: i32,
+	pub secret: Vec<u8>
+}
+
+/// Bounds of the blocking pool passwords are hashed on
+#[derive(Clone, Copy, Debug)]
+pub struct HashingLimits {
+	/// Hashes computed at the same time
+	pub max_concurrent: usize,
+	/// Hashes waiting for a free slot, more are refused
+	pub max_queued: usize
+}
+
+/// Enough for a few cores, with room for a login burst
+pub const DEFAULT_HASHING_LIMITS: HashingLimits = HashingLimits { max_concurrent: 4, max_queued: 64 };
+
+#[derive(Default)]
+struct HashingCounters {
+	queued: AtomicUsize,
+	running: AtomicUsize,
+	completed: AtomicU64,
+	rejected: AtomicU64
+}
+
+/// Decrements a counter when dropped, so cancelled requests leave the queue too
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+/// Runs hashing on the blocking thread pool, so a login burst can not stall the async workers
+///
+/// At most `max_concurrent` jobs run at once, the slot is held until the job finishes even if the
+/// request was cancelled. Jobs beyond `max_queued` waiting ones are refused right away.
+pub struct HashingPool {
+	limits: HashingLimits,
+	slots: Arc<Semaphore>,
+	counters: Arc<HashingCounters>
+}
+
+impl HashingPool {
+	pub fn new(limits: HashingLimits) -> HashingPool {
+		HashingPool {
+			limits: limits,
+			slots: Arc::new(Semaphore::new(limits.max_concurrent.max(1))),
+			counters: Default::default()
+		}
+	}
+	pub async fn run<T, F>(&self, job: F) -> Result<T, Error> where T: Send + 'static, F: FnOnce() -> Result<T, Error> + Send + 'static {
+		let permit = match self.slots.clone().try_acquire_owned() {
+			Ok(permit) => permit,
+			Err(_) => {
+				if self.counters.queued.fetch_add(1, Ordering::SeqCst) >= self.limits.max_queued {
+					self.counters.queued.fetch_sub(1, Ordering::SeqCst);
+					self.counters.rejected.fetch_add(1, Ordering::SeqCst);
+					return Err(Error::Overloaded("PASSWORD_HASHING_BUSY"));
+				}
+				let _queued = Queued(&self.counters.queued);
+				self.slots.clone().acquire_owned().await.map_err(Error::internal)?
+			}
+		};
+		let counters = self.counters.clone();
+		tokio::task::spawn_blocking(move || {
+			let _permit = permit;
+			counters.running.fetch_add(1, Ordering::SeqCst);
+			let result = job();
+			counters.running.fetch_sub(1, Ordering::SeqCst);
+			counters.completed.fetch_add(1, Ordering::SeqCst);
+			result
+		}).await.map_err(Error::internal)?
+	}
+	pub fn metrics(&self) -> HashingMetrics {
+		HashingMetrics {
+			queued: self.counters.queued.load(Ordering::SeqCst),
+			running: self.counters.running.load(Ordering::SeqCst),
+			completed: self.counters.completed.load(Ordering::SeqCst),
+			rejected: self.counters.rejected.load(Ordering::SeqCst),
+			max_concurrent: self.limits.max_concurrent,
+			max_queued: self.limits.max_queued
+		}
+	}
+}
+
+/// Hashes and verifies passwords with the configured parameters and pepper
+pub struct PasswordHasher {
+	pub params: HashParams,
+	/// Every pepper still in use, the one with the highest version is used for new hashes
+	pub peppers: Vec<Pepper>,
+	pub pool: HashingPool
+}
+
+impl Default for PasswordHasher {
+	fn default() -> Self {
+		PasswordHasher { params: DEFAULT_HASH_PARAMS, peppers: vec![], pool: HashingPool::new(DEFAULT_HASHING_LIMITS) }
+	}
+}
+
+/// Cost parameters and variant of an encoded argon2 hash, `$argon2id$v=19$m=..,t=..,p=..$salt$hash`
+fn encoded_params(encoded: &str) -> Option<(&str, HashParams)> {
+	let mut parts = encoded.split('$').skip(1);
+	let variant = parts.next()?;
+	let mut params = parts.nth(1)?.split(',').map(|p| p.splitn(2, '=').nth(1).and_then(|v| v.parse().ok()));
+	Some((variant, HashParams { mem_cost_kib: params.next()??, time_cost: params.next()??, parallelism: params.next()?? }))
+}
+
+/// Argon2id hash of `password`, blocking
+fn hash(params: &HashParams, pepper: Option<&Pepper>, password: &str) -> Result<String, Error> {
+	let mut salt = [0u8; 16];
+	OsRng.fill_bytes(&mut salt);
+	let config = Config {
+		variant: Variant::Argon2id,
+		version: Version::Version13,
+		mem_cost: params.mem_cost_kib,
+		time_cost: params.time_cost,
+		lanes: params.parallelism,
+		thread_mode: if params.parallelism > 1 { ThreadMode::Parallel } else { ThreadMode::Sequential },
+		secret: pepper.map(|p| p.secret.as_slice()).unwrap_or(&[]),
+		ad: &[],
+		hash_length: 32
+	};
+	Ok(argon2::hash_encoded(password.as_bytes(), &salt, &config)?)
+}
+
+impl PasswordHasher {
+	fn current_pepper(&self) -> Option<&Pepper> {
+		self.peppers.iter().max_by_key(|p| p.version)
+	}
+	fn pepper(&self, version: Option<i32>) -> Result<&[u8], Error> {
+		match version {
+			Some(version) => self.peppers.iter().find(|p| p.version == version).map(|p| p.secret.as_slice())
+				.ok_or_else(|| Error::internal(format!("unknown pepper version {}", version))),
+			None => Ok(&[])
+		}
+	}
+	/// Set a new password, hashed with the current parameters and pepper
+	pub async fn set_password(&self, voter: &mut Voter, password: &str) -> Result<(), Error> {
+		let (params, pepper, password) = (self.params, self.current_pepper().cloned(), password.to_string());
+		let pepper_version = pepper.as_ref().map(|p| p.version);
+		voter.password_hashed = Some(self.pool.run(move || hash(&params, pepper.as_ref(), &password)).await?);
+		voter.salt = None;
+		voter.pepper_version = pepper_version;
+		Ok(())
+	}
+	/// Whether a hash made with other parameters, variant or pepper should be replaced
+	fn is_outdated(&self, voter: &Voter) -> bool {
+		let current = match voter.password_hashed.as_deref().and_then(encoded_params) {
+			Some(("argon2id", params)) => params == self.params,
+			_ => false
+		};
+		!current || voter.pepper_version != self.current_pepper().map(|p| p.version)
+	}
+	/// Check a password against the voter's hash
+	///
+	/// Legacy `bcrypt(password + salt)` hashes and hashes with outdated parameters or pepper are
+	/// replaced, the updated voter is returned and has to be persisted by the caller.
+	pub async fn verify(&self, voter: &Voter, password: &str) -> Result<Option<Voter>, Error> {
+		let password_hashed = voter.password_hashed.clone().ok_or(Error::Validation("LOGIN_METHOD_NOT_SUPPORTED"))?;
+		let (salt, pepper, attempt) = (voter.salt.clone(), self.pepper(voter.pepper_version)?.to_vec(), password.to_string());
+		let matched = self.pool.run(move || match salt {
+			Some(salt) => Ok(bcrypt::verify(format!("{}{}", attempt, salt), &password_hashed).unwrap_or(false)),
+			None => Ok(argon2::verify_encoded_ext(&password_hashed, attempt.as_bytes(), &pepper, &[])?)
+		}).await?;
+		if !matched {
+			return Err(Error::Auth("INCORRECT_PASSWORD"));
+		}
+		if !self.is_outdated(voter) {
+			return Ok(None);
+		}
+		let mut voter = voter.clone();
+		self.set_password(&mut voter, password).await?;
+		Ok(Some(voter))
+	}
+}
+
+/// Rules new passwords have to follow
+pub struct PasswordPolicy {
+	pub min_length: usize,
+	/// Longer passwords only make hashing slower
+	pub max_length: usize,
+	/// Minimum of `estimate_entropy`
+	pub min_entropy_bits: f64,
+	/// Known breached passwords, not checked if unset
+	pub breached: Option<BreachedPasswords>
+}
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+pub const MIN_PASSWORD_ENTROPY_BITS: f64 = 30.0;
+
+impl Default for PasswordPolicy {
+	fn default() -> Self {
+		PasswordPolicy {
+			min_length: MIN_PASSWORD_LENGTH,
+			max_length: MAX_PASSWORD_LENGTH,
+			min_entropy_bits: MIN_PASSWORD_ENTROPY_BITS,
+			breached: None
+		}
+	}
+}
+
+impl PasswordPolicy {
+	/// Every rule `password` breaks, `personal` holds the voter's email, phone and nickname
+	pub fn violations(&self, password: &str, personal: &[Option<&str>]) -> Vec<&'static str> {
+		let mut reasons = vec![];
+		let length = password.chars().count();
+		if length < self.min_length {
+			reasons.push("TOO_SHORT");
+		}
+		if length > self.max_length {
+			reasons.push("TOO_LONG");
+		}
+		if estimate_entropy(password) < self.min_entropy_bits {
+			reasons.push("TOO_PREDICTABLE");
+		}
+		let lowercase = password.to_lowercase();
+		let is_personal = personal.iter().flatten().any(|info| {
+			let info = info.to_lowercase();
+			// the local part of an email counts too
+			lowercase == info || info.split('@').next().map_or(false, |local| lowercase == local)
+		});
+		if is_personal {
+			reasons.push("SAME_AS_PERSONAL_INFO");
+		}
+		if self.breached.as_ref().map_or(false, |b| b.contains(password)) {
+			reasons.push("BREACHED");
+		}
+		reasons
+	}
+	/// Refuse passwords breaking any rule, with every reason
+	pub fn check(&self, password: &str, personal: &[Option<&str>]) -> Result<(), Error> {
+		let reasons = self.violations(password, personal);
+		if reasons.is_empty() { Ok(()) } else { Err(Error::WeakPassword(reasons)) }
+	}
+}
+
+/// Rough entropy in bits, from the character classes used
+///
+/// Characters repeating or continuing a sequence of the previous one (`aaa`, `abc`, `321`) count a quarter.
+pub fn estimate_entropy(password: &str) -> f64 {
+	let chars: Vec<char> = password.chars().collect();
+	let mut pool = 0;
+	if chars.iter().any(|c| c.is_ascii_lowercase()) {
+		pool += 26;
+	}
+	if chars.iter().any(|c| c.is_ascii_uppercase()) {
+		pool += 26;
+	}
+	if chars.iter().any(|c| c.is_ascii_digit()) {
+		pool += 10;
+	}
+	if chars.iter().any(|c| c.is_ascii_punctuation() || *c == ' ') {
+		pool += 33;
+	}
+	if chars.iter().any(|c| !c.is_ascii()) {
+		pool += 100;
+	}
+	if pool == 0 {
+		return 0.0;
+	}
+	let effective_length: f64 = chars.iter().enumerate().map(|(i, c)| {
+		let predictable = i > 0 && (*c as i64 - chars[i - 1] as i64).abs() <= 1;
+		if predictable { 0.25 } else { 1.0 }
+	}).sum();
+	effective_length * (pool as f64).log2()
+}
+
+/// Compact set of breached passwords
+///
+/// Only the first 8 bytes of each SHA-1 are kept, sorted, so millions of entries fit in a few dozen MB.
+pub struct BreachedPasswords {
+	prefixes: Vec<u64>
+}
+
+fn sha1_prefix(digest: &[u8]) -> u64 {
+	let mut prefix = [0u8; 8];
+	prefix.copy_from_slice(&digest[..8]);
+	u64::from_be_bytes(prefix)
+}
+
+impl BreachedPasswords {
+	/// Build from lines holding either a plain password or a hex SHA-1, optionally followed by `:<count>`
+	/// as in the Have I Been Pwned downloads
+	pub fn from_lines<I: IntoIterator<Item = String>>(lines: I) -> BreachedPasswords {
+		let mut prefixes: Vec<u64> = lines.into_iter().filter_map(|line| {
+			let line = line.trim_end_matches(|c| c == '\r' || c == '\n');
+			let hash = line.split(':').next().unwrap_or("");
+			if hash.len() == 40 {
+				if let Ok(digest) = hex::decode(hash) {
+					return Some(sha1_prefix(&digest));
+				}
+			}
+			if line.is_empty() { None } else { Some(sha1_prefix(&Sha1::digest(line.as_bytes()))) }
+		}).collect();
+		prefixes.sort_unstable();
+		prefixes.dedup();
+		BreachedPasswords { prefixes }
+	}
+	pub fn load(path: &Path) -> Result<BreachedPasswords, Error> {
+		let file = File::open(path).map_err(Error::internal)?;
+		Ok(BreachedPasswords::from_lines(BufReader::new(file).lines().filter_map(|line| line.ok())))
+	}
+	pub fn len(&self) -> usize {
+		self.prefixes.len()
+	}
+	pub fn contains(&self, password: &str) -> bool {
+		self.prefixes.binary_search(&sha1_prefix(&Sha1::digest(password.as_bytes()))).is_ok()
+	}
+}
@@ -0,0 +1,113 @@
+//! Forgotten password reset with a code sent to the voter's email or phone
+
+use crate::{code_delivery::Notice, context::AppContext, error::Error, identity::Identity, log, models::{ActivityLogEntry, Voter}, new_login::check_verify_code, send_quota::{SendChannel, consume_send_quota}};
+
+/// A reset code can be requested every 2 minutes per contact
+const RESET_CODE_INTERVAL: usize = 120;
+/// Reset codes expire after 15 minutes
+const RESET_CODE_TTL: usize = 900;
+
+fn code_key(identity: &Identity) -> String {
+	format!("password-reset-{}-{}", identity.kind(), identity.value())
+}
+
+/// Send a reset code to `identity`, an email or phone
+///
+/// Nothing is sent when nobody owns the contact, without telling the requester.
+pub async fn send_reset_code(ctx: &AppContext, identity: Identity, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Error> {
+	let key = code_key(&identity);
+	claim_resend_guard(ctx, &key, RESET_CODE_INTERVAL).await?;
+	if let Some(voter) = send_code_to_owner(ctx, key, identity, RESET_CODE_TTL, ip, additional_fingerprint).await? {
+		println!(" -- [PasswordReset] code sent to voter {}", voter._id.as_ref().unwrap());
+	}
+	Ok(())
+}
+
+/// Allow one code under `key` per `interval`
+///
+/// Claimed before looking the contact up, so known and unknown contacts are answered alike.
+pub(crate) async fn claim_resend_guard(ctx: &AppContext, key: &str, interval: usize) -> Result<(), Error> {
+	if !ctx.kv.set_nx(&format!("{}-guard", key), "guard", interval).await? {
+		return Err(Error::RateLimited("REQUEST_TOO_FREQUENT"));
+	}
+	Ok(())
+}
+
+/// Send a code stored under `key` to the owner of `identity`, an email or phone, after `claim_resend_guard`
+///
+/// Returns the owner, or `None` without sending anything when nobody owns the contact.
+pub(crate) async fn send_code_to_owner(ctx: &AppContext, key: String, identity: Identity, ttl: usize, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<Option<Voter>, Error> {
+	let voter = match ctx.voters.find_by_identity(&identity).await? {
+		Some(voter) if voter.removed != Some(true) => voter,
+		_ => return Ok(None)
+	};
+	let code = ctx.code_generator.numeric_code(6);
+	let entry = match &identity {
+		Identity::Email(email) => {
+			consume_send_quota(ctx, SendChannel::Email, email, ip.clone()).await?;
+			ctx.code_sender.send_email_code(email, &code).await?;
+			ActivityLogEntry::SendEmail {
+				created_at: ctx.now(),
+				target_email: email.clone(),
+				code: code.clone(),
+				requester_ip: ip,
+				requester_additional_fingerprint: additional_fingerprint
+			}
+		},
+		Identity::Phone(phone) => {
+			consume_send_quota(ctx, SendChannel::SMS, phone, ip.clone()).await?;
+			ctx.code_sender.send_sms_code(phone, &code).await?;
+			ActivityLogEntry::SendSMS {
+				created_at: ctx.now(),
+				target_phone: phone.clone(),
+				code: code.clone(),
+				requester_ip: ip,
+				requester_additional_fingerprint: additional_fingerprint
+			}
+		},
+		_ => return Err(Error::Validation("LOGIN_METHOD_NOT_SUPPORTED"))
+	};
+	ctx.kv.set(&key, &code, Some(ttl)).await?;
+	log(ctx, entry).await;
+	Ok(Some(voter))
+}
+
+/// Set a new password after checking the code sent by `send_reset_code`
+///
+/// Unknown contacts fail like a wrong code. Every session is revoked and the voter is notified on its other contact.
+pub async fn reset_password(ctx: &AppContext, identity: Identity, verify_code: &str, new_password: &str, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Error> {
+	// checked first so a refused password does not use up the code, without the voter's personal info
+	// which must not be revealed before the code is
+	ctx.password_policy.check(new_password, &[])?;
+	check_verify_code(ctx, code_key(&identity), identity.value(), verify_code).await?;
+	let mut voter = ctx.voters.find_by_identity(&identity).await?.ok_or(Error::Auth("INCORRECT_VERIFY_CODE"))?;
+	ctx.password_policy.check(new_password, &voter.personal_info())?;
+	voter.check_usable(ctx.now())?;
+	ctx.password_hasher.set_password(&mut voter, new_password).await?;
+	// the code proves the voter owns the contact
+	identity.link_to(&mut voter);
+	ctx.voters.replace(&voter).await?;
+	let uid = voter._id.clone().unwrap();
+	ctx.revoke_sessions(&uid).await?;
+	let notified = match &identity {
+		Identity::Email(_) => match voter.phone.as_ref() {
+			Some(phone) => ctx.code_sender.send_sms_notice(phone, Notice::PasswordReset).await,
+			None => Ok(())
+		},
+		_ => match voter.email.as_ref() {
+			Some(email) => ctx.code_sender.send_email_notice(email, Notice::PasswordReset).await,
+			None => Ok(())
+		}
+	};
+	if let Err(e) = notified {
+		println!(" -- [PasswordReset] failed to notify voter {}: {}", uid, e);
+	}
+	log(ctx, ActivityLogEntry::ResetPassword {
+		created_at: ctx.now(),
+		uid: uid,
+		channel: identity.kind().to_string(),
+		requester_ip: ip,
+		requester_additional_fingerprint: additional_fingerprint
+	}).await;
+	Ok(())
+}
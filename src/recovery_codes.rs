@@ -0,0 +1,108 @@
+//! Single-use recovery codes, for voters who lost their authenticator or phone
+
+use async_trait::async_trait;
+use bson::oid::ObjectId;
+use rand::{Rng, rngs::OsRng};
+use sha2::{Digest, Sha256};
+
+use crate::{account_management::reauthenticate, code_delivery::{Notice, notify_voter}, common::rate_limit, context::AppContext, error::Error, extractors::Requester, identity::{Identity, IdentityProvider}, log, models::{ActivityLogEntry, RecoveryCodes, Voter}};
+
+/// Codes in a set
+pub const RECOVERY_CODE_COUNT: usize = 10;
+/// No 0/O or 1/I, 10 characters make 50 bits
+const ALPHABET: &'static [u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 10;
+
+/// Uppercase without separators, as hashed
+fn normalize(code: &str) -> String {
+	code.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_uppercase()).collect()
+}
+
+fn hash(code: &str) -> String {
+	hex::encode(Sha256::digest(normalize(code).as_bytes()))
+}
+
+/// Whether a code given in place of a TOTP or SMS code is a recovery code
+pub fn is_recovery_code(code: &str) -> bool {
+	let code = normalize(code);
+	code.len() == CODE_LENGTH && !code.chars().all(|c| c.is_ascii_digit())
+}
+
+/// `XXXXX-XXXXX`
+fn generate_code() -> String {
+	let code: String = (0..CODE_LENGTH).map(|_| ALPHABET[OsRng.gen_range(0..ALPHABET.len())] as char).collect();
+	format!("{}-{}", &code[..CODE_LENGTH / 2], &code[CODE_LENGTH / 2..])
+}
+
+/// Replace the voter's recovery codes after re-authentication, the new codes are only shown now
+pub async fn regenerate(ctx: &AppContext, uid: ObjectId, password: Option<String>, verify_code: Option<String>, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<RecoveryCodes, Error> {
+	let mut voter = ctx.voters.find_by_id(&uid).await?.ok_or(Error::NotFound)?;
+	rate_limit(&uid, ctx).await?;
+	reauthenticate(ctx, &mut voter, password, verify_code, &Requester { ip: ip.clone(), additional_fingerprint: additional_fingerprint.clone() }).await?;
+	let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_code()).collect();
+	voter.recovery_codes = codes.iter().map(|c| hash(c)).collect();
+	ctx.voters.replace(&voter).await?;
+	log(ctx, ActivityLogEntry::UpdateMfa {
+		created_at: ctx.now(),
+		uid: uid,
+		method: "recovery_codes".to_string(),
+		enabled: true,
+		requester_ip: ip,
+		requester_additional_fingerprint: additional_fingerprint
+	}).await;
+	Ok(RecoveryCodes { codes: codes })
+}
+
+/// Use up one of the voter's recovery codes, the code is removed from `voter` and the store, the voter notified and the use logged
+pub async fn consume(ctx: &AppContext, voter: &mut Voter, code: &str, requester: &Requester) -> Result<(), Error> {
+	let uid = voter._id.clone().unwrap();
+	rate_limit(&uid, ctx).await?;
+	let hashed = hash(code);
+	// removed in the store, `voter` may be stale and concurrent requests may hold the same code
+	if !ctx.voters.remove_recovery_code(&uid, &hashed).await? {
+		return Err(Error::Auth("INCORRECT_RECOVERY_CODE"));
+	}
+	voter.recovery_codes.retain(|c| *c != hashed);
+	log(ctx, ActivityLogEntry::UseRecoveryCode {
+		created_at: ctx.now(),
+		uid: uid.clone(),
+		remaining: voter.recovery_codes.len() as i32,
+		requester_ip: requester.ip.clone(),
+		requester_additional_fingerprint: requester.additional_fingerprint.clone()
+	}).await;
+	if let Err(e) = notify_voter(ctx.code_sender.as_ref(), voter, Notice::RecoveryCodeUsed).await {
+		println!(" -- [RecoveryCode] failed to notify voter {}: {}", uid, e);
+	}
+	Ok(())
+}
+
+/// Login with an email or phone and a recovery code instead of the code sent there, only for existing voters
+pub struct RecoveryCodeProvider {
+	pub identity: Identity,
+	pub code: String,
+	pub requester: Requester,
+	/// Restoring a removed voter, which is refused otherwise
+	pub restoring: bool
+}
+
+#[async_trait]
+impl IdentityProvider for RecoveryCodeProvider {
+	fn identity(&self) -> Identity {
+		self.identity.clone()
+	}
+	async fn verify(&self, ctx: &AppContext, voter: Option<&Voter>) -> Result<Option<Voter>, Error> {
+		let mut voter = voter.ok_or(Error::NotFound)?.clone();
+		// voters who could not log in anyway must not use up a code
+		if voter.active_ban(ctx.now()).is_some() {
+			return Err(Error::Forbidden("VOTER_SUSPENDED"));
+		}
+		if voter.removed == Some(true) && !self.restoring {
+			return Err(Error::Forbidden("VOTER_REMOVED"));
+		}
+		consume(ctx, &mut voter, &self.code, &self.requester).await?;
+		Ok(Some(voter))
+	}
+	fn allows_signup(&self) -> bool {
+		false
+	}
+}
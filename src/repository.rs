@@ -0,0 +1,364 @@
+use async_trait::async_trait;
+use bson::{Bson, DateTime, Document, doc, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use futures::{StreamExt, TryStreamExt, stream::BoxStream};
+use mongodb::{Collection, Database, IndexModel, error::{ErrorKind, WriteFailure}, options::{FindOneAndUpdateOptions, FindOptions, IndexOptions, ReplaceOptions, ReturnDocument}};
+
+use crate::{error::Error, identity::Identity, models::{ActivityLogEntry, Voter}};
+
+/// Storage of voters
+#[async_trait]
+pub trait VoterRepository: Send + Sync {
+	async fn find_by_id(&self, uid: &ObjectId) -> Result<Option<Voter>, Error>;
+	/// Find the voter owning an identity
+	async fn find_by_identity(&self, identity: &Identity) -> Result<Option<Voter>, Error>;
+	/// Insert a new voter and return its id
+	///
+	/// Fails with a conflict if another voter already owns one of its identities.
+	async fn insert(&self, voter: &Voter) -> Result<ObjectId, Error>;
+	/// Atomically insert `voter` unless someone already owns `identity`
+	///
+	/// Returns the owner of the identity and whether it was just created.
+	async fn insert_or_get(&self, identity: &Identity, voter: &Voter) -> Result<(Voter, bool), Error>;
+	/// Replace the voter with the same `_id`
+	///
+	/// Fails with a conflict if another voter already owns one of its identities.
+	async fn replace(&self, voter: &Voter) -> Result<(), Error>;
+	/// Voters matching `query` at `now`, newest first, and the total number of matches
+	async fn search(&self, query: &VoterQuery, now: DateTime, skip: u64, limit: i64) -> Result<(Vec<Voter>, u64), Error>;
+	/// Removed voters whose grace period ended at `now`
+	async fn find_purgeable(&self, now: DateTime) -> Result<Vec<Voter>, Error>;
+	/// Atomically remove a hashed recovery code of the voter, returns whether it was there
+	async fn remove_recovery_code(&self, uid: &ObjectId, hashed: &str) -> Result<bool, Error>;
+}
+
+/// Filters of the admin voter search, unset ones match everything
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VoterQuery {
+	/// Case-insensitive substring of email, phone or nickname
+	pub text: Option<String>,
+	/// Suspensions count as bans until they expire
+	pub banned: Option<bool>,
+	pub removed: Option<bool>,
+	/// Unix millis
+	pub created_after: Option<i64>,
+	/// Unix millis
+	pub created_before: Option<i64>
+}
+
+impl VoterQuery {
+	pub fn matches(&self, voter: &Voter, now: DateTime) -> bool {
+		let text_matches = self.text.as_ref().map_or(true, |text| {
+			let text = text.to_lowercase();
+			[&voter.email, &voter.phone, &voter.nickname].iter().any(|f| f.as_ref().map_or(false, |f| f.to_lowercase().contains(&text)))
+		});
+		let created_at = voter.created_at.timestamp_millis();
+		text_matches
+			&& self.banned.map_or(true, |banned| voter.active_ban(now).is_some() == banned)
+			&& self.removed.map_or(true, |removed| (voter.removed == Some(true)) == removed)
+			&& self.created_after.map_or(true, |t| created_at >= t)
+			&& self.created_before.map_or(true, |t| created_at < t)
+	}
+	pub fn to_filter(&self, now: DateTime) -> Document {
+		let mut filter = Document::new();
+		if let Some(text) = self.text.as_ref() {
+			let pattern = escape_regex(text);
+			filter.insert("$or", ["email", "phone", "nickname"].iter().map(|f| doc! { *f: { "$regex": pattern.clone(), "$options": "i" } }).collect::<Vec<_>>());
+		}
+		// a missing `expires_at` is permanent, `$not` matches it as well
+		let active_ban = doc! { "ban": { "$type": "object" }, "ban.expires_at": { "$not": { "$lte": now } } };
+		match self.banned {
+			Some(true) => { filter.extend(active_ban); },
+			Some(false) => { filter.insert("$nor", vec![active_ban]); },
+			None => {}
+		}
+		match self.removed {
+			Some(true) => { filter.insert("removed", true); },
+			Some(false) => { filter.insert("removed", doc! { "$ne": true }); },
+			None => {}
+		}
+		let mut created_at = Document::new();
+		if let Some(t) = self.created_after {
+			created_at.insert("$gte", DateTime::from_millis(t));
+		}
+		if let Some(t) = self.created_before {
+			created_at.insert("$lt", DateTime::from_millis(t));
+		}
+		if !created_at.is_empty() {
+			filter.insert("created_at", created_at);
+		}
+		filter
+	}
+}
+
+fn escape_regex(s: &str) -> String {
+	let mut escaped = String::with_capacity(s.len());
+	for c in s.chars() {
+		if "\\.+*?()|[]{}^$".contains(c) {
+			escaped.push('\\');
+		}
+		escaped.push(c);
+	}
+	escaped
+}
+
+/// Storage of activity logs
+#[async_trait]
+pub trait ActivityLogRepository: Send + Sync {
+	async fn insert(&self, entry: &ActivityLogEntry) -> Result<(), Error>;
+	/// Most recent entries about a voter, newest first
+	async fn find_by_uid(&self, uid: &ObjectId, limit: i64) -> Result<Vec<ActivityLogEntry>, Error>;
+	/// Every entry about a voter and codes sent to its contacts, oldest first
+	async fn find_by_voter(&self, uid: &ObjectId, email: Option<&str>, phone: Option<&str>) -> Result<Vec<ActivityLogEntry>, Error>;
+	/// Pseudonymize every entry about a voter and codes sent to its contacts, returns the number of entries changed
+	async fn pseudonymize(&self, uid: &ObjectId, email: Option<&str>, phone: Option<&str>) -> Result<u64, Error>;
+}
+
+/// Untyped documents of any collection, for migrations and imports
+#[async_trait]
+pub trait DocumentStore: Send + Sync {
+	async fn count(&self, collection: &str, filter: Document) -> Result<u64, Error>;
+	/// Every document of `collection` matching `filter`, one at a time
+	async fn find(&self, collection: &str, filter: Document) -> Result<BoxStream<'static, Result<Document, Error>>, Error>;
+	async fn find_one(&self, collection: &str, filter: Document) -> Result<Option<Document>, Error>;
+	/// `$set` fields of the document with `_id` equal to `id`
+	///
+	/// Fails with a conflict if a value is unique and owned by another document.
+	async fn set_fields(&self, collection: &str, id: &Bson, set: Document) -> Result<(), Error>;
+	/// Insert the document or replace the one with the same `_id`
+	async fn upsert(&self, collection: &str, document: Document) -> Result<(), Error>;
+}
+
+/// Voter fields no two voters may share
+pub const UNIQUE_VOTER_FIELDS: [&'static str; 4] = ["email", "phone", "thbwiki_uid", "qq_openid"];
+
+/// Several voters sharing a value of a unique field, found at startup
+#[derive(Debug, Clone)]
+pub struct DuplicateIdentity {
+	pub field: &'static str,
+	pub value: String,
+	pub uids: Vec<ObjectId>
+}
+
+/// Whether a write failed on a unique index
+pub(crate) fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+	match e.kind.as_ref() {
+		ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == 11000,
+		ErrorKind::Command(e) => e.code == 11000,
+		_ => false
+	}
+}
+
+pub struct MongoVoterRepository {
+	coll: Collection<Voter>
+}
+
+impl MongoVoterRepository {
+	pub fn new(db: &Database) -> MongoVoterRepository {
+		MongoVoterRepository { coll: db.collection("voters") }
+	}
+	/// Voters sharing the same value of `field`
+	async fn find_duplicates(&self, field: &'static str) -> Result<Vec<DuplicateIdentity>, Error> {
+		let pipeline = vec![
+			doc! { "$match": { field: { "$type": "string" } } },
+			doc! { "$group": { "_id": format!("${}", field), "count": { "$sum": 1 }, "uids": { "$push": "$_id" } } },
+			doc! { "$match": { "count": { "$gt": 1 } } },
+		];
+		let groups: Vec<Document> = self.coll.aggregate(pipeline, None).await?.try_collect().await?;
+		Ok(groups.into_iter().map(|g| DuplicateIdentity {
+			field: field,
+			value: g.get_str("_id").unwrap_or_default().to_string(),
+			uids: g.get_array("uids").map(|uids| uids.iter().filter_map(|id| id.as_object_id().map(|id| id.clone())).collect()).unwrap_or_default()
+		}).collect())
+	}
+	/// Create a unique partial index on every field in `UNIQUE_VOTER_FIELDS` and on passkey credential ids
+	///
+	/// Fields with existing duplicates are left unindexed and the duplicates are returned so they can be fixed by hand.
+	pub async fn ensure_indexes(&self) -> Result<Vec<DuplicateIdentity>, Error> {
+		let mut duplicates = vec![];
+		for field in UNIQUE_VOTER_FIELDS {
+			let found = self.find_duplicates(field).await?;
+			if !found.is_empty() {
+				duplicates.extend(found);
+				continue;
+			}
+			let options = IndexOptions::builder()
+				.name(format!("unique_{}", field))
+				.unique(true)
+				.partial_filter_expression(doc! { field: { "$type": "string" } })
+				.build();
+			let index = IndexModel::builder().keys(doc! { field: 1 }).options(options).build();
+			self.coll.create_index(index, None).await?;
+		}
+		// unique across voters, passkeys were never stored without it
+		let options = IndexOptions::builder()
+			.name("unique_passkey".to_string())
+			.unique(true)
+			.partial_filter_expression(doc! { "passkeys.credential_id": { "$type": "string" } })
+			.build();
+		let index = IndexModel::builder().keys(doc! { "passkeys.credential_id": 1 }).options(options).build();
+		self.coll.create_index(index, None).await?;
+		Ok(duplicates)
+	}
+}
+
+#[async_trait]
+impl VoterRepository for MongoVoterRepository {
+	async fn find_by_id(&self, uid: &ObjectId) -> Result<Option<Voter>, Error> {
+		Ok(self.coll.find_one(doc! { "_id": uid.clone() }, None).await?)
+	}
+	async fn find_by_identity(&self, identity: &Identity) -> Result<Option<Voter>, Error> {
+		Ok(self.coll.find_one(identity.filter(), None).await?)
+	}
+	async fn insert(&self, voter: &Voter) -> Result<ObjectId, Error> {
+		let iid = match self.coll.insert_one(voter.clone(), None).await {
+			Ok(iid) => iid,
+			Err(e) if is_duplicate_key(&e) => return Err(Error::Conflict("IDENTITY_ALREADY_LINKED")),
+			Err(e) => return Err(e.into())
+		};
+		iid.inserted_id.as_object_id().map(|id| id.clone()).ok_or_else(|| Error::internal("inserted voter has no ObjectId"))
+	}
+	async fn insert_or_get(&self, identity: &Identity, voter: &Voter) -> Result<(Voter, bool), Error> {
+		let uid = ObjectId::new();
+		let mut voter = voter.clone();
+		voter._id = Some(uid.clone());
+		let update = doc! { "$setOnInsert": bson::to_document(&voter).map_err(Error::internal)? };
+		let options = FindOneAndUpdateOptions::builder().upsert(true).return_document(ReturnDocument::After).build();
+		match self.coll.find_one_and_update(identity.filter(), update, options).await {
+			Ok(Some(owner)) => {
+				let created = owner._id.as_ref() == Some(&uid);
+				Ok((owner, created))
+			},
+			Ok(None) => Err(Error::internal("upsert returned no voter")),
+			// lost a race against a concurrent upsert, the winner owns the identity now
+			Err(e) if is_duplicate_key(&e) => {
+				let owner = self.find_by_identity(identity).await?.ok_or_else(|| Error::internal("duplicate key without an owner"))?;
+				Ok((owner, false))
+			},
+			Err(e) => Err(e.into())
+		}
+	}
+	async fn replace(&self, voter: &Voter) -> Result<(), Error> {
+		let uid = voter._id.clone().ok_or_else(|| Error::internal("replacing voter without _id"))?;
+		match self.coll.replace_one(doc! { "_id": uid }, voter.clone(), None).await {
+			Ok(_) => Ok(()),
+			Err(e) if is_duplicate_key(&e) => Err(Error::Conflict("IDENTITY_ALREADY_LINKED")),
+			Err(e) => Err(e.into())
+		}
+	}
+	async fn search(&self, query: &VoterQuery, now: DateTime, skip: u64, limit: i64) -> Result<(Vec<Voter>, u64), Error> {
+		let filter = query.to_filter(now);
+		let total = self.coll.count_documents(filter.clone(), None).await?;
+		let options = FindOptions::builder().sort(doc! { "_id": -1 }).skip(skip).limit(limit).build();
+		Ok((self.coll.find(filter, options).await?.try_collect().await?, total))
+	}
+	async fn find_purgeable(&self, now: DateTime) -> Result<Vec<Voter>, Error> {
+		let filter = doc! { "removed": true, "purge_after": { "$lte": now } };
+		Ok(self.coll.find(filter, None).await?.try_collect().await?)
+	}
+	async fn remove_recovery_code(&self, uid: &ObjectId, hashed: &str) -> Result<bool, Error> {
+		let filter = doc! { "_id": uid.clone(), "recovery_codes": hashed };
+		let result = self.coll.update_one(filter, doc! { "$pull": { "recovery_codes": hashed } }, None).await?;
+		Ok(result.modified_count == 1)
+	}
+}
+
+/// Activity log variants carrying a `uid`, see `ActivityLogEntry::uid`
+const UID_LOG_VARIANTS: [&'static str; 17] = ["VoterCreation", "VoterLogin", "LoginFailed", "LoginLocked", "AccountUnlocked", "UpdateEmail", "UpdatePhone", "UpdateNickname", "UpdatePassword", "ResetPassword", "RemoveVoter", "RestoreVoter", "ExportVoterData", "PurgeVoter", "UpdateMfa", "UseRecoveryCode", "AdminAction"];
+
+fn uid_log_filters(uid: &ObjectId) -> Vec<Document> {
+	UID_LOG_VARIANTS.iter().map(|v| doc! { format!("{}.uid", v): uid.clone() }).collect()
+}
+
+/// Entries about a voter and codes sent to its contacts
+fn voter_log_filter(uid: &ObjectId, email: Option<&str>, phone: Option<&str>) -> Document {
+	let mut filters = uid_log_filters(uid);
+	if let Some(email) = email {
+		filters.push(doc! { "SendEmail.target_email": email });
+	}
+	if let Some(phone) = phone {
+		filters.push(doc! { "SendSMS.target_phone": phone });
+	}
+	doc! { "$or": filters }
+}
+
+pub struct MongoActivityLogRepository {
+	coll: Collection<ActivityLogEntry>
+}
+
+impl MongoActivityLogRepository {
+	pub fn new(db: &Database) -> MongoActivityLogRepository {
+		MongoActivityLogRepository { coll: db.collection("voter_logs") }
+	}
+}
+
+#[async_trait]
+impl ActivityLogRepository for MongoActivityLogRepository {
+	async fn insert(&self, entry: &ActivityLogEntry) -> Result<(), Error> {
+		self.coll.insert_one(entry.clone(), None).await?;
+		Ok(())
+	}
+	async fn find_by_uid(&self, uid: &ObjectId, limit: i64) -> Result<Vec<ActivityLogEntry>, Error> {
+		// entries are stored as `{ "<variant>": { "uid": ... } }`
+		let filter = doc! { "$or": uid_log_filters(uid) };
+		let options = FindOptions::builder().sort(doc! { "_id": -1 }).limit(limit).build();
+		Ok(self.coll.find(filter, options).await?.try_collect().await?)
+	}
+	async fn find_by_voter(&self, uid: &ObjectId, email: Option<&str>, phone: Option<&str>) -> Result<Vec<ActivityLogEntry>, Error> {
+		let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+		Ok(self.coll.find(voter_log_filter(uid, email, phone), options).await?.try_collect().await?)
+	}
+	async fn pseudonymize(&self, uid: &ObjectId, email: Option<&str>, phone: Option<&str>) -> Result<u64, Error> {
+		let coll = self.coll.clone_with_type::<Document>();
+		let documents: Vec<Document> = coll.find(voter_log_filter(uid, email, phone), None).await?.try_collect().await?;
+		let mut changed = 0;
+		for document in documents {
+			let id = document.get("_id").cloned().ok_or_else(|| Error::internal("log entry without _id"))?;
+			let mut entry: ActivityLogEntry = bson::from_document(document).map_err(Error::internal)?;
+			entry.pseudonymize();
+			coll.replace_one(doc! { "_id": id }, bson::to_document(&entry).map_err(Error::internal)?, None).await?;
+			changed += 1;
+		}
+		Ok(changed)
+	}
+}
+
+pub struct MongoDocumentStore {
+	db: Database
+}
+
+impl MongoDocumentStore {
+	pub fn new(db: &Database) -> MongoDocumentStore {
+		MongoDocumentStore { db: db.clone() }
+	}
+	fn coll(&self, collection: &str) -> Collection<Document> {
+		self.db.collection(collection)
+	}
+}
+
+#[async_trait]
+impl DocumentStore for MongoDocumentStore {
+	async fn count(&self, collection: &str, filter: Document) -> Result<u64, Error> {
+		Ok(self.coll(collection).count_documents(filter, None).await?)
+	}
+	async fn find(&self, collection: &str, filter: Document) -> Result<BoxStream<'static, Result<Document, Error>>, Error> {
+		let cursor = self.coll(collection).find(filter, None).await?;
+		Ok(cursor.map_err(Error::from).boxed())
+	}
+	async fn find_one(&self, collection: &str, filter: Document) -> Result<Option<Document>, Error> {
+		Ok(self.coll(collection).find_one(filter, None).await?)
+	}
+	async fn set_fields(&self, collection: &str, id: &Bson, set: Document) -> Result<(), Error> {
+		match self.coll(collection).update_one(doc! { "_id": id.clone() }, doc! { "$set": set }, None).await {
+			Ok(_) => Ok(()),
+			Err(e) if is_duplicate_key(&e) => Err(Error::Conflict("DUPLICATE_KEY")),
+			Err(e) => Err(e.into())
+		}
+	}
+	async fn upsert(&self, collection: &str, document: Document) -> Result<(), Error> {
+		let id = document.get("_id").cloned().ok_or_else(|| Error::internal("upserting document without _id"))?;
+		let options = ReplaceOptions::builder().upsert(true).build();
+		self.coll(collection).replace_one(doc! { "_id": id }, document, options).await?;
+		Ok(())
+	}
+}
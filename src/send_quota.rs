@@ -0,0 +1,157 @@
+use chrono::{DateTime, Timelike, Utc};
+
+use crate::{context::AppContext, error::Error, log, models::ActivityLogEntry};
+
+/// Numbers without a leading `+` are treated as belonging to this country
+pub const DEFAULT_COUNTRY_CODE: &'static str = "86";
+/// Number of national digits forming a number prefix (carrier + region for mainland numbers)
+pub const SMS_PREFIX_LENGTH: usize = 7;
+
+/// Send budgets, every one can be overridden from the environment
+#[derive(Clone, Debug)]
+pub struct SendQuotas {
+	/// Global SMS budget per clock hour
+	pub sms_hourly: u64,
+	/// Global SMS budget per day (UTC)
+	pub sms_daily: u64,
+	/// Global email budget per clock hour
+	pub email_hourly: u64,
+	/// Global email budget per day (UTC)
+	pub email_daily: u64,
+	/// Daily SMS budget per country calling code
+	pub sms_country_daily: Vec<(String, u64)>,
+	/// Daily SMS budget shared by all country codes not listed in `sms_country_daily`
+	pub sms_other_country_daily: u64,
+	/// Hourly SMS budget per number prefix
+	pub sms_prefix_hourly: u64
+}
+
+impl Default for SendQuotas {
+	fn default() -> SendQuotas {
+		SendQuotas {
+			sms_hourly: 3000,
+			sms_daily: 30000,
+			email_hourly: 5000,
+			email_daily: 50000,
+			sms_country_daily: vec![("86".into(), 30000), ("852".into(), 300), ("853".into(), 100), ("886".into(), 300)],
+			sms_other_country_daily: 50,
+			sms_prefix_hourly: 30
+		}
+	}
+}
+
+/// Redis pub/sub channel admin alerts are published to
+pub const ADMIN_ALERT_CHANNEL: &'static str = "thvote-admin-alerts";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SendChannel {
+	SMS,
+	Email
+}
+
+impl SendChannel {
+	pub fn name(&self) -> &'static str {
+		match self {
+			SendChannel::SMS => "sms",
+			SendChannel::Email => "email",
+		}
+	}
+}
+
+struct Budget {
+	/// Human readable scope, e.g. `global-hourly` or `prefix-1381234`
+	scope: String,
+	key: String,
+	limit: u64,
+	/// Seconds until the current window ends
+	remaining: u64
+}
+
+/// Split a phone number into a country calling code with its daily budget and the national number
+fn split_phone<'a>(quotas: &'a SendQuotas, phone: &str) -> (Option<&'a (String, u64)>, String) {
+	let digits: String = phone.chars().filter(|c| c.is_ascii_digit()).collect();
+	if !phone.trim_start().starts_with('+') {
+		return (quotas.sms_country_daily.iter().find(|(cc, _)| cc == DEFAULT_COUNTRY_CODE), digits);
+	}
+	// longest listed country code wins
+	let mut matched: Option<&'a (String, u64)> = None;
+	for entry in quotas.sms_country_daily.iter() {
+		if digits.starts_with(&entry.0) && matched.map_or(true, |m| m.0.len() < entry.0.len()) {
+			matched = Some(entry);
+		}
+	}
+	match matched {
+		Some(entry) => (Some(entry), digits[entry.0.len()..].to_string()),
+		None => (None, digits)
+	}
+}
+
+fn budgets_for(quotas: &SendQuotas, channel: SendChannel, target: &str, now: DateTime<Utc>) -> Vec<Budget> {
+	let hour = now.format("%Y%m%d%H").to_string();
+	let day = now.format("%Y%m%d").to_string();
+	let hour_remaining = 3600 - (now.minute() * 60 + now.second()) as u64;
+	let day_remaining = 86400 - now.num_seconds_from_midnight() as u64;
+	let prefix = format!("send-quota-{}", channel.name());
+	let (hourly, daily) = match channel {
+		SendChannel::SMS => (quotas.sms_hourly, quotas.sms_daily),
+		SendChannel::Email => (quotas.email_hourly, quotas.email_daily),
+	};
+	let mut budgets = vec![
+		Budget { scope: "global-hourly".into(), key: format!("{}-global-{}", prefix, hour), limit: hourly, remaining: hour_remaining },
+		Budget { scope: "global-daily".into(), key: format!("{}-global-{}", prefix, day), limit: daily, remaining: day_remaining },
+	];
+	if channel == SendChannel::SMS {
+		let (cc, national) = split_phone(quotas, target);
+		let (cc_name, cc_limit) = match cc {
+			Some((cc, limit)) => (cc.as_str(), *limit),
+			None => ("other", quotas.sms_other_country_daily),
+		};
+		budgets.push(Budget { scope: format!("country-{}", cc_name), key: format!("{}-country-{}-{}", prefix, cc_name, day), limit: cc_limit, remaining: day_remaining });
+		let number_prefix: String = national.chars().take(SMS_PREFIX_LENGTH).collect();
+		budgets.push(Budget { scope: format!("prefix-{}-{}", cc_name, number_prefix), key: format!("{}-prefix-{}-{}-{}", prefix, cc_name, number_prefix, hour), limit: quotas.sms_prefix_hourly, remaining: hour_remaining });
+	}
+	budgets
+}
+
+/// Consume one unit of every budget covering `target`, fails with `SEND_QUOTA_EXCEEDED` if any of them is exhausted
+pub async fn consume_send_quota(ctx: &AppContext, channel: SendChannel, target: &str, ip: Option<String>) -> Result<(), Error> {
+	// sending is paused after a global budget runs out, operators may also set this key by hand
+	let paused_key = format!("send-paused-{}", channel.name());
+	if ctx.kv.get(&paused_key).await?.is_some() {
+		return Err(Error::RateLimited("SEND_QUOTA_EXCEEDED"));
+	}
+	let budgets = budgets_for(&ctx.send_quotas, channel, target, ctx.clock.now());
+	// keep counters around for one extra window so they can be inspected afterwards
+	let counters: Vec<(String, u64, usize)> = budgets.iter().map(|b| (b.key.clone(), b.limit, (b.remaining * 2) as usize)).collect();
+	let exhausted = match ctx.kv.consume_budgets(&counters).await? {
+		Some(i) => &budgets[i],
+		None => return Ok(())
+	};
+	if exhausted.scope.starts_with("global") {
+		ctx.kv.set(&paused_key, &exhausted.scope, Some(exhausted.remaining as usize)).await?;
+	}
+	// alert only once per exhausted window
+	if ctx.kv.set_nx(&format!("{}-alerted", exhausted.key), "alerted", exhausted.remaining as usize).await? {
+		emit_quota_alert(ctx, channel, exhausted, ip).await;
+	}
+	Err(Error::RateLimited("SEND_QUOTA_EXCEEDED"))
+}
+
+async fn emit_quota_alert(ctx: &AppContext, channel: SendChannel, budget: &Budget, ip: Option<String>) {
+	let alert = serde_json::json!({
+		"kind": "SEND_QUOTA_EXHAUSTED",
+		"channel": channel.name(),
+		"scope": budget.scope,
+		"limit": budget.limit,
+		"resumes_in_seconds": budget.remaining
+	});
+	// best effort, the activity log entry is the record of the alert
+	let _ = ctx.kv.publish(ADMIN_ALERT_CHANNEL, &alert.to_string()).await;
+	log(ctx, ActivityLogEntry::SendQuotaExhausted {
+		created_at: ctx.now(),
+		channel: channel.name().to_string(),
+		scope: budget.scope.clone(),
+		limit: budget.limit as i64,
+		requester_ip: ip
+	}).await;
+}
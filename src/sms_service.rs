@@ -6,3 +6,9 @@ pub struct SMSRequest {
     pub code: String,
     pub mobile: String
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct SMSNoticeRequest {
+    pub notice: String,
+    pub mobile: String
+}
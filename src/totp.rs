@@ -0,0 +1,166 @@
+//! TOTP second factor (RFC 6238), enrollment and the encrypted storage of secrets
+
+use aes_gcm::{Aes256Gcm, Nonce, aead::{Aead, KeyInit, Payload}};
+use bson::oid::ObjectId;
+use hmac::{Hmac, Mac};
+use rand::{RngCore, rngs::OsRng};
+use sha1::Sha1;
+
+use crate::{account_management::reauthenticate, common::rate_limit, context::AppContext, error::Error, extractors::Requester, log, models::{ActivityLogEntry, TotpEnrollment, Voter}};
+
+/// Seconds a code is valid for
+pub const TOTP_STEP: i64 = 30;
+pub const TOTP_DIGITS: u32 = 6;
+/// Codes of this many steps before and after now are accepted, for clock drift
+const TOTP_SKEW_STEPS: i64 = 1;
+/// Issuer shown by authenticator apps
+const TOTP_ISSUER: &'static str = "THVote";
+/// An enrollment has to be confirmed within 10 minutes
+const ENROLLMENT_TTL: usize = 600;
+
+/// Encrypts secrets stored in the database with AES-256-GCM
+///
+/// Every value is bound to the voter it belongs to, so it can not be copied to another voter.
+pub struct SecretCipher {
+	cipher: Aes256Gcm
+}
+
+impl SecretCipher {
+	pub fn new(key: &[u8; 32]) -> SecretCipher {
+		SecretCipher { cipher: Aes256Gcm::new_from_slice(key).expect("AES-256 takes 32 byte keys") }
+	}
+	/// Hex encoded nonce followed by the ciphertext
+	pub fn encrypt(&self, uid: &ObjectId, plaintext: &[u8]) -> Result<String, Error> {
+		let mut nonce = [0u8; 12];
+		OsRng.fill_bytes(&mut nonce);
+		let ciphertext = self.cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &uid.bytes() }).map_err(|_| Error::internal("secret encryption failed"))?;
+		Ok(format!("{}{}", hex::encode(nonce), hex::encode(ciphertext)))
+	}
+	pub fn decrypt(&self, uid: &ObjectId, encrypted: &str) -> Result<Vec<u8>, Error> {
+		let bytes = hex::decode(encrypted).map_err(Error::internal)?;
+		if bytes.len() < 12 {
+			return Err(Error::internal("encrypted secret too short"));
+		}
+		let (nonce, ciphertext) = bytes.split_at(12);
+		self.cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &uid.bytes() }).map_err(|_| Error::internal("secret decryption failed"))
+	}
+}
+
+/// Code for a secret at a unix time
+pub fn code_at(secret: &[u8], unix_time: i64) -> String {
+	hotp(secret, (unix_time / TOTP_STEP) as u64)
+}
+
+fn hotp(secret: &[u8], counter: u64) -> String {
+	let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+	mac.update(&counter.to_be_bytes());
+	let digest = mac.finalize().into_bytes();
+	let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+	let binary = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+	format!("{:0width$}", binary % 10u32.pow(TOTP_DIGITS), width = TOTP_DIGITS as usize)
+}
+
+fn encode_secret(secret: &[u8]) -> String {
+	base32::encode(base32::Alphabet::RFC4648 { padding: false }, secret)
+}
+
+/// Percent-encode everything but unreserved characters
+fn uri_encode(s: &str) -> String {
+	s.bytes().map(|b| match b {
+		b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+		_ => format!("%{:02X}", b)
+	}).collect()
+}
+
+/// `otpauth://` URI authenticator apps scan as a QR code
+pub fn otpauth_uri(secret: &[u8], account: &str) -> String {
+	format!(
+		"otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+		issuer = TOTP_ISSUER,
+		account = uri_encode(account),
+		secret = encode_secret(secret),
+		digits = TOTP_DIGITS,
+		period = TOTP_STEP
+	)
+}
+
+/// Step of the code matching `code` around `unix_time`, if any
+fn matching_step(secret: &[u8], code: &str, unix_time: i64) -> Option<i64> {
+	let now = unix_time / TOTP_STEP;
+	(now - TOTP_SKEW_STEPS..=now + TOTP_SKEW_STEPS).find(|step| hotp(secret, *step as u64) == code)
+}
+
+/// Check a code against the voter's secret, each code is accepted once
+pub async fn check_code(ctx: &AppContext, voter: &Voter, code: &str) -> Result<(), Error> {
+	let uid = voter._id.as_ref().unwrap();
+	let encrypted = voter.totp_secret.as_ref().ok_or(Error::Validation("TOTP_NOT_ENABLED"))?;
+	rate_limit(uid, ctx).await?;
+	let secret = ctx.secret_cipher.decrypt(uid, encrypted)?;
+	let step = matching_step(&secret, code.trim(), ctx.clock.now().timestamp()).ok_or(Error::Auth("INCORRECT_TOTP_CODE"))?;
+	// a code seen once could have been observed by someone else
+	let used_for = ((2 * TOTP_SKEW_STEPS + 1) * TOTP_STEP) as usize;
+	if !ctx.kv.set_nx(&format!("totp-used-{}-{}", uid, step), "used", used_for).await? {
+		return Err(Error::Auth("INCORRECT_TOTP_CODE"));
+	}
+	Ok(())
+}
+
+/// Start enrolling a voter, the secret is kept aside until confirmed with a code
+pub async fn start_enrollment(ctx: &AppContext, uid: ObjectId) -> Result<TotpEnrollment, Error> {
+	let voter = ctx.voters.find_by_id(&uid).await?.ok_or(Error::NotFound)?;
+	if voter.totp_secret.is_some() {
+		return Err(Error::Conflict("TOTP_ALREADY_ENABLED"));
+	}
+	let mut secret = [0u8; 20];
+	OsRng.fill_bytes(&mut secret);
+	ctx.kv.set(&format!("totp-enrollment-{}", uid), &ctx.secret_cipher.encrypt(&uid, &secret)?, Some(ENROLLMENT_TTL)).await?;
+	let account = voter.email.clone().or(voter.phone.clone()).unwrap_or(uid.to_hex());
+	Ok(TotpEnrollment {
+		secret: encode_secret(&secret),
+		otpauth_uri: otpauth_uri(&secret, &account)
+	})
+}
+
+/// Enable TOTP once the voter proves its authenticator produces the right codes
+pub async fn confirm_enrollment(ctx: &AppContext, uid: ObjectId, code: &str, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Error> {
+	let mut voter = ctx.voters.find_by_id(&uid).await?.ok_or(Error::NotFound)?;
+	if voter.totp_secret.is_some() {
+		return Err(Error::Conflict("TOTP_ALREADY_ENABLED"));
+	}
+	let key = format!("totp-enrollment-{}", uid);
+	let encrypted = ctx.kv.get(&key).await?.ok_or(Error::Validation("TOTP_ENROLLMENT_EXPIRED"))?;
+	voter.totp_secret = Some(encrypted);
+	check_code(ctx, &voter, code).await?;
+	ctx.voters.replace(&voter).await?;
+	ctx.kv.del(&key).await?;
+	log(ctx, ActivityLogEntry::UpdateMfa {
+		created_at: ctx.now(),
+		uid: uid,
+		method: "totp".to_string(),
+		enabled: true,
+		requester_ip: ip,
+		requester_additional_fingerprint: additional_fingerprint
+	}).await;
+	Ok(())
+}
+
+/// Turn TOTP off after re-authentication
+pub async fn disable(ctx: &AppContext, uid: ObjectId, password: Option<String>, verify_code: Option<String>, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Error> {
+	let mut voter = ctx.voters.find_by_id(&uid).await?.ok_or(Error::NotFound)?;
+	if voter.totp_secret.is_none() {
+		return Err(Error::Validation("TOTP_NOT_ENABLED"));
+	}
+	rate_limit(&uid, ctx).await?;
+	reauthenticate(ctx, &mut voter, password, verify_code, &Requester { ip: ip.clone(), additional_fingerprint: additional_fingerprint.clone() }).await?;
+	voter.totp_secret = None;
+	ctx.voters.replace(&voter).await?;
+	log(ctx, ActivityLogEntry::UpdateMfa {
+		created_at: ctx.now(),
+		uid: uid,
+		method: "totp".to_string(),
+		enabled: false,
+		requester_ip: ip,
+		requester_additional_fingerprint: additional_fingerprint
+	}).await;
+	Ok(())
+}
@@ -0,0 +1,338 @@
+//! WebAuthn passkeys (ES256 only), registration by logged in voters and passwordless login
+
+use async_trait::async_trait;
+use bson::oid::ObjectId;
+use p256::ecdsa::{Signature, VerifyingKey, signature::Verifier};
+use rand::{RngCore, rngs::OsRng};
+use serde::Deserialize;
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{account_management::reauthenticate, comm, common::rate_limit, context::AppContext, error::Error, extractors::Requester, identity::{Identity, IdentityProvider}, log, models::{ActivityLogEntry, AssertionCredential, AuthenticatorSelection, CredentialDescriptor, CredentialParameters, Passkey, PasskeyCreationOptions, PasskeyFE, PasskeyList, PasskeyRequestOptions, PasskeyUser, RegistrationCredential, RelyingParty, Voter}, recovery_codes, totp};
+
+/// A ceremony has to be finished within 5 minutes
+const CEREMONY_TTL: usize = 300;
+/// COSE algorithm id of ECDSA with P-256 and SHA-256
+const COSE_ES256: i32 = -7;
+const MAX_PASSKEYS: usize = 10;
+const RP_NAME: &'static str = "THVote";
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+fn malformed() -> Error {
+	Error::Validation("MALFORMED_PASSKEY_RESPONSE")
+}
+
+fn encode(bytes: &[u8]) -> String {
+	base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(s: &str) -> Result<Vec<u8>, Error> {
+	base64::decode_config(s.trim_end_matches('='), base64::URL_SAFE_NO_PAD).map_err(|_| malformed())
+}
+
+fn new_challenge() -> String {
+	let mut challenge = [0u8; 32];
+	OsRng.fill_bytes(&mut challenge);
+	encode(&challenge)
+}
+
+fn registration_key(uid: &ObjectId) -> String {
+	format!("webauthn-registration-{}", uid)
+}
+
+fn login_key(challenge: &str) -> String {
+	format!("webauthn-login-{}", challenge)
+}
+
+/// Take the challenge stored under `key` for one answer, right or wrong, even among concurrent requests
+async fn claim_challenge(ctx: &AppContext, key: &str, challenge: &str) -> Result<bool, Error> {
+	if !ctx.kv.set_nx(&format!("webauthn-claimed-{}", challenge), "claimed", CEREMONY_TTL).await? {
+		return Ok(false);
+	}
+	ctx.kv.del(key).await?;
+	Ok(true)
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+	#[serde(rename = "type")]
+	kind: String,
+	challenge: String,
+	origin: String
+}
+
+/// Parse `clientDataJSON`, checking it is for a `kind` ceremony on one of our origins
+fn parse_client_data(json: &[u8], kind: &str) -> Result<ClientData, Error> {
+	let client_data: ClientData = serde_json::from_slice(json).map_err(|_| malformed())?;
+	if client_data.kind != kind {
+		return Err(malformed());
+	}
+	if !comm::WEBAUTHN_ORIGINS.contains(&client_data.origin.as_str()) {
+		return Err(Error::Auth("PASSKEY_WRONG_ORIGIN"));
+	}
+	Ok(client_data)
+}
+
+struct AuthenticatorData {
+	flags: u8,
+	sign_count: u32,
+	/// Credential id and SEC1 public key, only set during registration
+	attested: Option<(Vec<u8>, Vec<u8>)>
+}
+
+/// Parse authenticator data, checking it is for our relying party and the user was present
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, Error> {
+	if data.len() < 37 {
+		return Err(malformed());
+	}
+	if data[..32] != Sha256::digest(comm::WEBAUTHN_RP_ID.as_bytes())[..] {
+		return Err(Error::Auth("PASSKEY_WRONG_RELYING_PARTY"));
+	}
+	let flags = data[32];
+	if flags & FLAG_USER_PRESENT == 0 {
+		return Err(Error::Auth("PASSKEY_USER_NOT_PRESENT"));
+	}
+	let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+	let attested = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+		// AAGUID, credential id length, credential id, COSE key
+		let rest = &data[37..];
+		if rest.len() < 18 {
+			return Err(malformed());
+		}
+		let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+		if rest.len() < 18 + id_len {
+			return Err(malformed());
+		}
+		Some((rest[18..18 + id_len].to_vec(), parse_cose_key(&rest[18 + id_len..])?))
+	} else {
+		None
+	};
+	Ok(AuthenticatorData { flags: flags, sign_count: sign_count, attested: attested })
+}
+
+/// SEC1 point of an ES256 COSE key, extensions may follow the key
+fn parse_cose_key(cose: &[u8]) -> Result<Vec<u8>, Error> {
+	let key = serde_cbor::Deserializer::from_slice(cose).into_iter::<Value>().next().ok_or_else(malformed)?.map_err(|_| malformed())?;
+	let map = match key {
+		Value::Map(map) => map,
+		_ => return Err(malformed())
+	};
+	let get = |label: i128| map.get(&Value::Integer(label));
+	// kty EC2, alg ES256, crv P-256, x, y
+	match (get(1), get(3), get(-1), get(-2), get(-3)) {
+		(Some(Value::Integer(2)), Some(Value::Integer(alg)), Some(Value::Integer(1)), Some(Value::Bytes(x)), Some(Value::Bytes(y))) if *alg == COSE_ES256 as i128 && x.len() == 32 && y.len() == 32 => {
+			let mut point = vec![0x04];
+			point.extend_from_slice(x);
+			point.extend_from_slice(y);
+			VerifyingKey::from_sec1_bytes(&point).map_err(|_| malformed())?;
+			Ok(point)
+		},
+		_ => Err(Error::Validation("PASSKEY_ALGORITHM_NOT_SUPPORTED"))
+	}
+}
+
+/// Authenticator data of an attestation object, the attestation statement itself is not checked
+fn attested_authenticator_data(attestation_object: &[u8]) -> Result<Vec<u8>, Error> {
+	match serde_cbor::from_slice::<Value>(attestation_object).map_err(|_| malformed())? {
+		Value::Map(map) => match map.get(&Value::Text("authData".to_string())) {
+			Some(Value::Bytes(auth_data)) => Ok(auth_data.clone()),
+			_ => Err(malformed())
+		},
+		_ => Err(malformed())
+	}
+}
+
+/// Options for registering a new passkey after re-authentication, the challenge is kept until it is answered
+///
+/// Passkey logins skip the second factor, so voters with TOTP also give a TOTP or recovery code.
+pub async fn start_registration(ctx: &AppContext, uid: ObjectId, password: Option<String>, verify_code: Option<String>, mfa_code: Option<String>, requester: &Requester) -> Result<PasskeyCreationOptions, Error> {
+	let mut voter = ctx.voters.find_by_id(&uid).await?.ok_or(Error::NotFound)?;
+	voter.check_usable(ctx.now())?;
+	if voter.passkeys.len() >= MAX_PASSKEYS {
+		return Err(Error::Validation("TOO_MANY_PASSKEYS"));
+	}
+	rate_limit(&uid, ctx).await?;
+	reauthenticate(ctx, &mut voter, password, verify_code, requester).await?;
+	if voter.totp_secret.is_some() {
+		let mfa_code = mfa_code.ok_or(Error::Validation("MFA_REQUIRED"))?;
+		if recovery_codes::is_recovery_code(&mfa_code) {
+			recovery_codes::consume(ctx, &mut voter, &mfa_code, requester).await?;
+		} else {
+			totp::check_code(ctx, &voter, &mfa_code).await?;
+		}
+	}
+	let challenge = new_challenge();
+	ctx.kv.set(&registration_key(&uid), &challenge, Some(CEREMONY_TTL)).await?;
+	let name = voter.email.clone().or(voter.phone.clone()).unwrap_or(uid.to_hex());
+	Ok(PasskeyCreationOptions {
+		challenge: challenge,
+		rp: RelyingParty { id: comm::WEBAUTHN_RP_ID.to_string(), name: RP_NAME.to_string() },
+		user: PasskeyUser { id: encode(&uid.bytes()), display_name: voter.nickname.clone().unwrap_or(name.clone()), name: name },
+		pub_key_cred_params: vec![CredentialParameters { kind: "public-key".to_string(), alg: COSE_ES256 }],
+		timeout: CEREMONY_TTL as u64 * 1000,
+		attestation: "none".to_string(),
+		exclude_credentials: voter.passkeys.iter().map(|p| CredentialDescriptor { kind: "public-key".to_string(), id: p.credential_id.clone() }).collect(),
+		authenticator_selection: AuthenticatorSelection { resident_key: "required".to_string(), user_verification: "required".to_string() }
+	})
+}
+
+/// Add the passkey created for the challenge from `start_registration`
+pub async fn finish_registration(ctx: &AppContext, uid: ObjectId, credential: &RegistrationCredential, name: Option<String>, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Error> {
+	let mut voter = ctx.voters.find_by_id(&uid).await?.ok_or(Error::NotFound)?;
+	voter.check_usable(ctx.now())?;
+	let key = registration_key(&uid);
+	let challenge = ctx.kv.get(&key).await?.ok_or(Error::Validation("PASSKEY_CEREMONY_EXPIRED"))?;
+	if !claim_challenge(ctx, &key, &challenge).await? {
+		return Err(Error::Validation("PASSKEY_CEREMONY_EXPIRED"));
+	}
+	let client_data = parse_client_data(&decode(&credential.response.client_data_json)?, "webauthn.create")?;
+	if client_data.challenge != challenge {
+		return Err(Error::Auth("INCORRECT_PASSKEY_CHALLENGE"));
+	}
+	let auth_data = parse_authenticator_data(&attested_authenticator_data(&decode(&credential.response.attestation_object)?)?)?;
+	// logins with the passkey count as two factors, which needs the user verified from the start
+	if auth_data.flags & FLAG_USER_VERIFIED == 0 {
+		return Err(Error::Auth("PASSKEY_USER_NOT_VERIFIED"));
+	}
+	let (credential_id, public_key) = auth_data.attested.ok_or_else(malformed)?;
+	let credential_id = encode(&credential_id);
+	if credential_id != credential.id.trim_end_matches('=') {
+		return Err(malformed());
+	}
+	if ctx.voters.find_by_identity(&Identity::Passkey(credential_id.clone())).await?.is_some() {
+		return Err(Error::Conflict("PASSKEY_ALREADY_REGISTERED"));
+	}
+	if voter.passkeys.len() >= MAX_PASSKEYS {
+		return Err(Error::Validation("TOO_MANY_PASSKEYS"));
+	}
+	voter.passkeys.push(Passkey {
+		credential_id: credential_id,
+		public_key: hex::encode(public_key),
+		sign_count: auth_data.sign_count,
+		name: name,
+		created_at: ctx.now(),
+		last_used_at: None
+	});
+	ctx.voters.replace(&voter).await?;
+	log(ctx, ActivityLogEntry::UpdateMfa {
+		created_at: ctx.now(),
+		uid: uid,
+		method: "passkey".to_string(),
+		enabled: true,
+		requester_ip: ip,
+		requester_additional_fingerprint: additional_fingerprint
+	}).await;
+	Ok(())
+}
+
+/// Passkeys of a voter, without their keys
+pub async fn list(ctx: &AppContext, uid: ObjectId) -> Result<PasskeyList, Error> {
+	let voter = ctx.voters.find_by_id(&uid).await?.ok_or(Error::NotFound)?;
+	Ok(PasskeyList {
+		passkeys: voter.passkeys.iter().map(|p| PasskeyFE {
+			credential_id: p.credential_id.clone(),
+			name: p.name.clone(),
+			created_at: p.created_at.timestamp_millis(),
+			last_used_at: p.last_used_at.map(|t| t.timestamp_millis())
+		}).collect()
+	})
+}
+
+pub async fn remove(ctx: &AppContext, uid: ObjectId, credential_id: &str, ip: Option<String>, additional_fingerprint: Option<String>) -> Result<(), Error> {
+	let mut voter = ctx.voters.find_by_id(&uid).await?.ok_or(Error::NotFound)?;
+	let index = voter.passkeys.iter().position(|p| p.credential_id == credential_id).ok_or(Error::NotFound)?;
+	voter.passkeys.remove(index);
+	ctx.voters.replace(&voter).await?;
+	log(ctx, ActivityLogEntry::UpdateMfa {
+		created_at: ctx.now(),
+		uid: uid,
+		method: "passkey".to_string(),
+		enabled: false,
+		requester_ip: ip,
+		requester_additional_fingerprint: additional_fingerprint
+	}).await;
+	Ok(())
+}
+
+/// Options for logging in with any passkey, the challenge is kept until it is answered
+pub async fn start_login(ctx: &AppContext, ip: Option<String>) -> Result<PasskeyRequestOptions, Error> {
+	if let Some(ip) = ip.as_ref() {
+		rate_limit(ip, ctx).await?;
+	}
+	let challenge = new_challenge();
+	ctx.kv.set(&login_key(&challenge), "pending", Some(CEREMONY_TTL)).await?;
+	Ok(PasskeyRequestOptions {
+		challenge: challenge,
+		rp_id: comm::WEBAUTHN_RP_ID.to_string(),
+		timeout: CEREMONY_TTL as u64 * 1000,
+		user_verification: "required".to_string(),
+		allow_credentials: vec![]
+	})
+}
+
+/// Login with an assertion answering a challenge from `start_login`, only for existing voters
+///
+/// The authenticator verified the user, so the passkey counts as both factors.
+pub struct PasskeyProvider {
+	pub credential: AssertionCredential,
+	pub requester: Requester
+}
+
+impl PasskeyProvider {
+	fn credential_id(&self) -> String {
+		self.credential.id.trim_end_matches('=').to_string()
+	}
+}
+
+#[async_trait]
+impl IdentityProvider for PasskeyProvider {
+	fn identity(&self) -> Identity {
+		Identity::Passkey(self.credential_id())
+	}
+	async fn verify(&self, ctx: &AppContext, voter: Option<&Voter>) -> Result<Option<Voter>, Error> {
+		let mut voter = voter.ok_or(Error::Auth("UNKNOWN_PASSKEY"))?.clone();
+		let uid = voter._id.clone().unwrap();
+		rate_limit(&uid, ctx).await?;
+		let response = &self.credential.response;
+		if let Some(user_handle) = response.user_handle.as_ref() {
+			if decode(user_handle)? != uid.bytes() {
+				return Err(Error::Auth("UNKNOWN_PASSKEY"));
+			}
+		}
+		let client_data_json = decode(&response.client_data_json)?;
+		let client_data = parse_client_data(&client_data_json, "webauthn.get")?;
+		let key = login_key(&client_data.challenge);
+		if ctx.kv.get(&key).await?.is_none() || !claim_challenge(ctx, &key, &client_data.challenge).await? {
+			return Err(Error::Auth("INCORRECT_PASSKEY_CHALLENGE"));
+		}
+		let auth_data_bytes = decode(&response.authenticator_data)?;
+		let auth_data = parse_authenticator_data(&auth_data_bytes)?;
+		if auth_data.flags & FLAG_USER_VERIFIED == 0 {
+			return Err(Error::Auth("PASSKEY_USER_NOT_VERIFIED"));
+		}
+		let credential_id = self.credential_id();
+		let passkey = voter.passkeys.iter_mut().find(|p| p.credential_id == credential_id).ok_or(Error::Auth("UNKNOWN_PASSKEY"))?;
+		let public_key = VerifyingKey::from_sec1_bytes(&hex::decode(&passkey.public_key).map_err(Error::internal)?).map_err(Error::internal)?;
+		let signature = Signature::from_der(&decode(&response.signature)?).map_err(|_| Error::Auth("INCORRECT_PASSKEY_SIGNATURE"))?;
+		let mut signed = auth_data_bytes.clone();
+		signed.extend_from_slice(&Sha256::digest(&client_data_json));
+		public_key.verify(&signed, &signature).map_err(|_| Error::Auth("INCORRECT_PASSKEY_SIGNATURE"))?;
+		// a counter not moving forward means the key was cloned, authenticators without one always send 0
+		if (auth_data.sign_count != 0 || passkey.sign_count != 0) && auth_data.sign_count <= passkey.sign_count {
+			println!(" -- [Passkey] sign count of {} went back on voter {}", credential_id, uid);
+			return Err(Error::Auth("PASSKEY_CLONED"));
+		}
+		passkey.sign_count = auth_data.sign_count;
+		passkey.last_used_at = Some(ctx.now());
+		Ok(Some(voter))
+	}
+	fn allows_signup(&self) -> bool {
+		false
+	}
+	fn is_multi_factor(&self) -> bool {
+		true
+	}
+}
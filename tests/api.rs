@@ -0,0 +1,1396 @@
+//! End-to-end tests of every route, running the real `App` against in-memory stores
+
+use std::{collections::{BTreeMap, HashSet}, sync::Arc};
+
+use actix_web::{App, cookie::Cookie, http::StatusCode, test, web::Data};
+use bson::{Bson, DateTime, doc, oid::ObjectId};
+use jwt_simple::prelude::*;
+use serde_json::{json, Value};
+use async_trait::async_trait;
+use p256::ecdsa::{Signature as EcdsaSignature, SigningKey, signature::Signer};
+use serde_cbor::Value as Cbor;
+use sha2::{Digest, Sha256};
+use thvote_user_manager::{admin, client_ip::{client_ip, parse_trusted_proxies}, comm, clock::{Clock, FakeClock}, code_delivery::{CapturingCodeSender, Notice, SentCode}, code_generator::SequentialCodeGenerator, context::{AppContext, LoginSession}, error::Error, extractors::Requester, identity::{Identity, IdentityProvider}, kv_store::KeyValueStore, legacy_import::{LegacyImporter, LegacyVoter}, login::complete_login, migrations, password::{BreachedPasswords, HashParams, HashingLimits, HashingPool, PasswordHasher, PasswordPolicy, Pepper}, memory_store::{MemoryActivityLogRepository, MemoryDocumentStore, MemoryKeyValueStore, MemoryVoterRepository}, models::{ActivityLogEntry, AdminRole, LoginResponse, UserEventMeta, VOTER_SCHEMA_VERSION, VoteTokenClaim, Voter}, recovery_codes, repository::{DocumentStore, VoterQuery, VoterRepository}, routes, send_quota::{ADMIN_ALERT_CHANNEL, SendQuotas}, totp::{self, SecretCipher}};
+
+const PEER: &str = "203.0.113.7:40000";
+
+struct Harness {
+	ctx: AppContext,
+	voters: Arc<MemoryVoterRepository>,
+	logs: Arc<MemoryActivityLogRepository>,
+	kv: Arc<MemoryKeyValueStore>,
+	codes: Arc<CapturingCodeSender>,
+	clock: Arc<FakeClock>
+}
+
+/// Hashing as in production, with parameters fast enough for tests
+fn cheap_hasher(peppers: Vec<Pepper>) -> PasswordHasher {
+	PasswordHasher { params: HashParams { mem_cost_kib: 1024, time_cost: 1, parallelism: 1 }, peppers, pool: HashingPool::new(HashingLimits { max_concurrent: 2, max_queued: 16 }) }
+}
+
+fn harness() -> Harness {
+	let voters = Arc::new(MemoryVoterRepository::new());
+	let logs = Arc::new(MemoryActivityLogRepository::new());
+	let clock = Arc::new(FakeClock::new(chrono::Utc::now()));
+	let kv = Arc::new(MemoryKeyValueStore::with_clock(clock.clone()));
+	let codes = Arc::new(CapturingCodeSender::new());
+	let ctx = AppContext {
+		vote_year: 10,
+		key_pair: ES256kKeyPair::generate(),
+		voters: voters.clone(),
+		logs: logs.clone(),
+		kv: kv.clone(),
+		code_sender: codes.clone(),
+		clock: clock.clone(),
+		code_generator: Arc::new(SequentialCodeGenerator::new(123456)),
+		trusted_proxies: vec![],
+		gateway_secret: None,
+		deletion_grace_days: 14,
+		password_policy: Arc::new(PasswordPolicy {
+			breached: Some(BreachedPasswords::from_lines(vec!["password".to_string(), "iloveyou123".to_string()])),
+			..PasswordPolicy::default()
+		}),
+		password_hasher: Arc::new(cheap_hasher(vec![])),
+		secret_cipher: Arc::new(SecretCipher::new(&[7u8; 32])),
+		send_quotas: Arc::new(SendQuotas::default())
+	};
+	Harness { ctx, voters, logs, kv, codes, clock }
+}
+
+macro_rules! app {
+	($h:expr) => {
+		test::init_service(App::new().app_data(Data::new($h.ctx.clone())).configure(routes)).await
+	};
+}
+
+macro_rules! post {
+	($app:expr, $uri:expr, $body:expr) => {
+		post!($app, $uri, $body, None::<String>)
+	};
+	($app:expr, $uri:expr, $body:expr, $sid:expr) => {{
+		let mut req = test::TestRequest::post().uri($uri).peer_addr(PEER.parse().unwrap()).set_json(&$body);
+		if let Some(sid) = $sid {
+			req = req.cookie(Cookie::new("sid", sid));
+		}
+		let resp = test::call_service(&$app, req.to_request()).await;
+		let status = resp.status();
+		let body = test::read_body(resp).await;
+		(status, serde_json::from_slice::<Value>(&body).unwrap_or(Value::Null))
+	}};
+}
+
+fn verify(h: &Harness, token: &str, audience: &str) -> JWTClaims<VoteTokenClaim> {
+	let mut options = VerificationOptions::default();
+	options.allowed_audiences = Some([audience.to_string()].iter().cloned().collect::<HashSet<_>>());
+	h.ctx.key_pair.public_key().verify_token::<VoteTokenClaim>(token, Some(options)).expect("token should verify")
+}
+
+fn existing_voter(email: Option<&str>, phone: Option<&str>) -> Voter {
+	Voter {
+		_id: None,
+		phone: phone.map(|s| s.to_string()),
+		phone_verified: phone.is_some(),
+		email: email.map(|s| s.to_string()),
+		email_verified: email.is_some(),
+		password_hashed: None,
+		salt: None,
+		pepper_version: None,
+		created_at: DateTime::now(),
+		nickname: Some("old-nick".into()),
+		signup_ip: None,
+		qq_openid: None,
+		pfp: None,
+		thbwiki_uid: None,
+		removed: None,
+		schema_version: VOTER_SCHEMA_VERSION,
+		ban: None,
+		purge_after: None,
+		purged_at: None,
+		totp_secret: None,
+		recovery_codes: vec![],
+		passkeys: vec![]
+	}
+}
+
+fn argon2_hash(password: &str) -> String {
+	argon2::hash_encoded(password.as_bytes(), b"0123456789abcdef", &argon2::Config::default()).unwrap()
+}
+
+/// Log a voter in by phone, returns (uid, session token)
+macro_rules! login_by_phone {
+	($h:expr, $app:expr, $phone:expr) => {{
+		let (status, _) = post!($app, "/v1/send-sms-code", json!({ "phone": $phone, "meta": {} }));
+		assert_eq!(status, StatusCode::OK);
+		let code = $h.codes.last_code_for($phone).unwrap();
+		let (status, body) = post!($app, "/v1/login-phone", json!({ "phone": $phone, "verify_code": code, "nickname": "nick", "meta": {} }));
+		assert_eq!(status, StatusCode::OK);
+		let session_token = body["session_token"].as_str().unwrap().to_string();
+		let uid = ObjectId::parse_str(&verify(&$h, &session_token, "userspace").custom.vote_id.unwrap()).unwrap();
+		(uid, session_token)
+	}};
+}
+
+#[actix_rt::test]
+async fn phone_code_signup_then_login() {
+	let h = harness();
+	let app = app!(h);
+	let (uid, _) = login_by_phone!(h, app, "13800000001");
+
+	let voters = h.voters.all();
+	assert_eq!(voters.len(), 1);
+	assert_eq!(voters[0]._id, Some(uid.clone()));
+	assert_eq!(voters[0].phone.as_deref(), Some("13800000001"));
+	assert!(voters[0].phone_verified);
+	assert_eq!(voters[0].nickname.as_deref(), Some("nick"));
+
+	// logging in again reuses the voter
+	h.kv.del("phone-verify-guard-13800000001").await.unwrap();
+	let (status, _) = post!(app, "/v1/send-sms-code", json!({ "phone": "13800000001", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let code = h.codes.last_code_for("13800000001").unwrap();
+	let (status, body) = post!(app, "/v1/login-phone", json!({ "phone": "13800000001", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["user"]["phone"], "13800000001");
+	let vote = verify(&h, body["vote_token"].as_str().unwrap(), "vote");
+	assert_eq!(vote.custom.vote_id, Some(format!("thvote-10-{}", uid)));
+	assert_eq!(h.voters.all().len(), 1);
+
+	let logs = h.logs.entries();
+	assert_eq!(logs.iter().filter(|e| matches!(e, ActivityLogEntry::SendSMS { .. })).count(), 2);
+	assert_eq!(logs.iter().filter(|e| matches!(e, ActivityLogEntry::VoterCreation { .. })).count(), 1);
+	assert_eq!(logs.iter().filter(|e| matches!(e, ActivityLogEntry::VoterLogin { uid: u, phone: Some(_), .. } if *u == uid)).count(), 2);
+	assert!(logs.iter().all(|e| match e {
+		ActivityLogEntry::VoterLogin { requester_ip, .. } => requester_ip.as_deref() == Some("203.0.113.7"),
+		_ => true
+	}));
+}
+
+#[actix_rt::test]
+async fn wrong_verify_code_is_rejected() {
+	let h = harness();
+	let app = app!(h);
+	let (status, _) = post!(app, "/v1/send-sms-code", json!({ "phone": "13800000002", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(h.codes.last_code_for("13800000002").as_deref(), Some("123456"));
+	let (status, _) = post!(app, "/v1/login-phone", json!({ "phone": "13800000002", "verify_code": "000000", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	assert!(h.voters.all().is_empty());
+}
+
+#[actix_rt::test]
+async fn resending_code_too_soon_is_rate_limited() {
+	let h = harness();
+	let app = app!(h);
+	let (status, _) = post!(app, "/v1/send-email-code", json!({ "email": "a@example.com", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let (status, _) = post!(app, "/v1/send-email-code", json!({ "email": "a@example.com", "meta": {} }));
+	assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+	assert_eq!(h.codes.sent().len(), 1);
+}
+
+#[actix_rt::test]
+async fn email_code_login() {
+	let h = harness();
+	let app = app!(h);
+	let (status, _) = post!(app, "/v1/send-email-code", json!({ "email": "b@example.com", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let code = h.codes.last_code_for("b@example.com").unwrap();
+	let (status, body) = post!(app, "/v1/login-email", json!({ "email": "b@example.com", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["user"]["email"], "b@example.com");
+	assert_eq!(body["user"]["password"], false);
+	let voters = h.voters.all();
+	assert_eq!(voters.len(), 1);
+	assert!(voters[0].email_verified);
+	assert!(h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::SendEmail { target_email, .. } if target_email == "b@example.com")));
+}
+
+#[actix_rt::test]
+async fn email_password_login() {
+	let h = harness();
+	let app = app!(h);
+	let mut voter = existing_voter(Some("c@example.com"), None);
+	voter.password_hashed = Some(argon2_hash("hunter22"));
+	let uid = h.voters.insert(&voter).await.unwrap();
+
+	let (status, _) = post!(app, "/v1/login-email-password", json!({ "email": "c@example.com", "password": "wrong", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, _) = post!(app, "/v1/login-email-password", json!({ "email": "nobody@example.com", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::NOT_FOUND);
+	let (status, body) = post!(app, "/v1/login-email-password", json!({ "email": "c@example.com", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["user"]["password"], true);
+	let session = verify(&h, body["session_token"].as_str().unwrap(), "userspace");
+	assert_eq!(session.custom.vote_id, Some(uid.to_string()));
+}
+
+#[actix_rt::test]
+async fn legacy_bcrypt_password_is_upgraded() {
+	let h = harness();
+	let app = app!(h);
+	let mut voter = existing_voter(Some("d@example.com"), None);
+	voter.salt = Some("legacy-salt".into());
+	voter.password_hashed = Some(bcrypt::hash("hunter22legacy-salt", 4).unwrap());
+	let uid = h.voters.insert(&voter).await.unwrap();
+
+	let (status, _) = post!(app, "/v1/login-email-password", json!({ "email": "d@example.com", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let voter = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	assert!(voter.salt.is_none());
+	assert!(argon2::verify_encoded(voter.password_hashed.as_ref().unwrap(), b"hunter22").unwrap());
+}
+
+#[actix_rt::test]
+async fn imported_voter_logs_in() {
+	let h = harness();
+	let app = app!(h);
+	let importer = LegacyImporter::with_stores(h.voters.clone(), Arc::new(MemoryDocumentStore::new()));
+	let record = LegacyVoter {
+		legacy_id: "legacy-1".into(),
+		email: Some(" Imported@Example.com ".into()),
+		phone: None,
+		nickname: Some("veteran".into()),
+		password_hashed: Some(bcrypt::hash("hunter22legacy-salt", 4).unwrap()),
+		salt: Some("legacy-salt".into()),
+		created_at: Some("2019-02-01T00:00:00Z".into())
+	};
+	let invalid = LegacyVoter { legacy_id: "legacy-2".into(), email: None, ..record.clone() };
+	let mut report = vec![];
+	let summary = importer.import(&[record.clone(), invalid], &mut report).await.unwrap();
+	assert_eq!((summary.imported, summary.invalid), (1, 1));
+	assert!(String::from_utf8(report).unwrap().contains("NO_CONTACT"));
+	// processed records are skipped when run again
+	let summary = importer.import(&[record], &mut Vec::<u8>::new()).await.unwrap();
+	assert_eq!((summary.imported, summary.skipped), (0, 1));
+	assert_eq!(h.voters.all().len(), 1);
+	assert!(!h.voters.all()[0].email_verified);
+
+	// the first code login verifies the email
+	post!(app, "/v1/send-email-code", json!({ "email": "imported@example.com", "meta": {} }));
+	let code = h.codes.last_code_for("imported@example.com").unwrap();
+	let (status, body) = post!(app, "/v1/login-email", json!({ "email": "imported@example.com", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert!(body["vote_token"].is_string());
+	assert_eq!(body["user"]["username"], "veteran");
+	assert!(h.voters.all()[0].email_verified);
+	let (status, _) = post!(app, "/v1/login-email-password", json!({ "email": "imported@example.com", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn migrations_normalize_contacts_and_backfill_once() {
+	let store = MemoryDocumentStore::new();
+	let (old, owner, clash) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+	store.upsert("voters", doc! { "_id": old, "email": " Mixed@Example.COM ", "phone": "+86 138-0000-0035" }).await.unwrap();
+	store.upsert("voters", doc! { "_id": owner, "email": "taken@example.com", "email_verified": true, "phone_verified": false, "schema_version": VOTER_SCHEMA_VERSION }).await.unwrap();
+	store.upsert("voters", doc! { "_id": clash, "email": "Taken@Example.com", "email_verified": false, "phone_verified": false, "schema_version": VOTER_SCHEMA_VERSION }).await.unwrap();
+	store.upsert("voter_logs", doc! { "_id": ObjectId::new(), "SendSMS": { "target_phone": "+86 138 0000 0035", "code": "123456" } }).await.unwrap();
+	let counts = |reports: &[(&str, migrations::MigrationReport)]| reports.iter().map(|(id, r)| (id.to_string(), r.scanned, r.changed, r.conflicts)).collect::<Vec<_>>();
+	let expected = vec![
+		("0001_normalize_voter_contacts".to_string(), 3, 1, 1),
+		("0002_normalize_log_contacts".to_string(), 1, 1, 0),
+		("0003_backfill_voter_fields".to_string(), 1, 1, 0)
+	];
+
+	// a dry run reports the changes but writes nothing
+	let voters = store.all("voters");
+	let logs = store.all("voter_logs");
+	let reports = migrations::run_pending(&store, true).await.unwrap();
+	assert_eq!(counts(&reports), expected);
+	assert_eq!(store.all("voters"), voters);
+	assert_eq!(store.all("voter_logs"), logs);
+	assert!(store.all("schema_migrations").is_empty());
+
+	let reports = migrations::run_pending(&store, false).await.unwrap();
+	assert_eq!(counts(&reports), expected);
+	let voters = store.all("voters");
+	assert_eq!(voters[0].get_str("email").unwrap(), "mixed@example.com");
+	assert_eq!(voters[0].get_str("phone").unwrap(), "13800000035");
+	assert_eq!(voters[0].get_bool("email_verified").unwrap(), false);
+	assert_eq!(voters[0].get_bool("phone_verified").unwrap(), false);
+	assert_eq!(voters[0].get("schema_version"), Some(&Bson::from(VOTER_SCHEMA_VERSION)));
+	// the owner keeps its email, the clashing voter is left alone
+	assert_eq!(voters[1].get_bool("email_verified").unwrap(), true);
+	assert_eq!(voters[2].get_str("email").unwrap(), "Taken@Example.com");
+	assert_eq!(store.all("voter_logs")[0].get_document("SendSMS").unwrap().get_str("target_phone").unwrap(), "13800000035");
+	assert_eq!(store.all("schema_migrations").len(), 3);
+
+	// applied migrations are not run again
+	assert!(migrations::run_pending(&store, false).await.unwrap().is_empty());
+	assert_eq!(store.all("voters"), voters);
+	assert_eq!(store.all("schema_migrations").len(), 3);
+}
+
+#[actix_rt::test]
+async fn signup_with_password_then_login_by_phone_password() {
+	let h = harness();
+	let app = app!(h);
+	post!(app, "/v1/send-sms-code", json!({ "phone": "13800000022", "meta": {} }));
+	let code = h.codes.last_code_for("13800000022").unwrap();
+	let (status, body) = post!(app, "/v1/login-phone", json!({ "phone": "13800000022", "verify_code": code, "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let uid = ObjectId::parse_str(&verify(&h, body["session_token"].as_str().unwrap(), "userspace").custom.vote_id.unwrap()).unwrap();
+
+	let (status, _) = post!(app, "/v1/login-phone-password", json!({ "phone": "13800000022", "password": "wrong", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, _) = post!(app, "/v1/login-phone-password", json!({ "phone": "13800000099", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, body) = post!(app, "/v1/login-phone-password", json!({ "phone": "+86 138-0000-0022", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(verify(&h, body["session_token"].as_str().unwrap(), "userspace").custom.vote_id, Some(uid.to_string()));
+}
+
+#[actix_rt::test]
+async fn phone_password_login_upgrades_legacy_hash() {
+	let h = harness();
+	let app = app!(h);
+	let mut voter = existing_voter(None, Some("13800000023"));
+	voter.salt = Some("legacy-salt".into());
+	voter.password_hashed = Some(bcrypt::hash("hunter22legacy-salt", 4).unwrap());
+	let uid = h.voters.insert(&voter).await.unwrap();
+	// phone-only voters without a password can not use one
+	let uid_without = h.voters.insert(&existing_voter(None, Some("13800000024"))).await.unwrap();
+
+	let (status, _) = post!(app, "/v1/login-phone-password", json!({ "phone": "13800000023", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let voter = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	assert!(voter.salt.is_none());
+	assert!(argon2::verify_encoded(voter.password_hashed.as_ref().unwrap(), b"hunter22").unwrap());
+	let (status, _) = post!(app, "/v1/login-phone-password", json!({ "phone": "13800000024", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+	assert!(h.voters.find_by_id(&uid_without).await.unwrap().unwrap().password_hashed.is_none());
+}
+
+#[actix_rt::test]
+async fn outdated_hashes_are_replaced_on_login() {
+	let mut h = harness();
+	let pepper = |version: i32| Pepper { version, secret: format!("pepper-{}", version).into_bytes() };
+	h.ctx.password_hasher = Arc::new(cheap_hasher(vec![pepper(1)]));
+	let app = app!(h);
+	let mut voter = existing_voter(Some("rehash@example.com"), None);
+	// argon2i with default parameters and no pepper
+	voter.password_hashed = Some(argon2_hash("hunter22"));
+	let uid = h.voters.insert(&voter).await.unwrap();
+
+	let (status, _) = post!(app, "/v1/login-email-password", json!({ "email": "rehash@example.com", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let voter = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	let hash = voter.password_hashed.clone().unwrap();
+	assert!(hash.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+	assert_eq!(voter.pepper_version, Some(1));
+	assert!(argon2::verify_encoded_ext(&hash, b"hunter22", b"pepper-1", &[]).unwrap());
+	assert!(!argon2::verify_encoded(&hash, b"hunter22").unwrap());
+
+	// up to date hashes are kept
+	let (status, _) = post!(app, "/v1/login-email-password", json!({ "email": "rehash@example.com", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(h.voters.find_by_id(&uid).await.unwrap().unwrap().password_hashed, Some(hash.clone()));
+
+	// after rotating the pepper, the old one still verifies and is replaced
+	h.ctx.password_hasher = Arc::new(cheap_hasher(vec![pepper(1), pepper(2)]));
+	let app = app!(h);
+	let (status, _) = post!(app, "/v1/login-email-password", json!({ "email": "rehash@example.com", "password": "wrong", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	assert_eq!(h.voters.find_by_id(&uid).await.unwrap().unwrap().pepper_version, Some(1));
+	let (status, _) = post!(app, "/v1/login-email-password", json!({ "email": "rehash@example.com", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let voter = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	assert_eq!(voter.pepper_version, Some(2));
+	assert!(argon2::verify_encoded_ext(voter.password_hashed.as_ref().unwrap(), b"hunter22", b"pepper-2", &[]).unwrap());
+}
+
+#[actix_rt::test]
+async fn hashing_overload_is_refused() {
+	let mut h = harness();
+	h.ctx.password_hasher = Arc::new(PasswordHasher { pool: HashingPool::new(HashingLimits { max_concurrent: 1, max_queued: 0 }), ..cheap_hasher(vec![]) });
+	let app = app!(h);
+	let mut voter = existing_voter(Some("busy@example.com"), None);
+	h.ctx.password_hasher.set_password(&mut voter, "hunter22").await.unwrap();
+	h.voters.insert(&voter).await.unwrap();
+
+	// hold the only slot
+	let (release, wait) = std::sync::mpsc::channel::<()>();
+	let hasher = h.ctx.password_hasher.clone();
+	let busy = actix_rt::spawn(async move { hasher.pool.run(move || Ok(wait.recv().ok())).await });
+	while h.ctx.password_hasher.pool.metrics().running == 0 {
+		actix_rt::time::sleep(std::time::Duration::from_millis(5)).await;
+	}
+	let (status, body) = post!(app, "/v1/login-email-password", json!({ "email": "busy@example.com", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+	assert!(body.to_string().contains("PASSWORD_HASHING_BUSY"));
+
+	release.send(()).unwrap();
+	assert_eq!(busy.await.unwrap().unwrap(), Some(()));
+	let (status, _) = post!(app, "/v1/login-email-password", json!({ "email": "busy@example.com", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+
+	let (status, body) = post!(app, "/v1/admin/hashing-metrics", json!({ "admin_token": admin_token(&h, AdminRole::Viewer) }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["completed"], json!(3));
+	assert_eq!(body["rejected"], json!(1));
+	assert_eq!(body["running"], json!(0));
+	assert_eq!(body["queued"], json!(0));
+}
+
+#[actix_rt::test]
+async fn totp_enrollment_and_mfa_login() {
+	let h = harness();
+	let app = app!(h);
+	let (uid, token) = login_by_phone!(h, app, "13800000032");
+	let (status, body) = post!(app, "/v1/enroll-totp", json!({ "user_token": token, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let encoded = body["secret"].as_str().unwrap().to_string();
+	assert!(body["otpauth_uri"].as_str().unwrap().starts_with(&format!("otpauth://totp/THVote:13800000032?secret={}&issuer=THVote", encoded)));
+	let secret = base32::decode(base32::Alphabet::RFC4648 { padding: false }, &encoded).unwrap();
+	let code_now = || totp::code_at(&secret, h.clock.now().timestamp());
+
+	let (status, _) = post!(app, "/v1/confirm-totp", json!({ "user_token": token, "code": "000000", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, _) = post!(app, "/v1/confirm-totp", json!({ "user_token": token, "code": code_now(), "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let voter = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	// stored encrypted
+	assert!(!voter.totp_secret.as_ref().unwrap().contains(&hex::encode(&secret)));
+	assert!(h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::UpdateMfa { method, enabled: true, .. } if method == "totp")));
+
+	// logins stop at the second factor
+	h.clock.advance(chrono::Duration::seconds(121));
+	post!(app, "/v1/send-sms-code", json!({ "phone": "13800000032", "meta": {} }));
+	let sms = h.codes.last_code_for("13800000032").unwrap();
+	let (status, body) = post!(app, "/v1/login-phone", json!({ "phone": "13800000032", "verify_code": sms, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["mfa_required"], true);
+	assert_eq!(body["methods"], json!(["totp"]));
+	assert!(body["session_token"].is_null());
+	let challenge = body["challenge_token"].as_str().unwrap().to_string();
+	let (status, _) = post!(app, "/v1/login-mfa", json!({ "challenge_token": challenge, "code": "000000", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, body) = post!(app, "/v1/login-mfa", json!({ "challenge_token": challenge, "code": code_now(), "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["user"]["totp"], true);
+	let token = body["session_token"].as_str().unwrap().to_string();
+	// challenges are single use
+	let (status, _) = post!(app, "/v1/login-mfa", json!({ "challenge_token": challenge, "code": code_now(), "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	// and dropped after 5 wrong codes
+	h.clock.advance(chrono::Duration::seconds(121));
+	post!(app, "/v1/send-sms-code", json!({ "phone": "13800000032", "meta": {} }));
+	let sms = h.codes.last_code_for("13800000032").unwrap();
+	let (_, body) = post!(app, "/v1/login-phone", json!({ "phone": "13800000032", "verify_code": sms, "meta": {} }));
+	let challenge = body["challenge_token"].as_str().unwrap().to_string();
+	for _ in 0..5 {
+		h.clock.advance(chrono::Duration::seconds(31));
+		let (status, _) = post!(app, "/v1/login-mfa", json!({ "challenge_token": challenge, "code": "000000", "meta": {} }));
+		assert_eq!(status, StatusCode::UNAUTHORIZED);
+	}
+	h.clock.advance(chrono::Duration::seconds(31));
+	let (status, _) = post!(app, "/v1/login-mfa", json!({ "challenge_token": challenge, "code": code_now(), "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+	// disabling takes re-authentication
+	let (status, _) = post!(app, "/v1/disable-totp", json!({ "user_token": token, "meta": {} }));
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+	h.clock.advance(chrono::Duration::seconds(121));
+	post!(app, "/v1/send-sms-code", json!({ "phone": "13800000032", "meta": {} }));
+	let sms = h.codes.last_code_for("13800000032").unwrap();
+	let (status, _) = post!(app, "/v1/disable-totp", json!({ "user_token": token, "verify_code": sms, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert!(h.voters.find_by_id(&uid).await.unwrap().unwrap().totp_secret.is_none());
+	h.clock.advance(chrono::Duration::seconds(121));
+	login_by_phone!(h, app, "13800000032");
+}
+
+#[actix_rt::test]
+async fn recovery_codes_replace_lost_factors() {
+	let h = harness();
+	let app = app!(h);
+	let (uid, token) = login_by_phone!(h, app, "13800000033");
+	let (status, _) = post!(app, "/v1/regenerate-recovery-codes", json!({ "user_token": token, "meta": {} }));
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+	h.clock.advance(chrono::Duration::seconds(121));
+	post!(app, "/v1/send-sms-code", json!({ "phone": "13800000033", "meta": {} }));
+	let sms = h.codes.last_code_for("13800000033").unwrap();
+	let (status, body) = post!(app, "/v1/regenerate-recovery-codes", json!({ "user_token": token, "verify_code": sms, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let codes: Vec<String> = body["codes"].as_array().unwrap().iter().map(|c| c.as_str().unwrap().to_string()).collect();
+	assert_eq!(codes.len(), 10);
+	assert_eq!(codes[0].len(), 11);
+	// stored hashed
+	let voter = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	assert_eq!(voter.recovery_codes.len(), 10);
+	assert!(voter.recovery_codes.iter().all(|c| c.len() == 64 && !codes.contains(c)));
+
+	// in place of the SMS code of a lost phone
+	let (status, body) = post!(app, "/v1/login-phone", json!({ "phone": "13800000033", "verify_code": codes[0].to_lowercase(), "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["user"]["recovery_codes"], 9);
+	assert!(h.codes.sent().contains(&SentCode::Notice { target: "13800000033".into(), notice: Notice::RecoveryCodeUsed }));
+	assert!(h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::UseRecoveryCode { uid: u, remaining: 9, .. } if *u == uid)));
+	let (status, _) = post!(app, "/v1/login-phone", json!({ "phone": "13800000033", "verify_code": codes[0], "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+	// and of a TOTP code
+	let (_, body) = post!(app, "/v1/enroll-totp", json!({ "user_token": token, "meta": {} }));
+	let secret = base32::decode(base32::Alphabet::RFC4648 { padding: false }, body["secret"].as_str().unwrap()).unwrap();
+	let (status, _) = post!(app, "/v1/confirm-totp", json!({ "user_token": token, "code": totp::code_at(&secret, h.clock.now().timestamp()), "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	h.clock.advance(chrono::Duration::seconds(61));
+	let (_, body) = post!(app, "/v1/login-phone", json!({ "phone": "13800000033", "verify_code": codes[1], "meta": {} }));
+	assert_eq!(body["methods"], json!(["totp", "recovery_code"]));
+	let (status, body) = post!(app, "/v1/login-mfa", json!({ "challenge_token": body["challenge_token"], "code": codes[2], "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["user"]["recovery_codes"], 7);
+
+	// a code is used once even by requests holding the voter from before
+	h.clock.advance(chrono::Duration::seconds(61));
+	let stale = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	let requester = Requester { ip: None, additional_fingerprint: None };
+	let (mut first, mut second) = (stale.clone(), stale);
+	let (a, b) = futures::join!(recovery_codes::consume(&h.ctx, &mut first, &codes[4], &requester), recovery_codes::consume(&h.ctx, &mut second, &codes[4], &requester));
+	assert!(a.is_ok() != b.is_ok());
+	assert!(matches!(a.err().or(b.err()), Some(Error::Auth("INCORRECT_RECOVERY_CODE"))));
+	assert_eq!(h.voters.find_by_id(&uid).await.unwrap().unwrap().recovery_codes.len(), 6);
+
+	// suspended voters keep their codes
+	admin::ban_voter(&h.ctx, "alice", &uid, "spam", Some(24)).await.unwrap();
+	h.clock.advance(chrono::Duration::seconds(61));
+	let (status, _) = post!(app, "/v1/login-phone", json!({ "phone": "13800000033", "verify_code": codes[3], "meta": {} }));
+	assert_eq!(status, StatusCode::FORBIDDEN);
+	assert_eq!(h.voters.find_by_id(&uid).await.unwrap().unwrap().recovery_codes.len(), 6);
+}
+
+#[actix_rt::test]
+async fn pending_login_session_is_linked() {
+	let h = harness();
+	let app = app!(h);
+	let sid = h.ctx.create_login_session(LoginSession { thbwiki_uid: Some("thb-1".into()), qq_openid: None, signup_ip: None }).await.unwrap();
+	let (status, _) = post!(app, "/v1/send-sms-code", json!({ "phone": "13800000003", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let code = h.codes.last_code_for("13800000003").unwrap();
+	let (status, _) = post!(app, "/v1/login-phone", json!({ "phone": "13800000003", "verify_code": code, "meta": {} }), Some(sid.clone()));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(h.voters.all()[0].thbwiki_uid.as_deref(), Some("thb-1"));
+	// sessions are single use
+	assert!(h.ctx.get_login_session(&sid).await.is_none());
+}
+
+#[actix_rt::test]
+async fn update_contact_details_and_nickname() {
+	let h = harness();
+	let app = app!(h);
+	let (uid, token) = login_by_phone!(h, app, "13800000004");
+
+	let (status, _) = post!(app, "/v1/send-email-code", json!({ "email": "e@example.com", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let code = h.codes.last_code_for("e@example.com").unwrap();
+	let (status, _) = post!(app, "/v1/update-email", json!({ "user_token": token, "email": "e@example.com", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+
+	let (status, _) = post!(app, "/v1/send-sms-code", json!({ "phone": "13800000005", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let code = h.codes.last_code_for("13800000005").unwrap();
+	let (status, _) = post!(app, "/v1/update-phone", json!({ "user_token": token, "phone": "13800000005", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+
+	let (status, _) = post!(app, "/v1/update-nickname", json!({ "user_token": token, "nickname": "new-nick", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+
+	let voter = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	assert_eq!(voter.email.as_deref(), Some("e@example.com"));
+	assert!(voter.email_verified);
+	assert_eq!(voter.phone.as_deref(), Some("13800000005"));
+	assert_eq!(voter.nickname.as_deref(), Some("new-nick"));
+	let logs = h.logs.entries();
+	assert!(logs.iter().any(|e| matches!(e, ActivityLogEntry::UpdateEmail { old_email: None, new_email, .. } if new_email == "e@example.com")));
+	assert!(logs.iter().any(|e| matches!(e, ActivityLogEntry::UpdatePhone { old_phone: Some(old), new_phone, .. } if old == "13800000004" && new_phone == "13800000005")));
+	assert!(logs.iter().any(|e| matches!(e, ActivityLogEntry::UpdateNickname { new_nickname, .. } if new_nickname == "new-nick")));
+}
+
+#[actix_rt::test]
+async fn email_in_use_is_a_conflict() {
+	let h = harness();
+	let app = app!(h);
+	h.voters.insert(&existing_voter(Some("f@example.com"), None)).await.unwrap();
+	let (_, token) = login_by_phone!(h, app, "13800000006");
+	let (status, _) = post!(app, "/v1/send-email-code", json!({ "email": "f@example.com", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let code = h.codes.last_code_for("f@example.com").unwrap();
+	let (status, _) = post!(app, "/v1/update-email", json!({ "user_token": token, "email": "f@example.com", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::CONFLICT);
+}
+
+#[actix_rt::test]
+async fn update_password() {
+	let h = harness();
+	let app = app!(h);
+	let mut voter = existing_voter(Some("g@example.com"), None);
+	voter.password_hashed = Some(argon2_hash("old-password"));
+	let uid = h.voters.insert(&voter).await.unwrap();
+	let (status, body) = post!(app, "/v1/login-email-password", json!({ "email": "g@example.com", "password": "old-password", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let token = body["session_token"].as_str().unwrap().to_string();
+
+	let (status, _) = post!(app, "/v1/update-password", json!({ "user_token": token, "old_password": "wrong", "new_password": "new-password", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, _) = post!(app, "/v1/update-password", json!({ "user_token": token, "old_password": "old-password", "new_password": "new-password", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let voter = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	assert!(argon2::verify_encoded(voter.password_hashed.as_ref().unwrap(), b"new-password").unwrap());
+	assert!(h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::UpdatePassword { uid: u, .. } if *u == uid)));
+}
+
+#[actix_rt::test]
+async fn weak_passwords_are_refused_with_reasons() {
+	let h = harness();
+	let app = app!(h);
+	let mut voter = existing_voter(Some("weak@example.com"), None);
+	voter.password_hashed = Some(argon2_hash("old-password"));
+	h.voters.insert(&voter).await.unwrap();
+	let (_, body) = post!(app, "/v1/login-email-password", json!({ "email": "weak@example.com", "password": "old-password", "meta": {} }));
+	let token = body["session_token"].as_str().unwrap().to_string();
+
+	let cases = [
+		("", vec!["TOO_SHORT", "TOO_PREDICTABLE"]),
+		("12345678", vec!["TOO_PREDICTABLE"]),
+		("iloveyou123", vec!["BREACHED"]),
+		("Weak@Example.com", vec!["SAME_AS_PERSONAL_INFO"]),
+		("weak", vec!["TOO_SHORT", "TOO_PREDICTABLE", "SAME_AS_PERSONAL_INFO"]),
+	];
+	for (password, reasons) in cases.iter() {
+		let (status, body) = post!(app, "/v1/update-password", json!({ "user_token": token, "old_password": "old-password", "new_password": password, "meta": {} }));
+		assert_eq!(status, StatusCode::BAD_REQUEST, "{}", password);
+		assert_eq!(body["reasons"], json!(reasons), "{}", password);
+	}
+	let (status, _) = post!(app, "/v1/update-password", json!({ "user_token": token, "old_password": "old-password", "new_password": "correct horse battery", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+
+	// signup with a weak password creates nobody
+	post!(app, "/v1/send-sms-code", json!({ "phone": "13800000025", "meta": {} }));
+	let code = h.codes.last_code_for("13800000025").unwrap();
+	let (status, body) = post!(app, "/v1/login-phone", json!({ "phone": "13800000025", "verify_code": code, "password": "password", "meta": {} }));
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+	assert_eq!(body["reasons"], json!(["BREACHED"]));
+	assert!(h.voters.find_by_identity(&Identity::Phone("13800000025".into())).await.unwrap().is_none());
+}
+
+#[test]
+fn breached_password_list_accepts_hashes() {
+	// SHA-1 of "hunter2", as found in the Have I Been Pwned downloads
+	let breached = BreachedPasswords::from_lines(vec!["F3BBBD66A63D4BF1747940578EC3D0103530E21D:17043".to_string(), "".to_string()]);
+	assert_eq!(breached.len(), 1);
+	assert!(breached.contains("hunter2"));
+	assert!(!breached.contains("hunter3"));
+}
+
+#[actix_rt::test]
+async fn user_token_status() {
+	let h = harness();
+	let app = app!(h);
+	let (_, token) = login_by_phone!(h, app, "13800000007");
+	let (status, _) = post!(app, "/v1/user-token-status", json!({ "user_token": token }));
+	assert_eq!(status, StatusCode::OK);
+	let (status, _) = post!(app, "/v1/user-token-status", json!({ "user_token": "not-a-token" }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	// tokens signed by someone else are refused
+	let foreign = harness();
+	let forged = foreign.ctx.key_pair.sign(Claims::with_custom_claims(VoteTokenClaim { vote_id: Some(ObjectId::new().to_string()) }, Duration::from_hours(1)).with_audience("userspace")).unwrap();
+	let (status, _) = post!(app, "/v1/user-token-status", json!({ "user_token": forged }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn remove_voter() {
+	let h = harness();
+	let app = app!(h);
+	let (uid, token) = login_by_phone!(h, app, "13800000008");
+	// re-authentication is required
+	let (status, _) = post!(app, "/v1/remove-voter", json!({ "user_token": token, "meta": {} }));
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+	h.clock.advance(chrono::Duration::seconds(121));
+	post!(app, "/v1/send-sms-code", json!({ "phone": "13800000008", "meta": {} }));
+	let code = h.codes.last_code_for("13800000008").unwrap();
+	let (status, _) = post!(app, "/v1/remove-voter", json!({ "user_token": token, "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let voter = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	assert_eq!(voter.removed, Some(true));
+	assert!(voter.purge_after.is_some());
+	assert!(h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::RemoveVoter { uid: u, .. } if *u == uid)));
+	// logged out, and can not log in again
+	let (status, _) = post!(app, "/v1/user-token-status", json!({ "user_token": token }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	h.clock.advance(chrono::Duration::seconds(121));
+	post!(app, "/v1/send-sms-code", json!({ "phone": "13800000008", "meta": {} }));
+	let code = h.codes.last_code_for("13800000008").unwrap();
+	let (status, _) = post!(app, "/v1/login-phone", json!({ "phone": "13800000008", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[actix_rt::test]
+async fn restore_voter_within_grace_period() {
+	let h = harness();
+	let app = app!(h);
+	let mut voter = existing_voter(Some("restore@example.com"), None);
+	voter.password_hashed = Some(argon2_hash("hunter22"));
+	let uid = h.voters.insert(&voter).await.unwrap();
+	let token = h.voters.find_by_id(&uid).await.unwrap().unwrap().generate_user_auth(&h.ctx.key_pair, h.clock.now());
+	let (status, _) = post!(app, "/v1/remove-voter", json!({ "user_token": token, "old_password": "wrong", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	h.clock.advance(chrono::Duration::seconds(1));
+	let (status, _) = post!(app, "/v1/remove-voter", json!({ "user_token": token, "old_password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+
+	h.clock.advance(chrono::Duration::days(13));
+	let (status, _) = post!(app, "/v1/restore-voter", json!({ "email": "restore@example.com", "password": "wrong", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, body) = post!(app, "/v1/restore-voter", json!({ "email": "Restore@Example.com", "password": "hunter22", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert!(body["session_token"].is_string());
+	let voter = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	assert_eq!(voter.removed, None);
+	assert!(voter.purge_after.is_none());
+	assert!(h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::RestoreVoter { uid: u, .. } if *u == uid)));
+}
+
+#[actix_rt::test]
+async fn purge_removed_voters_after_grace_period() {
+	let h = harness();
+	let app = app!(h);
+	let (uid, _) = login_by_phone!(h, app, "13800000019");
+	admin::remove_voter(&h.ctx, "alice", &uid).await.unwrap();
+	assert_eq!(admin::purge_removed_voters(&h.ctx, "alice").await.unwrap(), 0);
+
+	h.clock.advance(chrono::Duration::days(15));
+	assert_eq!(admin::purge_removed_voters(&h.ctx, "alice").await.unwrap(), 1);
+	let voter = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	assert!(voter.phone.is_none() && voter.nickname.is_none() && voter.signup_ip.is_none());
+	assert!(voter.purged_at.is_some());
+	// nothing in the log points at the phone number anymore
+	assert!(!serde_json::to_string(&h.logs.entries()).unwrap().contains("13800000019"));
+	assert!(h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::PurgeVoter { uid: u, .. } if *u == uid)));
+	// too late to restore, and the phone is free again
+	assert!(admin::restore_voter(&h.ctx, "alice", &uid).await.is_err());
+	let (new_uid, _) = login_by_phone!(h, app, "13800000019");
+	assert_ne!(new_uid, uid);
+}
+
+#[actix_rt::test]
+async fn export_voter_data() {
+	let h = harness();
+	let app = app!(h);
+	let (uid, token) = login_by_phone!(h, app, "13800000020");
+	let code = h.codes.last_code_for("13800000020").unwrap();
+	let mut voter = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	voter.password_hashed = Some(argon2_hash("hunter22"));
+	h.voters.replace(&voter).await.unwrap();
+
+	let (status, body) = post!(app, "/v1/export-voter-data", json!({ "user_token": token, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["voter"]["phone"], "13800000020");
+	assert_eq!(body["voter"]["password_hashed"], "<redacted>");
+	assert_eq!(body["identities"][0]["kind"], "phone");
+	assert_eq!(body["sessions"].as_array().unwrap().len(), 1);
+	let logs = body["logs"].as_array().unwrap();
+	assert!(logs.iter().any(|e| e["SendSMS"]["target_phone"] == "13800000020"));
+	assert!(logs.iter().any(|e| e["VoterLogin"].is_object()));
+	assert!(!body.to_string().contains(&code));
+	assert!(h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::ExportVoterData { uid: u, .. } if *u == uid)));
+
+	// once a day
+	let (status, _) = post!(app, "/v1/export-voter-data", json!({ "user_token": token, "meta": {} }));
+	assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+	// sessions revoked since are not listed
+	h.ctx.revoke_sessions(&uid).await.unwrap();
+	h.clock.advance(chrono::Duration::days(1));
+	let (_, token) = login_by_phone!(h, app, "13800000020");
+	let (status, body) = post!(app, "/v1/export-voter-data", json!({ "user_token": token, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["sessions"].as_array().unwrap().len(), 1);
+}
+
+#[actix_rt::test]
+async fn reset_forgotten_password() {
+	let h = harness();
+	let app = app!(h);
+	let mut voter = existing_voter(Some("forgot@example.com"), Some("13800000021"));
+	voter.password_hashed = Some(argon2_hash("forgotten"));
+	let uid = h.voters.insert(&voter).await.unwrap();
+	let old_token = h.voters.find_by_id(&uid).await.unwrap().unwrap().generate_user_auth(&h.ctx.key_pair, h.clock.now());
+
+	// unknown contacts get no code, and are not told so
+	let (status, _) = post!(app, "/v1/send-password-reset-code", json!({ "email": "nobody@example.com", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert!(h.codes.last_code_for("nobody@example.com").is_none());
+	// held back like a known contact would be
+	let (status, _) = post!(app, "/v1/send-password-reset-code", json!({ "email": "nobody@example.com", "meta": {} }));
+	assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+	let (status, _) = post!(app, "/v1/send-password-reset-code", json!({ "email": "Forgot@Example.com", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let code = h.codes.last_code_for("forgot@example.com").unwrap();
+	// login codes do not reset passwords and reset codes do not log in
+	let (status, _) = post!(app, "/v1/login-email", json!({ "email": "forgot@example.com", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, _) = post!(app, "/v1/reset-password", json!({ "email": "forgot@example.com", "verify_code": "000000", "new_password": "remembered", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	// neither the contact nor the nickname is confirmed without the code
+	let (status, _) = post!(app, "/v1/reset-password", json!({ "email": "nobody@example.com", "verify_code": "000000", "new_password": "remembered", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, _) = post!(app, "/v1/reset-password", json!({ "email": "forgot@example.com", "verify_code": "000000", "new_password": "old-nick", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, _) = post!(app, "/v1/reset-password", json!({ "email": "forgot@example.com", "verify_code": code, "new_password": "remembered", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+
+	let (status, _) = post!(app, "/v1/user-token-status", json!({ "user_token": old_token }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, _) = post!(app, "/v1/login-email-password", json!({ "email": "forgot@example.com", "password": "remembered", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert!(h.codes.sent().contains(&SentCode::Notice { target: "13800000021".into(), notice: Notice::PasswordReset }));
+	assert!(h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::ResetPassword { uid: u, channel, .. } if *u == uid && channel == "email")));
+	// codes are single use
+	h.clock.advance(chrono::Duration::seconds(61));
+	let (status, _) = post!(app, "/v1/reset-password", json!({ "email": "forgot@example.com", "verify_code": code, "new_password": "remembered-again", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn repeated_failed_logins_lock_the_account() {
+	let h = harness();
+	let app = app!(h);
+	let mut voter = existing_voter(Some("locked@example.com"), Some("13800000031"));
+	voter.password_hashed = Some(argon2_hash("correct-horse"));
+	let uid = h.voters.insert(&voter).await.unwrap();
+	let login = |password: &str| json!({ "email": "locked@example.com", "password": password, "meta": {} });
+
+	// unknown contacts are answered like a wrong password
+	let (unknown_status, unknown_body) = post!(app, "/v1/login-email-password", json!({ "email": "nobody@example.com", "password": "wrong", "meta": {} }));
+	let (status, body) = post!(app, "/v1/login-email-password", login("wrong"));
+	assert_eq!((unknown_status, unknown_body), (status, body));
+	h.clock.advance(chrono::Duration::days(1));
+	for _ in 0..5 {
+		let (status, _) = post!(app, "/v1/login-email-password", login("wrong"));
+		assert_eq!(status, StatusCode::UNAUTHORIZED);
+	}
+	// even the right password is refused while locked
+	let (status, body) = post!(app, "/v1/login-email-password", login("correct-horse"));
+	assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+	assert!(body.to_string().contains("ACCOUNT_LOCKED"));
+	assert!(h.codes.sent().contains(&SentCode::Notice { target: "locked@example.com".into(), notice: Notice::AccountLocked }));
+	assert_eq!(h.logs.entries().iter().filter(|e| matches!(e, ActivityLogEntry::LoginFailed { uid: u, .. } if *u == uid)).count(), 6);
+
+	// one more try per expired lock, until the next step locks for longer
+	for _ in 0..5 {
+		h.clock.advance(chrono::Duration::seconds(61));
+		let (status, _) = post!(app, "/v1/login-email-password", login("wrong"));
+		assert_eq!(status, StatusCode::UNAUTHORIZED);
+	}
+	h.clock.advance(chrono::Duration::seconds(61));
+	let (status, _) = post!(app, "/v1/login-email-password", login("correct-horse"));
+	assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+	// unlocking takes a code sent to the voter
+	let (status, _) = post!(app, "/v1/unlock-account", json!({ "email": "locked@example.com", "verify_code": "000000", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	// unknown contacts look the same
+	let (status, _) = post!(app, "/v1/unlock-account", json!({ "email": "nobody@example.com", "verify_code": "000000", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, _) = post!(app, "/v1/send-unlock-code", json!({ "phone": "13800000031", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let code = h.codes.last_code_for("13800000031").unwrap();
+	let (status, _) = post!(app, "/v1/unlock-account", json!({ "phone": "13800000031", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let locks: Vec<i64> = h.logs.entries().iter().filter_map(|e| match e {
+		ActivityLogEntry::LoginLocked { uid: Some(u), seconds, .. } if *u == uid => Some(*seconds),
+		_ => None
+	}).collect();
+	assert_eq!(locks, vec![60, 15 * 60]);
+	assert!(h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::AccountUnlocked { uid: u, channel, .. } if *u == uid && channel == "phone")));
+	let (status, _) = post!(app, "/v1/login-email-password", login("correct-horse"));
+	assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn verify_code_expires_after_an_hour() {
+	let h = harness();
+	let app = app!(h);
+	let (status, _) = post!(app, "/v1/send-sms-code", json!({ "phone": "13800000009", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let code = h.codes.last_code_for("13800000009").unwrap();
+	h.clock.advance(chrono::Duration::minutes(61));
+	let (status, _) = post!(app, "/v1/login-phone", json!({ "phone": "13800000009", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	assert!(h.voters.all().is_empty());
+}
+
+#[actix_rt::test]
+async fn resend_guard_lifts_after_interval() {
+	let h = harness();
+	let app = app!(h);
+	let (status, _) = post!(app, "/v1/send-sms-code", json!({ "phone": "13800000010", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	h.clock.advance(chrono::Duration::seconds(60));
+	let (status, _) = post!(app, "/v1/send-sms-code", json!({ "phone": "13800000010", "meta": {} }));
+	assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+	h.clock.advance(chrono::Duration::seconds(61));
+	let (status, _) = post!(app, "/v1/send-sms-code", json!({ "phone": "13800000010", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn sms_budgets_per_country_and_prefix() {
+	let mut h = harness();
+	h.ctx.send_quotas = Arc::new(SendQuotas {
+		sms_hourly: 6,
+		sms_country_daily: vec![("86".into(), 100), ("852".into(), 1)],
+		sms_other_country_daily: 1,
+		sms_prefix_hourly: 2,
+		..SendQuotas::default()
+	});
+	let app = app!(h);
+	let send = |phone: &str| json!({ "phone": phone, "meta": {} });
+
+	// held back by the guard before any budget is spent
+	assert_eq!(post!(app, "/v1/send-sms-code", send("13900000001")).0, StatusCode::OK);
+	assert_eq!(post!(app, "/v1/send-sms-code", send("13900000001")).0, StatusCode::TOO_MANY_REQUESTS);
+	assert_eq!(post!(app, "/v1/send-sms-code", send("13900000002")).0, StatusCode::OK);
+	// a prefix runs out without stopping the others
+	let (status, body) = post!(app, "/v1/send-sms-code", send("13900000003"));
+	assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+	assert!(body.to_string().contains("SEND_QUOTA_EXCEEDED"));
+	assert!(h.codes.last_code_for("13900000003").is_none());
+	assert_eq!(post!(app, "/v1/send-sms-code", send("13900000004")).0, StatusCode::TOO_MANY_REQUESTS);
+	assert_eq!(post!(app, "/v1/send-sms-code", send("13700000001")).0, StatusCode::OK);
+	// listed countries have their own budget, unlisted ones share one
+	assert_eq!(post!(app, "/v1/send-sms-code", send("+852 9123 4567")).0, StatusCode::OK);
+	assert_eq!(post!(app, "/v1/send-sms-code", send("+852 9123 4568")).0, StatusCode::TOO_MANY_REQUESTS);
+	assert_eq!(post!(app, "/v1/send-sms-code", send("+44 7700 900001")).0, StatusCode::OK);
+	assert_eq!(post!(app, "/v1/send-sms-code", send("+1 202 555 0101")).0, StatusCode::TOO_MANY_REQUESTS);
+
+	// the global budget pauses SMS until the hour is over, emails go on
+	assert_eq!(post!(app, "/v1/send-sms-code", send("13600000001")).0, StatusCode::OK);
+	assert_eq!(post!(app, "/v1/send-sms-code", send("13500000001")).0, StatusCode::TOO_MANY_REQUESTS);
+	assert!(h.kv.get("send-paused-sms").await.unwrap().is_some());
+	assert_eq!(post!(app, "/v1/send-sms-code", send("13400000001")).0, StatusCode::TOO_MANY_REQUESTS);
+	assert_eq!(post!(app, "/v1/send-email-code", json!({ "email": "quota@example.com", "meta": {} })).0, StatusCode::OK);
+	h.clock.advance(chrono::Duration::seconds(3601));
+	assert_eq!(post!(app, "/v1/send-sms-code", send("13400000002")).0, StatusCode::OK);
+
+	// one alert per exhausted budget and window
+	let scopes: Vec<String> = h.kv.published().iter()
+		.filter(|(channel, _)| channel == ADMIN_ALERT_CHANNEL)
+		.map(|(_, message)| serde_json::from_str::<Value>(message).unwrap()["scope"].as_str().unwrap().to_string())
+		.collect();
+	assert_eq!(scopes, vec!["prefix-86-1390000", "country-852", "country-other", "global-hourly"]);
+	assert_eq!(h.logs.entries().iter().filter(|e| matches!(e, ActivityLogEntry::SendQuotaExhausted { .. })).count(), 4);
+}
+
+/// Client IP resolved for a request from `peer` carrying `X-Forwarded-For: xff`
+fn resolved_ip(h: &Harness, peer: &str, xff: Option<&str>) -> Option<String> {
+	let mut req = test::TestRequest::default().peer_addr(peer.parse().unwrap());
+	if let Some(xff) = xff {
+		req = req.insert_header(("X-Forwarded-For", xff));
+	}
+	client_ip(&h.ctx, &req.to_http_request(), &UserEventMeta { user_ip: String::new(), additional_fingureprint: None })
+}
+
+#[actix_rt::test]
+async fn forwarded_for_only_trusted_from_proxies() {
+	let mut h = harness();
+	h.ctx.trusted_proxies = parse_trusted_proxies(&["10.0.0.0/8", "fd00::/8", "not-a-cidr"]);
+	assert_eq!(h.ctx.trusted_proxies.len(), 2);
+	let ip = |peer: &str, xff: Option<&str>| resolved_ip(&h, peer, xff).unwrap();
+
+	// spoofed by a client connecting directly
+	assert_eq!(ip("203.0.113.7:40000", Some("198.51.100.1")), "203.0.113.7");
+	assert_eq!(ip("203.0.113.7:40000", Some("10.0.0.2")), "203.0.113.7");
+	// through our proxies, the first address from the right that is not ours is the client
+	assert_eq!(ip("10.0.0.1:40000", Some("198.51.100.1")), "198.51.100.1");
+	assert_eq!(ip("10.0.0.1:40000", Some("1.2.3.4, 198.51.100.1, 10.0.0.2")), "198.51.100.1");
+	assert_eq!(ip("10.0.0.1:40000", Some("10.0.0.3, 10.0.0.2")), "10.0.0.3");
+	assert_eq!(ip("[::ffff:10.0.0.1]:40000", Some("198.51.100.1:5555")), "198.51.100.1");
+	assert_eq!(ip("[fd00::1]:40000", Some("2001:db8::1")), "2001:db8::1");
+	// malformed hops end the walk, never yielding an address made up by the client
+	assert_eq!(ip("10.0.0.1:40000", Some("garbage")), "10.0.0.1");
+	assert_eq!(ip("10.0.0.1:40000", Some("198.51.100.1, junk, 10.0.0.2")), "10.0.0.2");
+	assert_eq!(ip("10.0.0.1:40000", Some("")), "10.0.0.1");
+	assert_eq!(ip("10.0.0.1:40000", None), "10.0.0.1");
+}
+
+#[actix_rt::test]
+async fn session_token_expires_after_a_week() {
+	let h = harness();
+	let app = app!(h);
+	let (_, token) = login_by_phone!(h, app, "13800000011");
+	h.clock.advance(chrono::Duration::days(6));
+	let (status, _) = post!(app, "/v1/user-token-status", json!({ "user_token": token }));
+	assert_eq!(status, StatusCode::OK);
+	h.clock.advance(chrono::Duration::days(2));
+	let (status, _) = post!(app, "/v1/user-token-status", json!({ "user_token": token }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+/// Signs up a competing voter for the same phone while verifying, like a concurrent login would
+struct RacingProvider {
+	voters: Arc<MemoryVoterRepository>,
+	phone: String
+}
+
+#[async_trait]
+impl IdentityProvider for RacingProvider {
+	fn identity(&self) -> Identity {
+		Identity::Phone(self.phone.clone())
+	}
+	async fn verify(&self, _ctx: &AppContext, _voter: Option<&Voter>) -> Result<Option<Voter>, Error> {
+		self.voters.insert(&existing_voter(None, Some(&self.phone))).await?;
+		Ok(None)
+	}
+	fn allows_signup(&self) -> bool {
+		true
+	}
+}
+
+#[actix_rt::test]
+async fn concurrent_signup_logs_into_the_winner() {
+	let h = harness();
+	let provider = RacingProvider { voters: h.voters.clone(), phone: "13800000012".into() };
+	let requester = Requester { ip: None, additional_fingerprint: None };
+	let results = match complete_login(&h.ctx, &provider, None, &requester).await.unwrap() {
+		LoginResponse::Complete(results) => results,
+		LoginResponse::MfaRequired(_) => panic!("no second factor was enabled")
+	};
+	let voters = h.voters.all();
+	assert_eq!(voters.len(), 1);
+	assert_eq!(results.user.username.as_deref(), Some("old-nick"));
+	assert!(!h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::VoterCreation { .. })));
+}
+
+#[actix_rt::test]
+async fn linking_an_owned_identity_is_a_conflict() {
+	let h = harness();
+	let mut voter = existing_voter(None, Some("13800000013"));
+	voter.thbwiki_uid = Some("wiki-1".into());
+	h.voters.insert(&voter).await.unwrap();
+	let mut other = existing_voter(None, Some("13800000014"));
+	other._id = Some(h.voters.insert(&other).await.unwrap());
+	other.thbwiki_uid = Some("wiki-1".into());
+	assert!(matches!(h.voters.replace(&other).await, Err(Error::Conflict("IDENTITY_ALREADY_LINKED"))));
+}
+
+#[actix_rt::test]
+async fn contacts_are_normalized() {
+	let h = harness();
+	let app = app!(h);
+	let (status, _) = post!(app, "/v1/send-sms-code", json!({ "phone": "+86 138-0000-0015", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let code = h.codes.last_code_for("13800000015").unwrap();
+	let (status, _) = post!(app, "/v1/login-phone", json!({ "phone": "13800000015", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let (status, _) = post!(app, "/v1/send-email-code", json!({ "email": " Mixed@Example.COM", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert!(h.codes.last_code_for("mixed@example.com").is_some());
+	assert_eq!(h.voters.all()[0].phone.as_deref(), Some("13800000015"));
+}
+
+#[actix_rt::test]
+async fn admin_revoke_sessions() {
+	let h = harness();
+	let app = app!(h);
+	let (uid, token) = login_by_phone!(h, app, "13800000016");
+	admin::revoke_sessions(&h.ctx, "alice", &uid).await.unwrap();
+	let (status, _) = post!(app, "/v1/user-token-status", json!({ "user_token": token }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+	// logging in again works
+	h.clock.advance(chrono::Duration::seconds(121));
+	let (_, token) = login_by_phone!(h, app, "13800000016");
+	let (status, _) = post!(app, "/v1/user-token-status", json!({ "user_token": token }));
+	assert_eq!(status, StatusCode::OK);
+	assert!(h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::AdminAction { operator, action, uid: Some(u), .. } if operator == "alice" && action == "revoke-sessions" && *u == uid)));
+}
+
+#[actix_rt::test]
+async fn admin_remove_and_restore() {
+	let h = harness();
+	let app = app!(h);
+	let (uid, _) = login_by_phone!(h, app, "13800000017");
+	admin::remove_voter(&h.ctx, "alice", &uid).await.unwrap();
+	let voter = admin::find_voter(&h.ctx, "13800000017").await.unwrap().unwrap();
+	assert_eq!(voter.removed, Some(true));
+
+	admin::restore_voter(&h.ctx, "alice", &uid).await.unwrap();
+	let voter = admin::find_voter(&h.ctx, &format!("thvote-10-{}", uid)).await.unwrap().unwrap();
+	assert_eq!(voter.phone.as_deref(), Some("13800000017"));
+	assert_eq!(voter.removed, None);
+
+	let log = admin::voter_log(&h.ctx, "alice", &uid, 10).await.unwrap();
+	assert!(matches!(&log[0], ActivityLogEntry::AdminAction { action, .. } if action == "restore"));
+
+	// reads are audited too
+	let voter = admin::lookup_voter(&h.ctx, "bob", "13800000017").await.unwrap().unwrap();
+	assert_eq!(voter._id, Some(uid.clone()));
+	assert_eq!(voter.phone.as_deref(), Some("13800000017"));
+	let audited: Vec<String> = h.logs.entries().iter().filter_map(|e| match e {
+		ActivityLogEntry::AdminAction { action, uid: Some(u), .. } if *u == uid => Some(action.clone()),
+		_ => None
+	}).collect();
+	assert!(audited.contains(&"view-log".to_string()) && audited.contains(&"lookup".to_string()));
+}
+
+fn admin_token(h: &Harness, role: AdminRole) -> String {
+	thvote_user_manager::models::generate_admin_token(&h.ctx.key_pair, "mod-1", role, h.clock.now())
+}
+
+#[actix_rt::test]
+async fn admin_api_roles() {
+	let h = harness();
+	let app = app!(h);
+	let (uid, user_token) = login_by_phone!(h, app, "13800000018");
+	let viewer = admin_token(&h, AdminRole::Viewer);
+	let moderator = admin_token(&h, AdminRole::Moderator);
+
+	let (status, body) = post!(app, "/v1/admin/search-voters", json!({ "admin_token": viewer, "query": { "text": "0000018" } }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["total"], 1);
+	assert_eq!(body["voters"][0]["uid"], uid.to_hex());
+
+	// userspace tokens are not admin tokens and vice versa
+	let (status, _) = post!(app, "/v1/admin/search-voters", json!({ "admin_token": user_token, "query": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, _) = post!(app, "/v1/user-token-status", json!({ "user_token": viewer }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+	let (status, _) = post!(app, "/v1/admin/ban", json!({ "admin_token": viewer, "uid": uid.to_hex(), "reason": "spam" }));
+	assert_eq!(status, StatusCode::FORBIDDEN);
+	// a suspension must last
+	let (status, _) = post!(app, "/v1/admin/ban", json!({ "admin_token": moderator, "uid": uid.to_hex(), "reason": "spam", "hours": 0 }));
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+	let (status, _) = post!(app, "/v1/admin/ban", json!({ "admin_token": moderator, "uid": uid.to_hex(), "reason": "spam" }));
+	assert_eq!(status, StatusCode::OK);
+	let (status, _) = post!(app, "/v1/user-token-status", json!({ "user_token": user_token }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+	let (status, body) = post!(app, "/v1/admin/search-voters", json!({ "admin_token": viewer, "query": { "banned": true } }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["voters"][0]["ban"]["reason"], "spam");
+
+	let (status, body) = post!(app, "/v1/admin/voter-detail", json!({ "admin_token": viewer, "uid": uid.to_hex() }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["identities"][0]["kind"], "phone");
+	assert!(body["logs"].as_array().unwrap().len() >= 2);
+
+	let (status, _) = post!(app, "/v1/admin/override-contact", json!({ "admin_token": moderator, "uid": uid.to_hex(), "contact": "email", "value": "x@example.com" }));
+	assert_eq!(status, StatusCode::FORBIDDEN);
+	let (status, _) = post!(app, "/v1/admin/override-contact", json!({ "admin_token": admin_token(&h, AdminRole::Superadmin), "uid": uid.to_hex(), "contact": "email", "value": "X@example.com" }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(h.voters.all()[0].email.as_deref(), Some("x@example.com"));
+
+	let actions: Vec<String> = h.logs.entries().iter().filter_map(|e| match e {
+		ActivityLogEntry::AdminAction { operator, action, .. } if operator == "mod-1" => Some(action.clone()),
+		_ => None
+	}).collect();
+	assert_eq!(actions, vec!["search", "ban", "search", "view", "override-contact"]);
+	assert!(h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::AdminAction { action, detail: Some(detail), .. } if action == "search" && detail.contains("0000018"))));
+}
+
+#[actix_rt::test]
+async fn suspended_voter_cannot_login() {
+	let h = harness();
+	let app = app!(h);
+	let (status, _) = post!(app, "/v1/send-sms-code", json!({ "phone": "13800000019", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let code = h.codes.last_code_for("13800000019").unwrap();
+	let (status, body) = post!(app, "/v1/login-phone", json!({ "phone": "13800000019", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let vote_token = body["vote_token"].as_str().unwrap().to_string();
+	let uid = h.voters.all()[0]._id.clone().unwrap();
+
+	let (status, body) = post!(app, "/v1/vote-token-status", json!({ "vote_token": vote_token }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["suspended"], false);
+
+	admin::ban_voter(&h.ctx, "mod-1", &uid, "spam", Some(24)).await.unwrap();
+	let (status, body) = post!(app, "/v1/vote-token-status", json!({ "vote_token": vote_token }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["suspended"], true);
+	assert!(body["suspended_until"].is_i64());
+	let banned = |banned| VoterQuery { banned: Some(banned), ..Default::default() };
+	assert_eq!(admin::search_voters(&h.ctx, "mod-1", &banned(true), 1, 10).await.unwrap().1, 1);
+	assert_eq!(admin::search_voters(&h.ctx, "mod-1", &banned(false), 1, 10).await.unwrap().1, 0);
+
+	h.clock.advance(chrono::Duration::seconds(121));
+	let (status, _) = post!(app, "/v1/send-sms-code", json!({ "phone": "13800000019", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let code = h.codes.last_code_for("13800000019").unwrap();
+	let (status, _) = post!(app, "/v1/login-phone", json!({ "phone": "13800000019", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::FORBIDDEN);
+	let (status, _) = post!(app, "/v1/vote-token-status", json!({ "vote_token": "not-a-token" }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+	// the suspension runs out
+	h.clock.advance(chrono::Duration::hours(24));
+	assert_eq!(admin::search_voters(&h.ctx, "mod-1", &banned(true), 1, 10).await.unwrap().1, 0);
+	assert_eq!(admin::search_voters(&h.ctx, "mod-1", &banned(false), 1, 10).await.unwrap().1, 1);
+	let (status, _) = post!(app, "/v1/send-sms-code", json!({ "phone": "13800000019", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	let code = h.codes.last_code_for("13800000019").unwrap();
+	let (status, _) = post!(app, "/v1/login-phone", json!({ "phone": "13800000019", "verify_code": code, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+}
+
+fn b64url(bytes: &[u8]) -> String {
+	base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Authenticator in software holding one ES256 credential, answering as a browser would
+struct SoftAuthenticator {
+	key: SigningKey,
+	credential_id: Vec<u8>,
+	sign_count: u32,
+	user_verified: bool
+}
+
+impl SoftAuthenticator {
+	fn new(credential_id: &[u8]) -> SoftAuthenticator {
+		SoftAuthenticator { key: SigningKey::random(&mut rand::rngs::OsRng), credential_id: credential_id.to_vec(), sign_count: 0, user_verified: true }
+	}
+	fn id(&self) -> String {
+		b64url(&self.credential_id)
+	}
+	fn client_data(kind: &str, challenge: &str) -> Vec<u8> {
+		json!({ "type": kind, "challenge": challenge, "origin": comm::WEBAUTHN_ORIGINS[0] }).to_string().into_bytes()
+	}
+	/// User present and verified unless told otherwise, with the credential and its COSE key when `attested`
+	fn authenticator_data(&self, attested: bool) -> Vec<u8> {
+		let mut data = Sha256::digest(comm::WEBAUTHN_RP_ID.as_bytes()).to_vec();
+		data.push(0x01 | if self.user_verified { 0x04 } else { 0 } | if attested { 0x40 } else { 0 });
+		data.extend_from_slice(&self.sign_count.to_be_bytes());
+		if attested {
+			data.extend_from_slice(&[0u8; 16]);
+			data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+			data.extend_from_slice(&self.credential_id);
+			let point = self.key.verifying_key().to_encoded_point(false);
+			let cose: BTreeMap<Cbor, Cbor> = vec![
+				(Cbor::Integer(1), Cbor::Integer(2)),
+				(Cbor::Integer(3), Cbor::Integer(-7)),
+				(Cbor::Integer(-1), Cbor::Integer(1)),
+				(Cbor::Integer(-2), Cbor::Bytes(point.x().unwrap().to_vec())),
+				(Cbor::Integer(-3), Cbor::Bytes(point.y().unwrap().to_vec())),
+			].into_iter().collect();
+			data.extend(serde_cbor::to_vec(&Cbor::Map(cose)).unwrap());
+		}
+		data
+	}
+	/// `navigator.credentials.create` with `none` attestation
+	fn create(&self, challenge: &str) -> Value {
+		let attestation: BTreeMap<Cbor, Cbor> = vec![
+			(Cbor::Text("fmt".into()), Cbor::Text("none".into())),
+			(Cbor::Text("attStmt".into()), Cbor::Map(BTreeMap::new())),
+			(Cbor::Text("authData".into()), Cbor::Bytes(self.authenticator_data(true))),
+		].into_iter().collect();
+		json!({ "id": self.id(), "response": {
+			"clientDataJSON": b64url(&Self::client_data("webauthn.create", challenge)),
+			"attestationObject": b64url(&serde_cbor::to_vec(&Cbor::Map(attestation)).unwrap())
+		} })
+	}
+	/// `navigator.credentials.get`, moving the signature counter forward
+	fn get(&mut self, challenge: &str, uid: &ObjectId) -> Value {
+		self.sign_count += 1;
+		let authenticator_data = self.authenticator_data(false);
+		let client_data = Self::client_data("webauthn.get", challenge);
+		let mut signed = authenticator_data.clone();
+		signed.extend_from_slice(&Sha256::digest(&client_data));
+		let signature: EcdsaSignature = self.key.sign(&signed);
+		json!({ "id": self.id(), "response": {
+			"clientDataJSON": b64url(&client_data),
+			"authenticatorData": b64url(&authenticator_data),
+			"signature": b64url(signature.to_der().as_bytes()),
+			"userHandle": b64url(&uid.bytes())
+		} })
+	}
+}
+
+/// Registration options, re-authenticating with a fresh SMS code
+macro_rules! passkey_options {
+	($h:expr, $app:expr, $token:expr, $phone:expr) => {{
+		$h.clock.advance(chrono::Duration::seconds(121));
+		post!($app, "/v1/send-sms-code", json!({ "phone": $phone, "meta": {} }));
+		let code = $h.codes.last_code_for($phone).unwrap();
+		let (status, options) = post!($app, "/v1/passkey-registration-options", json!({ "user_token": $token, "verify_code": code, "meta": {} }));
+		assert_eq!(status, StatusCode::OK);
+		options
+	}};
+}
+
+#[actix_rt::test]
+async fn passkey_registration_and_login() {
+	let h = harness();
+	let app = app!(h);
+	let (uid, token) = login_by_phone!(h, app, "13800000034");
+	let mut authenticator = SoftAuthenticator::new(b"soft-credential-1");
+
+	// a session token alone does not add passkeys
+	let (status, _) = post!(app, "/v1/passkey-registration-options", json!({ "user_token": token, "meta": {} }));
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+	let (status, _) = post!(app, "/v1/passkey-registration-options", json!({ "user_token": token, "verify_code": "000000", "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let options = passkey_options!(h, app, &token, "13800000034");
+	assert_eq!(options["rp"]["id"], comm::WEBAUTHN_RP_ID);
+	assert_eq!(options["user"]["id"], b64url(&uid.bytes()));
+	assert_eq!(options["pubKeyCredParams"], json!([{ "type": "public-key", "alg": -7 }]));
+	// challenges are single use, even when answered wrong
+	let challenge = options["challenge"].as_str().unwrap().to_string();
+	let (status, _) = post!(app, "/v1/register-passkey", json!({ "user_token": token, "credential": authenticator.create("forged"), "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, _) = post!(app, "/v1/register-passkey", json!({ "user_token": token, "credential": authenticator.create(&challenge), "meta": {} }));
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+	// so is an attestation without user verification
+	let options = passkey_options!(h, app, &token, "13800000034");
+	authenticator.user_verified = false;
+	let (status, _) = post!(app, "/v1/register-passkey", json!({ "user_token": token, "credential": authenticator.create(options["challenge"].as_str().unwrap()), "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	authenticator.user_verified = true;
+	let options = passkey_options!(h, app, &token, "13800000034");
+	let (status, _) = post!(app, "/v1/register-passkey", json!({ "user_token": token, "credential": authenticator.create(options["challenge"].as_str().unwrap()), "name": "laptop", "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert!(h.logs.entries().iter().any(|e| matches!(e, ActivityLogEntry::UpdateMfa { method, enabled: true, .. } if method == "passkey")));
+	let (status, body) = post!(app, "/v1/list-passkeys", json!({ "user_token": token }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["passkeys"][0]["credential_id"], authenticator.id());
+	assert_eq!(body["passkeys"][0]["name"], "laptop");
+	let options = passkey_options!(h, app, &token, "13800000034");
+	assert_eq!(options["excludeCredentials"][0]["id"], authenticator.id());
+	let (status, _) = post!(app, "/v1/register-passkey", json!({ "user_token": token, "credential": authenticator.create(options["challenge"].as_str().unwrap()), "meta": {} }));
+	assert_eq!(status, StatusCode::CONFLICT);
+
+	// passwordless login, no token needed to start
+	h.clock.advance(chrono::Duration::seconds(61));
+	let (status, options) = post!(app, "/v1/passkey-login-options", json!({ "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(options["rpId"], comm::WEBAUTHN_RP_ID);
+	let assertion = authenticator.get(options["challenge"].as_str().unwrap(), &uid);
+	let (status, body) = post!(app, "/v1/login-passkey", json!({ "credential": assertion, "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(body["user"]["passkeys"], 1);
+	assert!(body["vote_token"].is_string());
+	let session = verify(&h, body["session_token"].as_str().unwrap(), "userspace");
+	assert_eq!(session.custom.vote_id.unwrap(), uid.to_hex());
+	let voter = h.voters.find_by_id(&uid).await.unwrap().unwrap();
+	assert_eq!(voter.passkeys[0].sign_count, 1);
+	assert!(voter.passkeys[0].last_used_at.is_some());
+	// replayed assertions find their challenge gone
+	let (status, _) = post!(app, "/v1/login-passkey", json!({ "credential": assertion, "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	// a copy of the key still counting from 0 is refused
+	let (_, options) = post!(app, "/v1/passkey-login-options", json!({ "meta": {} }));
+	authenticator.sign_count = 0;
+	let (status, _) = post!(app, "/v1/login-passkey", json!({ "credential": authenticator.get(options["challenge"].as_str().unwrap(), &uid), "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+	let (status, _) = post!(app, "/v1/remove-passkey", json!({ "user_token": token, "credential_id": authenticator.id(), "meta": {} }));
+	assert_eq!(status, StatusCode::OK);
+	assert!(h.voters.find_by_id(&uid).await.unwrap().unwrap().passkeys.is_empty());
+	h.clock.advance(chrono::Duration::seconds(61));
+	let (_, options) = post!(app, "/v1/passkey-login-options", json!({ "meta": {} }));
+	authenticator.sign_count = 5;
+	let (status, _) = post!(app, "/v1/login-passkey", json!({ "credential": authenticator.get(options["challenge"].as_str().unwrap(), &uid), "meta": {} }));
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+}